@@ -0,0 +1,61 @@
+//! Times AHX encoding of a minute of synthetic speech-like audio.
+//!
+//! Run with `cargo bench --bench ahx_encoder`.
+
+extern crate radx;
+
+use std::f64;
+use std::io::Cursor;
+use std::time::Instant;
+
+use radx::encoder::ahx_encoder::AhxEncoder;
+
+// The crate's tests use the same noise
+#[path = "../src/noise.rs"]
+mod noise;
+
+use noise::Noise;
+
+const SAMPLE_RATE: usize = 22050;
+const SECONDS: usize = 60;
+const RUNS: usize = 5;
+
+/// A few drifting harmonics with a syllable-rate envelope and some noise.
+fn voice() -> Vec<i16> {
+    let mut noise = Noise::new();
+    (0..SAMPLE_RATE * SECONDS).map(|i| {
+        let t = i as f64 / SAMPLE_RATE as f64;
+        let pitch = 140.0 + 30.0 * (t * 0.7).sin();
+        let envelope = 0.5 + 0.5 * (t * 2.0 * f64::consts::PI * 4.0).sin().abs();
+        let mut sample = 0.0;
+        for harmonic in 1..12 {
+            let phase = t * 2.0 * f64::consts::PI * pitch * harmonic as f64;
+            sample += phase.sin() / harmonic as f64;
+        }
+        let hiss = (noise.next().unwrap() as i32 as f64) / (1u64 << 31) as f64;
+        ((sample * 0.3 + hiss * 0.02) * envelope * 16000.0) as i16
+    }).collect()
+}
+
+fn main() {
+    let samples = voice();
+
+    let mut best = None;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let mut encoder = AhxEncoder::new(Cursor::new(Vec::new())).unwrap();
+        encoder.encode_data(samples.iter().cloned()).unwrap();
+        encoder.finalize().unwrap();
+        let elapsed = start.elapsed();
+        if best.map(|best| elapsed < best).unwrap_or(true) {
+            best = Some(elapsed);
+        }
+    }
+
+    let seconds = best.unwrap().as_secs() as f64 + best.unwrap().subsec_nanos() as f64 / 1e9;
+    println!("ahx_encoder: {} s of audio in {:.3} s, {:.1}x real time (best of {})",
+             SECONDS,
+             seconds,
+             SECONDS as f64 / seconds,
+             RUNS);
+}
@@ -1,11 +1,15 @@
-use std::io::{Read, Write, Seek, SeekFrom};
+#[cfg(feature = "std")]
+use std::io::Write;
 
 use adx_reader::AdxReader;
+#[cfg(feature = "std")]
 use adx_writer::AdxWriter;
 use error::{RadxResult, RadxError};
+use source::SeekSource;
 
 const ADX_MAGIC: u16 = 0x8000;
 // TODO: Make function to pub this
+#[cfg(feature = "std")]
 pub(crate) const ADX_HEADER_LEN: usize = 0x0032;
 
 #[derive(Clone,Copy,Debug)]
@@ -19,6 +23,21 @@ pub struct AdxVersion3LoopInfo {
     pub end_byte: u32,
 }
 
+/// Where an AHX stream starts and ends inside its Layer II frames.
+///
+/// CRI's headers don't have this, so radx keeps it in the zero bytes after
+/// the flags, behind `AHX_GAPLESS_TAG`. Streams without it are played from
+/// the first decoded sample, the way CRI's own decoders do.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct AdxVersion6GaplessInfo {
+    /// Samples of filterbank delay before the first input sample.
+    pub delay: u16,
+    /// Samples after the last input sample that fill out the last frame.
+    pub padding: u16,
+}
+
+const AHX_GAPLESS_TAG: [u8; 4] = *b"RADX";
+
 #[derive(Clone,Copy,Debug)]
 pub enum AdxVersion {
     Version3(Option<AdxVersion3LoopInfo>),
@@ -26,7 +45,7 @@ pub enum AdxVersion {
     /// Version 4 without looping support
     Version5,
     /// Seen in SA2B voice afs
-    Version6,
+    Version6(Option<AdxVersion6GaplessInfo>),
 }
 
 impl From<AdxVersion> for u8 {
@@ -35,7 +54,7 @@ impl From<AdxVersion> for u8 {
             AdxVersion::Version3(_) => 0x03,
             AdxVersion::Version4 => 0x04,
             AdxVersion::Version5 => 0x05,
-            AdxVersion::Version6 => 0x06,
+            AdxVersion::Version6(_) => 0x06,
         }
     }
 }
@@ -87,7 +106,13 @@ pub struct AdxHeader {
 
 impl AdxHeader {
     pub fn read_header<S>(mut inner: S) -> RadxResult<AdxHeader>
-        where S: Read + Seek
+        where S: SeekSource
+    {
+        AdxHeader::read_header_from(&mut inner)
+    }
+
+    pub(crate) fn read_header_from<S>(inner: &mut S) -> RadxResult<AdxHeader>
+        where S: SeekSource + ?Sized
     {
         let magic = inner.read_u16()?;
         if magic != ADX_MAGIC {
@@ -133,14 +158,29 @@ impl AdxHeader {
             }
             0x04 => AdxVersion::Version4,
             0x05 => AdxVersion::Version5,
-            0x06 => AdxVersion::Version6,
+            0x06 => {
+                let mut gapless_info = None;
+                if data_offset >= 0x1e {
+                    let mut tag = [0u8; 4];
+                    inner.read_all(&mut tag)?;
+                    if tag == AHX_GAPLESS_TAG {
+                        gapless_info = Some(
+                            AdxVersion6GaplessInfo {
+                                delay: inner.read_u16()?,
+                                padding: inner.read_u16()?,
+                            }
+                        );
+                    }
+                }
+                AdxVersion::Version6(gapless_info)
+            }
             _ => return Err(RadxError::BadAdxHeader("bad adx version value")),
         };
 
-        inner.seek(SeekFrom::Start(data_offset as u64 - 2))?;
+        inner.seek_to(data_offset as u64 - 2)?;
 
         let mut copyright_buffer = [0u8; 6];
-        inner.read_exact(&mut copyright_buffer)?;
+        inner.read_all(&mut copyright_buffer)?;
         if &copyright_buffer != b"(c)CRI" {
             return Err(RadxError::BadAdxHeader("bad copyright string"));
         }
@@ -159,6 +199,7 @@ impl AdxHeader {
         })
     }
 
+    #[cfg(feature = "std")]
     pub fn to_writer<W>(&self, mut writer: W, header_size: usize) -> RadxResult<()>
         where W: Write
     {
@@ -187,6 +228,14 @@ impl AdxHeader {
                     writer.write_u8(0)?;
                 }
             }
+            AdxVersion::Version6(Some(ref gapless_info)) => {
+                writer.write_all(&AHX_GAPLESS_TAG)?;
+                writer.write_u16(gapless_info.delay)?;
+                writer.write_u16(gapless_info.padding)?;
+                for _ in 0..(header_size - 0x1c - 0x06) {
+                    writer.write_u8(0)?;
+                }
+            }
             _ => {
                 for _ in 0..(header_size - 0x14 - 0x06) {
                     writer.write_u8(0)?;
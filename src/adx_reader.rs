@@ -1,26 +1,33 @@
-use std::io::{self, Read};
+use byteorder::{BigEndian, ByteOrder};
 
-use byteorder::{BigEndian, ReadBytesExt};
+use error::RadxResult;
+use source::Source;
 
 pub trait AdxReader
 {
-    fn read_u8(&mut self) -> io::Result<u8>;
-    fn read_u16(&mut self) -> io::Result<u16>;
-    fn read_u32(&mut self) -> io::Result<u32>;
+    fn read_u8(&mut self) -> RadxResult<u8>;
+    fn read_u16(&mut self) -> RadxResult<u16>;
+    fn read_u32(&mut self) -> RadxResult<u32>;
 }
 
 impl<R> AdxReader for R
-    where R: Read
+    where R: Source + ?Sized
 {
-    fn read_u8(&mut self) -> io::Result<u8> {
-        ReadBytesExt::read_u8(self)
+    fn read_u8(&mut self) -> RadxResult<u8> {
+        let mut buf = [0; 1];
+        self.read_all(&mut buf)?;
+        Ok(buf[0])
     }
 
-    fn read_u16(&mut self) -> io::Result<u16> {
-        ReadBytesExt::read_u16::<BigEndian>(self)
+    fn read_u16(&mut self) -> RadxResult<u16> {
+        let mut buf = [0; 2];
+        self.read_all(&mut buf)?;
+        Ok(BigEndian::read_u16(&buf))
     }
 
-    fn read_u32(&mut self) -> io::Result<u32> {
-        ReadBytesExt::read_u32::<BigEndian>(self)
+    fn read_u32(&mut self) -> RadxResult<u32> {
+        let mut buf = [0; 4];
+        self.read_all(&mut buf)?;
+        Ok(BigEndian::read_u32(&buf))
     }
 }
@@ -24,6 +24,8 @@ fn main() {
     let mut opts = Options::new();
     opts.optopt("l", "loop", "Loop N times", "N");
     opts.optflag("i", "info", "Print adx header info");
+    opts.optflag("r", "recover", "Keep decoding past damaged frames");
+    opts.optflag("m", "measure", "Print integrated loudness and true peak instead of writing a wav");
     opts.optflag("h", "help", "Print this help menu");
 
     // Parse options
@@ -62,7 +64,16 @@ fn main() {
 	if matches.opt_present("i") {
 		print_info(adx_file);
 	}
-    let mut adx = unwrap_or_barf(radx::from_reader(adx_file, loops_opt.is_some()), "Could not make adx reader");
+    let measure = matches.opt_present("m");
+    let mut adx = unwrap_or_barf(radx::from_reader(adx_file, loops_opt.is_some() && !measure), "Could not make adx reader");
+    adx.set_recovery(matches.opt_present("r"));
+
+    if measure {
+        let loudness = unwrap_or_barf(radx::loudness::measure(&mut *adx), "Could not measure loudness");
+        println!("Integrated loudness: {:.1} LUFS", loudness.integrated);
+        println!("True peak: {:.1} dBTP", loudness.true_peak);
+        process::exit(0);
+    }
 
     // Print adx info
     println!("ADX info:");
@@ -105,13 +116,18 @@ fn main() {
         }
     }
     else {
-        for sample in adx {
+        for sample in adx.by_ref() {
             for channel_sample in sample {
                 unwrap_or_barf(wav_writer.write_sample(channel_sample), "Problem writing wav samples");
             }
         }
     };
 
+    // Report anything we had to recover from
+    for event in adx.take_recovery_events() {
+        println!("Recovered at byte {:#x}: {:?}", event.offset, event.kind);
+    }
+
     // Finish writing to the wav
     unwrap_or_barf(wav_writer.finalize(), "Could not finalize writing wav file");
 }
@@ -9,13 +9,15 @@ use std::io::{Read, BufReader, BufWriter};
 use std::fs::File;
 use std::process;
 
-use radx::{AdxSpec, LoopInfo};
-use radx::encoder::standard_encoder::StandardEncoder;
-use radx::encoder::ahx_encoder::AhxEncoder;
+use radx::{LoopInfo, DEFAULT_HIGHPASS_FREQUENCY};
+use radx::convert::{Converter, Dither};
+use radx::encoder::{Codec, EncoderOptions};
+use radx::loudness::{LoudnessMeter, Normalization};
+use radx::encoder::standard_encoder::{Crossfade, CrossfadeCurve, NoiseShaping, Quality, best_highpass_frequency};
 
 use getopts::Options;
 
-use hound::{WavReader, Error as WavError, Result as WavResult};
+use hound::{WavReader, SampleFormat, Error as WavError, Result as WavResult};
 
 fn main() {
     let mut args = env::args();
@@ -27,7 +29,20 @@ fn main() {
     opts.optopt("s", "start", "Loop start sample (defaults to song start)", "START");
     opts.optopt("e", "end", "Loop end sample (defaults to song end)", "END");
     opts.optflag("n", "no-loop", "Don't loop the song");
-    opts.optflag("a", "ahx", "Use ahx encoding (cannot loop)");
+    opts.optflag("a", "ahx", "Use ahx encoding, mixing down to mono at 22050 Hz (cannot loop)");
+    opts.optflag("m", "mp2", "Write a plain mp2 file instead, keeping the input's channels and sample rate (cannot loop)");
+    opts.optopt("b", "bitrate", "Bitrate of mp2 output in kbps (defaults to 96 per channel, or 64 below 32000 Hz)", "KBPS");
+    opts.optflag("q", "quality", "Search for the best scale per block (slower)");
+    opts.optflag("t", "trellis", "Search nibble choices across blocks too (slowest)");
+    opts.optopt("N", "noise-shaping", "Noise shaping: none, light, medium or strong (defaults to none)", "PRESET");
+    opts.optopt("f", "highpass", "Predictor cutoff in Hz, or auto to pick the best one (defaults to 500)", "FREQ");
+    opts.optopt("d", "dither", "Dither for input over 16 bits: none, tpdf or shaped (defaults to tpdf)", "DITHER");
+    opts.optopt("x", "crossfade", "Crossfade the loop end into the audio before the loop start", "SAMPLES");
+    opts.optopt("C", "crossfade-curve", "Crossfade curve: linear or equal-power (defaults to equal-power)", "CURVE");
+    opts.optflag("E", "match-loop-entry", "Fade the frame before the loop start into the loop end, so the loop doesn't click the first time in");
+    opts.optopt("L", "loudness", "Normalize to this integrated loudness in LUFS, such as -16", "LUFS");
+    opts.optopt("P", "peak", "True peak ceiling in dBTP when normalizing (defaults to -1)", "DBTP");
+    opts.optopt("j", "threads", "Threads to encode adx on (defaults to 1)", "N");
     opts.optflag("h", "help", "Print this help menu");
 
     // Parse options
@@ -48,6 +63,45 @@ fn main() {
         .opt_str("e")
         .and_then(|end_str| { end_str.parse::<u32>().ok() });
 
+    let noise_shaping = match matches.opt_str("N") {
+        Some(preset) => NoiseShaping::preset(&preset).unwrap_or_else(|| barf("Unknown noise shaping preset")),
+        None => NoiseShaping::none(),
+    };
+
+    let dither = match matches.opt_str("d") {
+        Some(name) => Dither::from_name(&name).unwrap_or_else(|| barf("Unknown dither")),
+        None => Dither::Tpdf,
+    };
+
+    let crossfade_curve = match matches.opt_str("C") {
+        Some(name) => CrossfadeCurve::from_name(&name).unwrap_or_else(|| barf("Unknown crossfade curve")),
+        None => CrossfadeCurve::EqualPower,
+    };
+    let crossfade = matches
+        .opt_str("x")
+        .map(|length_str| Crossfade {
+            length: length_str.parse::<u32>().unwrap_or_else(|_| barf("Bad crossfade length")),
+            curve: crossfade_curve,
+        });
+
+    let loudness_target = matches
+        .opt_str("L")
+        .map(|target_str| target_str.parse::<f64>().unwrap_or_else(|_| barf("Bad loudness target")));
+    let peak_ceiling = matches
+        .opt_str("P")
+        .map(|peak_str| peak_str.parse::<f64>().unwrap_or_else(|_| barf("Bad peak ceiling")))
+        .unwrap_or(-1.0);
+
+    let bitrate = matches
+        .opt_str("b")
+        .map(|bitrate_str| bitrate_str.parse::<u32>().unwrap_or_else(|_| barf("Bad bitrate")));
+    let mp2 = matches.opt_present("m");
+
+    let threads = matches
+        .opt_str("j")
+        .map(|threads_str| threads_str.parse::<usize>().unwrap_or_else(|_| barf("Bad thread count")))
+        .unwrap_or(1);
+
     // Get input and output files
     let mut free_iter = matches.free.iter();
     let filename;
@@ -62,7 +116,7 @@ fn main() {
         .map(|s| s.clone())
         .unwrap_or({
             let mut filename_clone = filename.clone();
-            filename_clone.push_str(".adx");
+            filename_clone.push_str(if mp2 { ".mp2" } else { ".adx" });
             filename_clone
         });
 
@@ -70,58 +124,91 @@ fn main() {
     let input = BufReader::new(unwrap_or_barf(File::open(filename), "Could not open input file"));
     let output = BufWriter::new(unwrap_or_barf(File::create(&output_filename), "Could not open output file"));
 
-    // Change based on encoding
-    if matches.opt_present("a") {
-        // Read samples
-        println!("Reading Samples");
-        let (samples, sample_rate) = unwrap_or_barf(read_samples_ahx(input), "Could not read samples from input");
+    // Read samples
+    println!("Reading Samples");
+    let ahx = matches.opt_present("a");
+    let (samples, channels, sample_rate) = if ahx || mp2 {
+        unwrap_or_barf(read_samples_as_is(input, dither), "Could not read samples from input")
+    }
+    else {
+        let (samples, sample_rate) = unwrap_or_barf(read_samples(input, dither), "Could not read samples from input");
+        (samples, 2, sample_rate)
+    };
 
-        if sample_rate != 22050 {
-            barf("ahx encoding requires a sample rate of 22050");
+    let mut options = EncoderOptions::new().sample_rate(sample_rate);
+    if ahx {
+        // Mixed down to mono and resampled to 22050 Hz as needed
+        options = options.codec(Codec::Ahx).channels(channels);
+    }
+    else if mp2 {
+        options = options.codec(Codec::Mp2).channels(channels);
+        if let Some(bitrate) = bitrate {
+            options = options.bitrate(bitrate);
         }
-
-        // Make encoder
-        let mut encoder = unwrap_or_barf(AhxEncoder::new(output), "Could not make encoder");
-
-        // Encode data
-        println!("Encoding data");
-        unwrap_or_barf(encoder.encode_data(samples), "Could not encode data");
-        unwrap_or_barf(encoder.finalize(), "Could not finish writing adx file");
     }
     else {
-        // Read samples
-        println!("Reading Samples");
-        let (samples, sample_rate) = unwrap_or_barf(read_samples(input), "Could not read samples from input");
-
-        // Make adx spec
-        let spec = if matches.opt_present("n") {
-            AdxSpec {
-                channels: 2,
-                sample_rate: sample_rate,
-                loop_info: None,
+        let highpass_frequency = match matches.opt_str("f") {
+            Some(ref freq_str) if freq_str == "auto" => {
+                println!("Finding best highpass cutoff");
+                best_highpass_frequency(&samples, 2, sample_rate)
             }
+            Some(freq_str) => freq_str.parse::<u16>().unwrap_or_else(|_| barf("Bad highpass frequency")),
+            None => DEFAULT_HIGHPASS_FREQUENCY,
+        };
+
+        let loop_info = if matches.opt_present("n") {
+            None
         }
         else {
-            AdxSpec {
-                channels: 2,
-                sample_rate: sample_rate,
-                loop_info: Some(
-                    LoopInfo {
-                        start_sample: start_sample,
-                        end_sample: end_sample_opt.unwrap_or(samples.len() as u32),
-                    }
-                )
-            }
+            Some(LoopInfo {
+                start_sample: start_sample,
+                end_sample: end_sample_opt.unwrap_or(samples.len() as u32),
+            })
+        };
+
+        let quality = if matches.opt_present("t") {
+            Quality::Trellis
+        }
+        else if matches.opt_present("q") {
+            Quality::Best
+        }
+        else {
+            Quality::Fast
         };
 
-        // Make encoder from spec
-        let mut encoder = unwrap_or_barf(StandardEncoder::new(output, spec), "Could not make encoder");
+        options = options
+            .channels(2)
+            .loop_info(loop_info)
+            .quality(quality)
+            .highpass_frequency(highpass_frequency)
+            .noise_shaping(noise_shaping)
+            .crossfade(crossfade)
+            .match_loop_entry(matches.opt_present("E"))
+            .threads(threads);
+    }
 
-        // Encode data
-        println!("Encoding data");
-        unwrap_or_barf(encoder.encode_data(samples), "Could not encode data");
-        unwrap_or_barf(encoder.finish(), "Could not finish writing adx file");
+    if let Some(target) = loudness_target {
+        let normalization = Normalization {
+            target: target,
+            peak_ceiling: peak_ceiling,
+        };
+        let mut meter = LoudnessMeter::new(channels as usize, sample_rate);
+        meter.add_samples(&samples);
+        let loudness = meter.loudness();
+        println!("Input is {:.1} LUFS with a true peak of {:.1} dBTP, applying {:+.1} dB",
+                 loudness.integrated,
+                 loudness.true_peak,
+                 normalization.gain(&loudness));
+        options = options.normalize(Some(normalization));
     }
+
+    // Make encoder from options
+    let mut encoder = unwrap_or_barf(radx::to_writer(output, &options), "Could not make encoder");
+
+    // Encode data
+    println!("Encoding data");
+    unwrap_or_barf(encoder.encode_samples(&samples), "Could not encode data");
+    unwrap_or_barf(encoder.finish(), "Could not finish writing output file");
 }
 
 fn barf(message: &str) -> ! {
@@ -145,50 +232,63 @@ fn help(prog_name: &str, opts: Options) -> ! {
     process::exit(0);
 }
 
-fn read_samples<R>(reader: R) -> WavResult<(Vec<Vec<i16>>, u32)>
+fn read_samples<R>(reader: R, dither: Dither) -> WavResult<(Vec<Vec<i16>>, u32)>
     where R: Read
 {
     let mut reader = WavReader::new(reader)?;
     let spec = reader.spec();
     if spec.channels == 1 {
-        let mut samples = reader.samples::<i16>();
-        let mut sample_vec = Vec::new();
-        while let Some(sample_res) = samples.next() {
-            let sample = sample_res?;
-            sample_vec.push(vec![sample, sample]);
-        }
-        Ok((sample_vec, spec.sample_rate))
+        let samples = read_frames(&mut reader, dither)?;
+        Ok((samples.into_iter().map(|sample| vec![sample[0], sample[0]]).collect(), spec.sample_rate))
     }
     else if spec.channels == 2 {
-        let mut samples = reader.samples::<i16>();
-        let mut sample_vec = Vec::new();
-        while let Some(sample1_res) = samples.next() {
-            let sample1 = sample1_res?;
-            let sample2 = if let Some(sample_res) = samples.next() {
-                sample_res?
-            }
-            else {
-                sample1
-            };
-            sample_vec.push(vec![sample1, sample2]);
-        }
-        Ok((sample_vec, spec.sample_rate))
+        Ok((read_frames(&mut reader, dither)?, spec.sample_rate))
     }
     else {
         Err(WavError::Unsupported)
     }
 }
 
-fn read_samples_ahx<R>(reader: R) -> WavResult<(Vec<i16>, u32)>
+/// Reads samples with however many channels the input has.
+fn read_samples_as_is<R>(reader: R, dither: Dither) -> WavResult<(Vec<Vec<i16>>, u32, u32)>
     where R: Read
 {
     let mut reader = WavReader::new(reader)?;
     let spec = reader.spec();
-    if spec.channels == 1 {
-        let samples: WavResult<_> = reader.samples::<i16>().collect();
-        Ok((samples?, spec.sample_rate))
-    }
-    else {
-        barf("ahx encoding requires 1 channel (mono)");
+    Ok((read_frames(&mut reader, dither)?, spec.channels as u32, spec.sample_rate))
+}
+
+/// Reads every sample in as 16 bits, dithering down from higher resolutions.
+fn read_frames<R>(reader: &mut WavReader<R>, dither: Dither) -> WavResult<Vec<Vec<i16>>>
+    where R: Read
+{
+    let spec = reader.spec();
+    let channels = spec.channels as usize;
+    let mut converter = Converter::new(channels, dither);
+    let mut frames = Vec::new();
+
+    match spec.sample_format {
+        SampleFormat::Float => {
+            let mut frame = Vec::with_capacity(channels);
+            for sample in reader.samples::<f32>() {
+                frame.push(sample?);
+                if frame.len() == channels {
+                    frames.push(converter.convert_f32(&frame));
+                    frame.clear();
+                }
+            }
+        }
+        SampleFormat::Int => {
+            let bits = spec.bits_per_sample as u32;
+            let mut frame = Vec::with_capacity(channels);
+            for sample in reader.samples::<i32>() {
+                frame.push(sample?);
+                if frame.len() == channels {
+                    frames.push(converter.convert_int(&frame, bits));
+                    frame.clear();
+                }
+            }
+        }
     }
+    Ok(frames)
 }
@@ -0,0 +1,334 @@
+//! Bringing higher resolution audio down to the 16 bit samples the encoders
+//! take, and other sample rates and channel layouts to what a format allows.
+
+use std::collections::VecDeque;
+use std::f64;
+
+use Sample;
+
+/// Zero crossings of the resampling filter either side of its centre.
+const RESAMPLE_ZERO_CROSSINGS: usize = 48;
+
+/// Steps between zero crossings in the resampling filter table. Values in
+/// between are interpolated.
+const RESAMPLE_TABLE_STEPS: usize = 256;
+
+/// Resampling filter cutoff as a fraction of the lower Nyquist frequency.
+/// Leaves room for the filter to roll off before aliasing would start.
+const RESAMPLE_ROLLOFF: f64 = 0.94;
+
+/// Kaiser window shape for the resampling filter, for about 90 dB of
+/// stopband attenuation.
+const RESAMPLE_KAISER_BETA: f64 = 9.0;
+
+/// Noise added when cutting samples down to 16 bits.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Dither {
+    /// Plain rounding. Leaves distortion on quiet material that dither would
+    /// turn into noise.
+    None,
+    /// Triangular dither of one step either way, which makes the rounding
+    /// error plain noise with no trace of the signal.
+    Tpdf,
+    /// TPDF dither with the rounding error fed back through
+    /// `(1 - z^-1)^2`, moving the noise up towards high frequencies.
+    Shaped,
+}
+
+impl Dither {
+    /// Looks up a dither by name, `none`, `tpdf` or `shaped`.
+    pub fn from_name(name: &str) -> Option<Dither> {
+        match name {
+            "none" => Some(Dither::None),
+            "tpdf" => Some(Dither::Tpdf),
+            "shaped" => Some(Dither::Shaped),
+            _ => None,
+        }
+    }
+}
+
+/// Converts samples to 16 bits one multichannel sample at a time, keeping the
+/// dither state for each channel in between.
+#[derive(Clone,Debug)]
+pub struct Converter {
+    dither: Dither,
+    /// xorshift state for the dither noise.
+    rng: u32,
+    /// Last two rounding errors for each channel, newest first.
+    errors: Vec<[f64; 2]>,
+}
+
+impl Converter {
+    pub fn new(channels: usize, dither: Dither) -> Converter {
+        Converter {
+            dither: dither,
+            rng: 0x2545_f491,
+            errors: vec![[0.0; 2]; channels],
+        }
+    }
+
+    /// Converts float samples, with full scale from -1.0 to 1.0.
+    pub fn convert_f32(&mut self, sample: &[f32]) -> Sample {
+        sample.iter()
+            .enumerate()
+            .map(|(channel, &channel_sample)| self.quantize(channel, channel_sample as f64 * 32768.0))
+            .collect()
+    }
+
+    /// Converts integer samples `bits` wide, such as 24 bit samples held in
+    /// an `i32`. Samples of 16 bits or fewer come through exactly.
+    pub fn convert_int(&mut self, sample: &[i32], bits: u32) -> Sample {
+        if bits <= 16 {
+            return sample.iter().map(|&channel_sample| (channel_sample << (16 - bits)) as i16).collect();
+        }
+
+        let step = (1u32 << (bits - 16)) as f64;
+        sample.iter()
+            .enumerate()
+            .map(|(channel, &channel_sample)| self.quantize(channel, channel_sample as f64 / step))
+            .collect()
+    }
+
+    /// Scales 16 bit samples by `gain`, as for a change in volume.
+    pub fn convert_scaled(&mut self, sample: &[i16], gain: f64) -> Sample {
+        sample.iter()
+            .enumerate()
+            .map(|(channel, &channel_sample)| self.quantize(channel, channel_sample as f64 * gain))
+            .collect()
+    }
+
+    /// Rounds `value`, in 16 bit steps, to a sample.
+    fn quantize(&mut self, channel: usize, value: f64) -> i16 {
+        let target = match self.dither {
+            Dither::Shaped => {
+                let errors = self.errors[channel];
+                value - (2.0 * errors[0] - errors[1])
+            }
+            _ => value,
+        };
+        let noise = match self.dither {
+            Dither::None => 0.0,
+            Dither::Tpdf | Dither::Shaped => self.uniform() - self.uniform(),
+        };
+
+        let rounded = (target + noise).round().max(i16::MIN as f64).min(i16::MAX as f64);
+        if self.dither == Dither::Shaped {
+            // Clipping makes errors too big to feed back usefully
+            let error = (rounded - target).clamp(-2.0, 2.0);
+            let errors = &mut self.errors[channel];
+            errors[1] = errors[0];
+            errors[0] = error;
+        }
+        rounded as i16
+    }
+
+    /// Uniform noise in [-0.5, 0.5).
+    fn uniform(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng as f64 / 4294967296.0 - 0.5
+    }
+}
+
+/// Mixes a sample down to mono by averaging its channels.
+pub fn downmix(sample: &[i16]) -> i16 {
+    let sum: i32 = sample.iter().map(|&channel_sample| channel_sample as i32).sum();
+    (sum as f64 / sample.len() as f64).round() as i16
+}
+
+/// Converts between sample rates with a windowed sinc filter, a block of
+/// samples at a time.
+///
+/// The output lines up with the input, with no delay, and runs for as long:
+/// `ceil(samples * output_rate / input_rate)` samples once `finish` has
+/// flushed the end through.
+#[derive(Clone,Debug)]
+pub struct Resampler {
+    channels: usize,
+    input_rate: u64,
+    output_rate: u64,
+    /// Filter cutoff relative to the input Nyquist frequency.
+    cutoff: f64,
+    /// Input samples either side of an output sample the filter reaches.
+    half_width: usize,
+    /// One side of the filter, from its centre out to the last zero crossing.
+    table: Vec<f64>,
+    /// Input samples still in reach of the filter, from `buffer_start` on.
+    buffer: VecDeque<Sample>,
+    buffer_start: u64,
+    received: u64,
+    produced: u64,
+}
+
+impl Resampler {
+    pub fn new(channels: usize, input_rate: u32, output_rate: u32) -> Resampler {
+        let cutoff = RESAMPLE_ROLLOFF * (output_rate as f64 / input_rate as f64).min(1.0);
+        let steps = RESAMPLE_ZERO_CROSSINGS * RESAMPLE_TABLE_STEPS;
+        let table = (0..steps + 2)
+            .map(|step| {
+                let x = step as f64 / RESAMPLE_TABLE_STEPS as f64;
+                let sinc = if step == 0 { 1.0 } else { (f64::consts::PI * x).sin() / (f64::consts::PI * x) };
+                let position = (x / RESAMPLE_ZERO_CROSSINGS as f64).min(1.0);
+                let window = bessel_i0(RESAMPLE_KAISER_BETA * (1.0 - position * position).sqrt()) /
+                    bessel_i0(RESAMPLE_KAISER_BETA);
+                sinc * window
+            })
+            .collect();
+
+        Resampler {
+            channels: channels,
+            input_rate: input_rate as u64,
+            output_rate: output_rate as u64,
+            cutoff: cutoff,
+            half_width: (RESAMPLE_ZERO_CROSSINGS as f64 / cutoff).ceil() as usize,
+            table: table,
+            buffer: VecDeque::new(),
+            buffer_start: 0,
+            received: 0,
+            produced: 0,
+        }
+    }
+
+    /// Takes in `samples`, returning the output samples they complete.
+    pub fn process(&mut self, samples: &[Sample]) -> Vec<Sample> {
+        self.buffer.extend(samples.iter().cloned());
+        self.received += samples.len() as u64;
+        self.drain(false)
+    }
+
+    /// Returns the rest of the output, from the end of the input.
+    pub fn finish(&mut self) -> Vec<Sample> {
+        self.drain(true)
+    }
+
+    fn drain(&mut self, finished: bool) -> Vec<Sample> {
+        let mut output = Vec::new();
+        let total = (self.received * self.output_rate).div_ceil(self.input_rate);
+        while !finished || self.produced < total {
+            // Where the next output sample falls in the input
+            let position = self.produced * self.input_rate;
+            let (index, fraction) = (position / self.output_rate, (position % self.output_rate) as f64 / self.output_rate as f64);
+            if !finished && index + (self.half_width as u64) >= self.received {
+                break;
+            }
+
+            // Drop what the filter can't reach any more
+            let first = index.saturating_sub(self.half_width as u64 - 1);
+            while self.buffer_start < first && !self.buffer.is_empty() {
+                self.buffer.pop_front();
+                self.buffer_start += 1;
+            }
+
+            let mut sums = vec![0.0; self.channels];
+            for (offset, input) in self.buffer.iter().enumerate().take(2 * self.half_width) {
+                let distance = (self.buffer_start + offset as u64) as f64 - index as f64 - fraction;
+                let weight = self.tap(distance);
+                for (sum, &channel_sample) in sums.iter_mut().zip(input.iter()) {
+                    *sum += channel_sample as f64 * weight;
+                }
+            }
+            output.push(sums.into_iter()
+                .map(|sum| sum.round().max(i16::MIN as f64).min(i16::MAX as f64) as i16)
+                .collect());
+            self.produced += 1;
+        }
+        output
+    }
+
+    /// Filter weight for an input sample `distance` input samples away.
+    fn tap(&self, distance: f64) -> f64 {
+        let steps = (distance * self.cutoff).abs() * RESAMPLE_TABLE_STEPS as f64;
+        let step = steps as usize;
+        if step >= RESAMPLE_ZERO_CROSSINGS * RESAMPLE_TABLE_STEPS {
+            return 0.0;
+        }
+        let fraction = steps - step as f64;
+        let value = self.table[step] + (self.table[step + 1] - self.table[step]) * fraction;
+        value * self.cutoff
+    }
+}
+
+/// Modified Bessel function of the first kind, order zero.
+pub(crate) fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    for k in 1..50 {
+        term *= half / k as f64;
+        sum += term * term;
+        if term * term < sum * 1e-17 {
+            break;
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64;
+
+    use super::{Converter, Dither, Resampler};
+    use encoder::measure_snr;
+
+    #[test]
+    fn exact_conversions() {
+        let mut converter = Converter::new(2, Dither::None);
+        assert_eq!(converter.convert_int(&[-128, 127], 8), vec![-32768, 32512]);
+        assert_eq!(converter.convert_int(&[0x12_3400, -0x80_0000], 24), vec![0x1234, -32768]);
+        assert_eq!(converter.convert_f32(&[0.5, -1.5]), vec![16384, -32768]);
+    }
+
+    #[test]
+    fn dither() {
+        // A quiet 24 bit tone a fraction of a 16 bit step from zero
+        let input: Vec<i32> = (0..20000).map(|i| ((i as f64 * 0.01).sin() * 96.0) as i32).collect();
+
+        for &dither in [Dither::Tpdf, Dither::Shaped].iter() {
+            let mut converter = Converter::new(1, dither);
+            let output: Vec<i16> = input.iter().map(|&sample| converter.convert_int(&[sample], 24)[0]).collect();
+
+            // Rounding alone would leave this all zero. Dithered, the tone
+            // survives on average
+            let (mut xy, mut xx) = (0.0, 0.0);
+            for (&x, &y) in input.iter().zip(output.iter()) {
+                xy += x as f64 * y as f64;
+                xx += x as f64 * x as f64;
+            }
+            let gain = xy / xx * 256.0;
+            assert!((gain - 1.0).abs() < 0.1, "{:?} gain {}", dither, gain);
+        }
+    }
+
+    #[test]
+    fn resample() {
+        let tone = |freq: f64, rate: u32, len: usize| -> Vec<Vec<i16>> {
+            (0..len).map(|i| vec![((i as f64 * 2.0 * f64::consts::PI * freq / rate as f64).sin() * 10000.0).round() as i16]).collect()
+        };
+
+        for &input_rate in [44100, 48000, 16000].iter() {
+            let input = tone(1000.0, input_rate, input_rate as usize / 2 + 17);
+            let mut resampler = Resampler::new(1, input_rate, 22050);
+            let mut output = Vec::new();
+            for chunk in input.chunks(1000) {
+                output.extend(resampler.process(chunk));
+            }
+            output.extend(resampler.finish());
+            let expected_len = (input.len() as u64 * 22050).div_ceil(input_rate as u64);
+            assert_eq!(output.len() as u64, expected_len);
+
+            // Away from the ends, the tone should come out the same
+            let expected = tone(1000.0, 22050, output.len());
+            let middle = 200..output.len() - 200;
+            let snr = measure_snr(&expected[middle.clone()], &output[middle], 0);
+            assert!(snr > 60.0, "{} Hz snr {}", input_rate, snr);
+        }
+
+        // Above the new Nyquist frequency, a tone should all but vanish
+        let mut resampler = Resampler::new(1, 44100, 22050);
+        let mut output = resampler.process(&tone(15000.0, 44100, 20000));
+        output.extend(resampler.finish());
+        let peak = output[200..output.len() - 200].iter().map(|sample| sample[0].abs()).max().unwrap();
+        assert!(peak < 10, "peak {}", peak);
+    }
+}
@@ -0,0 +1,1086 @@
+use std::cmp;
+use std::num::Wrapping;
+
+use decoder::state::{StateReader, StateWriter};
+use error::{RadxResult, RadxError};
+use source::Source;
+
+pub(crate) const FRAC_BITS: u32 = 28;
+
+#[derive(Clone,Copy,Debug)]
+pub(crate) struct QuantizeSpec {
+    pub nlevels: i64,
+    /// Bits in each sample of a grouped class, or 0 for ungrouped ones.
+    pub group: u32,
+    /// Bits in a group, or in each sample.
+    pub bits: u32,
+    c: i64,
+    d: i64,
+}
+
+/// Every quantization class a Layer II stream can use, from 3 levels up to
+/// 65535 levels. Allocation tables index into this.
+pub(crate) const QUANT_CLASSES: [QuantizeSpec; 17] = [
+    QuantizeSpec{nlevels: 3,     group: 2, bits: 5,  c: 0x15555555, d: 0x08000000},
+    QuantizeSpec{nlevels: 5,     group: 3, bits: 7,  c: 0x1999999a, d: 0x08000000},
+    QuantizeSpec{nlevels: 7,     group: 0, bits: 3,  c: 0x12492492, d: 0x04000000},
+    QuantizeSpec{nlevels: 9,     group: 4, bits: 10, c: 0x1c71c71c, d: 0x08000000},
+    QuantizeSpec{nlevels: 15,    group: 0, bits: 4,  c: 0x11111111, d: 0x02000000},
+    QuantizeSpec{nlevels: 31,    group: 0, bits: 5,  c: 0x10842108, d: 0x01000000},
+    QuantizeSpec{nlevels: 63,    group: 0, bits: 6,  c: 0x10410410, d: 0x00800000},
+    QuantizeSpec{nlevels: 127,   group: 0, bits: 7,  c: 0x10204081, d: 0x00400000},
+    QuantizeSpec{nlevels: 255,   group: 0, bits: 8,  c: 0x10101010, d: 0x00200000},
+    QuantizeSpec{nlevels: 511,   group: 0, bits: 9,  c: 0x10080402, d: 0x00100000},
+    QuantizeSpec{nlevels: 1023,  group: 0, bits: 10, c: 0x10040100, d: 0x00080000},
+    QuantizeSpec{nlevels: 2047,  group: 0, bits: 11, c: 0x10020040, d: 0x00040000},
+    QuantizeSpec{nlevels: 4095,  group: 0, bits: 12, c: 0x10010010, d: 0x00020000},
+    QuantizeSpec{nlevels: 8191,  group: 0, bits: 13, c: 0x10008004, d: 0x00010000},
+    QuantizeSpec{nlevels: 16383, group: 0, bits: 14, c: 0x10004001, d: 0x00008000},
+    QuantizeSpec{nlevels: 32767, group: 0, bits: 15, c: 0x10002000, d: 0x00004000},
+    QuantizeSpec{nlevels: 65535, group: 0, bits: 16, c: 0x10001000, d: 0x00002000},
+];
+
+// Quantization classes for each allocation value (starting at 1) of a
+// subband. The number of allocation bits follows from the length.
+const ALLOC_4_A: &[u8] = &[0, 2, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+const ALLOC_4_B: &[u8] = &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 16];
+const ALLOC_4_C: &[u8] = &[0, 1, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+const ALLOC_4_LSF: &[u8] = &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14];
+const ALLOC_3_A: &[u8] = &[0, 1, 2, 3, 4, 5, 16];
+const ALLOC_3_C: &[u8] = &[0, 1, 3, 4, 5, 6, 7];
+const ALLOC_2_A: &[u8] = &[0, 1, 16];
+const ALLOC_2_LSF: &[u8] = &[0, 1, 3];
+
+/// ISO 11172-3 table B.2a. MPEG-1, medium bitrates.
+pub(crate) const ALLOC_TABLE_A: &[&[u8]] = &[
+    ALLOC_4_A, ALLOC_4_A, ALLOC_4_A,
+    ALLOC_4_B, ALLOC_4_B, ALLOC_4_B, ALLOC_4_B, ALLOC_4_B, ALLOC_4_B, ALLOC_4_B, ALLOC_4_B,
+    ALLOC_3_A, ALLOC_3_A, ALLOC_3_A, ALLOC_3_A, ALLOC_3_A, ALLOC_3_A,
+    ALLOC_3_A, ALLOC_3_A, ALLOC_3_A, ALLOC_3_A, ALLOC_3_A, ALLOC_3_A,
+    ALLOC_2_A, ALLOC_2_A, ALLOC_2_A, ALLOC_2_A,
+];
+
+/// ISO 11172-3 table B.2b. MPEG-1, high bitrates.
+pub(crate) const ALLOC_TABLE_B: &[&[u8]] = &[
+    ALLOC_4_A, ALLOC_4_A, ALLOC_4_A,
+    ALLOC_4_B, ALLOC_4_B, ALLOC_4_B, ALLOC_4_B, ALLOC_4_B, ALLOC_4_B, ALLOC_4_B, ALLOC_4_B,
+    ALLOC_3_A, ALLOC_3_A, ALLOC_3_A, ALLOC_3_A, ALLOC_3_A, ALLOC_3_A,
+    ALLOC_3_A, ALLOC_3_A, ALLOC_3_A, ALLOC_3_A, ALLOC_3_A, ALLOC_3_A,
+    ALLOC_2_A, ALLOC_2_A, ALLOC_2_A, ALLOC_2_A, ALLOC_2_A, ALLOC_2_A, ALLOC_2_A,
+];
+
+/// ISO 11172-3 table B.2c. MPEG-1, low bitrates at 44.1kHz and 48kHz.
+pub(crate) const ALLOC_TABLE_C: &[&[u8]] = &[
+    ALLOC_4_C, ALLOC_4_C,
+    ALLOC_3_C, ALLOC_3_C, ALLOC_3_C, ALLOC_3_C, ALLOC_3_C, ALLOC_3_C,
+];
+
+/// ISO 11172-3 table B.2d. MPEG-1, low bitrates at 32kHz.
+pub(crate) const ALLOC_TABLE_D: &[&[u8]] = &[
+    ALLOC_4_C, ALLOC_4_C,
+    ALLOC_3_C, ALLOC_3_C, ALLOC_3_C, ALLOC_3_C, ALLOC_3_C,
+    ALLOC_3_C, ALLOC_3_C, ALLOC_3_C, ALLOC_3_C, ALLOC_3_C,
+];
+
+/// ISO 13818-3 table B.1. Every MPEG-2 low sampling frequency stream,
+/// AHX included.
+pub(crate) const ALLOC_TABLE_LSF: &[&[u8]] = &[
+    ALLOC_4_LSF, ALLOC_4_LSF, ALLOC_4_LSF, ALLOC_4_LSF,
+    ALLOC_3_C, ALLOC_3_C, ALLOC_3_C, ALLOC_3_C, ALLOC_3_C, ALLOC_3_C, ALLOC_3_C,
+    ALLOC_2_LSF, ALLOC_2_LSF, ALLOC_2_LSF, ALLOC_2_LSF, ALLOC_2_LSF, ALLOC_2_LSF, ALLOC_2_LSF,
+    ALLOC_2_LSF, ALLOC_2_LSF, ALLOC_2_LSF, ALLOC_2_LSF, ALLOC_2_LSF, ALLOC_2_LSF, ALLOC_2_LSF,
+    ALLOC_2_LSF, ALLOC_2_LSF, ALLOC_2_LSF, ALLOC_2_LSF, ALLOC_2_LSF,
+];
+
+pub(crate) const SF_TABLE: [i64; 63] = [
+    0x20000000,
+    0x1965fea5,
+    0x1428a2fa,
+    0x10000000,
+    0x0cb2ff53,
+    0x0a14517d,
+    0x08000000,
+    0x06597fa9,
+    0x050a28be,
+    0x04000000,
+    0x032cbfd5,
+    0x0285145f,
+    0x02000000,
+    0x01965fea,
+    0x01428a30,
+    0x01000000,
+    0x00cb2ff5,
+    0x00a14518,
+    0x00800000,
+    0x006597fb,
+    0x0050a28c,
+    0x00400000,
+    0x0032cbfd,
+    0x00285146,
+    0x00200000,
+    0x001965ff,
+    0x001428a3,
+    0x00100000,
+    0x000cb2ff,
+    0x000a1451,
+    0x00080000,
+    0x00065980,
+    0x00050a29,
+    0x00040000,
+    0x00032cc0,
+    0x00028514,
+    0x00020000,
+    0x00019660,
+    0x0001428a,
+    0x00010000,
+    0x0000cb30,
+    0x0000a145,
+    0x00008000,
+    0x00006598,
+    0x000050a3,
+    0x00004000,
+    0x000032cc,
+    0x00002851,
+    0x00002000,
+    0x00001966,
+    0x00001429,
+    0x00001000,
+    0x00000cb3,
+    0x00000a14,
+    0x00000800,
+    0x00000659,
+    0x0000050a,
+    0x00000400,
+    0x0000032d,
+    0x00000285,
+    0x00000200,
+    0x00000196,
+    0x00000143,
+];
+
+// 1 / (2 cos((2k + 1)π / 2n)) in fixed point for each stage of `dct32`: the
+// stage of size n keeps its n / 2 values starting at index n / 2 - 1.
+const SECANTS: [i64; 31] = [
+    0x0B504F33, 0x08A8BD3E, 0x14E7AE91, 0x08281F69, 0x099F1BD1, 0x0E664D78, 0x2901B3A1, 0x0809E8CE,
+    0x085C2782, 0x091233E9, 0x0A5961CC, 0x0C9C4806, 0x10F8892A, 0x1B8F24B0, 0x519E4E04, 0x0802784A,
+    0x081668B8, 0x083F45AE, 0x087F2684, 0x08D98379, 0x0953B39E, 0x09F5C6E2, 0x0ACC03D5, 0x0BE99EDD,
+    0x0D6DF9E5, 0x0F8FA3B6, 0x12B606A6, 0x17BF2366, 0x20ECABC8, 0x36859068, 0xA30A45F5,
+];
+
+// Matrixing coefficients, cos((16 + i) * (2 * j + 1) * pi / 64) in fixed
+// point, as worked out with single precision cosf. Synthesis goes through
+// `dct32` instead; these are what it gets checked against. Stored
+// subband-major.
+#[cfg(test)]
+pub(crate) static N: [[i64; 64]; 32] = [
+    [
+        0x0B504F30, 0x0ABEB490, 0x0A267990, 0x0987FC00, 0x08E39D90, 0x0839C3C0, 0x078AD738, 0x06D74408,
+        0x061F78A8, 0x0563E698, 0x04A50180, 0x03E33F20, 0x031F16F0, 0x02590214, 0x01917A6A, 0x00C8FB29,
+       -0x0000000B,-0x00C8FB41,-0x01917A82,-0x02590228,-0x031F1708,-0x03E33F34,-0x04A50198,-0x0563E6B0,
+       -0x061F78C0,-0x06D74400,-0x078AD768,-0x0839C3D0,-0x08E39DC0,-0x0987FC10,-0x0A267990,-0x0ABEB4B0,
+       -0x0B504F30,-0x0BDAEFB0,-0x0C5E4040,-0x0CD9F040,-0x0D4DB320,-0x0DB941A0,-0x0E1C5990,-0x0E76BD80,
+       -0x0EC83600,-0x0F109090,-0x0F4FA0B0,-0x0F853F80,-0x0FB14BF0,-0x0FD3AAC0,-0x0FEC46D0,-0x0FFB10F0,
+       -0x10000000,-0x0FFB10F0,-0x0FEC46D0,-0x0FD3AAC0,-0x0FB14BE0,-0x0F853F80,-0x0F4FA0A0,-0x0F109080,
+       -0x0EC835E0,-0x0E76BD70,-0x0E1C5970,-0x0DB94190,-0x0D4DB300,-0x0CD9F020,-0x0C5E4020,-0x0BDAEF90,
+    ],
+    [
+       -0x0B504F30,-0x0CD9F040,-0x0E1C5990,-0x0F109090,-0x0FB14BF0,-0x0FFB10F0,-0x0FEC46D0,-0x0F853F80,
+       -0x0EC835E0,-0x0DB94190,-0x0C5E4020,-0x0ABEB490,-0x08E39D60,-0x06D743D8,-0x04A50168,-0x025901FC,
+        0x00000003, 0x02590200, 0x04A501E8, 0x06D74450, 0x08E39DD0, 0x0ABEB4C0, 0x0C5E4050, 0x0DB941B0,
+        0x0EC835F0, 0x0F853F80, 0x0FEC46E0, 0x0FFB10F0, 0x0FB14BE0, 0x0F109070, 0x0E1C5960, 0x0CD9F010,
+        0x0B504F30, 0x0987FBA0, 0x078AD6F8, 0x0563E650, 0x031F16C4, 0x00C8FAFA,-0x01917A92,-0x03E33F44,
+       -0x061F7928,-0x0839C3C0,-0x0A2679E0,-0x0BDAEF70,-0x0D4DB340,-0x0E76BDC0,-0x0F4FA0B0,-0x0FD3AAD0,
+       -0x10000000,-0x0FD3AAB0,-0x0F4FA0B0,-0x0E76BD50,-0x0D4DB2A0,-0x0BDAEF70,-0x0A267910,-0x0839C3C0,
+       -0x061F7830,-0x03E33F38,-0x01917A04, 0x00C8FB07, 0x031F174C, 0x0563E748, 0x078AD770, 0x0987FC80,
+    ],
+    [
+       -0x0B504F10,-0x0839C3E0,-0x04A50168,-0x00C8FAD2, 0x031F1720, 0x06D74450, 0x0A2679A0, 0x0CD9F050,
+        0x0EC835F0, 0x0FD3AAD0, 0x0FEC46D0, 0x0F109070, 0x0D4DB2D0, 0x0ABEB480, 0x078AD6F8, 0x03E33F10,
+       -0x0000005A,-0x03E33F44,-0x078AD720,-0x0ABEB500,-0x0D4DB340,-0x0F109080,-0x0FEC46E0,-0x0FD3AAB0,
+       -0x0EC835D0,-0x0CD9F030,-0x0A267910,-0x06D743A8,-0x031F16EC, 0x00C8FB07, 0x04A50218, 0x0839C410,
+        0x0B504F40, 0x0DB94200, 0x0F4FA0D0, 0x0FFB10F0, 0x0FB14BF0, 0x0E76BD30, 0x0C5E3FF0, 0x0987FBE0,
+        0x061F77E0, 0x02590178,-0x01917AC0,-0x0563E6A8,-0x08E39E40,-0x0BDAEFF0,-0x0E1C5990,-0x0F853F80,
+       -0x10000000,-0x0F853F60,-0x0E1C5960,-0x0BDAEEF0,-0x08E39DF0,-0x0563E648,-0x01917956, 0x025901E0,
+        0x061F7928, 0x0987FD00, 0x0C5E4030, 0x0E76BDD0, 0x0FB14C30, 0x0FFB10F0, 0x0F4FA060, 0x0DB941D0,
+    ],
+    [
+        0x0B504F70, 0x0E76BDA0, 0x0FEC46E0, 0x0F853F60, 0x0D4DB2D0, 0x0987FBA0, 0x04A50190,-0x00C8FB30,
+       -0x061F7928,-0x0ABEB500,-0x0E1C59C0,-0x0FD3AAD0,-0x0FB14BD0,-0x0DB94150,-0x0A267910,-0x0563E5F8,
+        0x000000B2, 0x0563E748, 0x0A267A20, 0x0DB94200, 0x0FB14C10, 0x0FD3AAA0, 0x0E1C5910, 0x0ABEB400,
+        0x061F77E0, 0x00C8FA4C,-0x04A50268,-0x0987FBF0,-0x0D4DB310,-0x0F853F80,-0x0FEC46D0,-0x0E76BD70,
+       -0x0B504E70,-0x06D743F0,-0x01917956, 0x03E33F4C, 0x08E39E90, 0x0CD9F040, 0x0F4FA100, 0x0FFB10F0,
+        0x0EC83570, 0x0BDAEF70, 0x078AD630, 0x025901C8,-0x031F1850,-0x0839C410,-0x0C5E4110,-0x0F1090A0,
+       -0x10000000,-0x0F109060,-0x0C5E3F50,-0x0839C360,-0x031F1594, 0x0259028C, 0x078AD8A8, 0x0BDAEFF0,
+        0x0EC83680, 0x0FFB1100, 0x0F4FA030, 0x0CD9EFC0, 0x08E39C40, 0x03E33E88,-0x01917C1C,-0x06D744A8,
+    ],
+    [
+        0x0B504F30, 0x0563E650,-0x01917A92,-0x0839C3C0,-0x0D4DB340,-0x0FD3AAD0,-0x0F4FA0B0,-0x0BDAEF70,
+       -0x061F7830, 0x00C8FB07, 0x078AD770, 0x0CD9F070, 0x0FB14C10, 0x0F853F70, 0x0C5E3FF0, 0x06D74358,
+       -0x00000009,-0x06D74450,-0x0C5E40A0,-0x0F853F80,-0x0FB14BE0,-0x0CD9EFC0,-0x078AD760,-0x00C8F9F4,
+        0x061F7928, 0x0BDAEF80, 0x0F4FA100, 0x0FD3AAB0, 0x0D4DB330, 0x0839C2D0, 0x019179FE,-0x0563E660,
+       -0x0B504FF0,-0x0F1090A0,-0x0FEC46E0,-0x0DB94120,-0x08E39D60,-0x02590074, 0x04A50270, 0x0ABEB4D0,
+        0x0EC83680, 0x0FFB10E0, 0x0E1C5960, 0x0987FAD0, 0x031F1638,-0x03E33F50,-0x0A267AB0,-0x0E76BDD0,
+       -0x10000000,-0x0E76BCE0,-0x0A267900,-0x03E33F2C, 0x031F1854, 0x0987FC80, 0x0E1C5970, 0x0FFB1100,
+        0x0EC835B0, 0x0ABEB4B0, 0x04A50060,-0x0259048C,-0x08E39D80,-0x0DB94240,-0x0FEC4710,-0x0F109090,
+    ],
+    [
+       -0x0B504FB0,-0x0F853F90,-0x0F4FA0B0,-0x0ABEB440,-0x031F16EC, 0x0563E748, 0x0C5E4070, 0x0FD3AAC0,
+        0x0EC835B0, 0x0987FBE0, 0x019179AE,-0x06D74450,-0x0D4DB310,-0x0FFB1100,-0x0E1C5960,-0x0839C400,
+        0x00000161, 0x0839C4A0, 0x0E1C59C0, 0x0FFB10F0, 0x0D4DB330, 0x06D742C0,-0x01917B6E,-0x0987FC80,
+       -0x0EC83600,-0x0FD3AAC0,-0x0C5E3F50,-0x0563E5A0, 0x031F17AC, 0x0ABEB4D0, 0x0F4FA0A0, 0x0F853F20,
+        0x0B504E70, 0x03E33E88,-0x04A501D0,-0x0BDAEF80,-0x0FB14C30,-0x0F109020,-0x0A267900,-0x025901C0,
+        0x061F7898, 0x0CD9F110, 0x0FEC46F0, 0x0E76BD30, 0x08E39D50, 0x00C8F93F,-0x078AD6E8,-0x0DB94240,
+       -0x10000000,-0x0DB94170,-0x078AD590, 0x00C8FAC3, 0x08E39E90, 0x0E76BEB0, 0x0FEC46D0, 0x0CD9EEF0,
+        0x061F7908,-0x02590340,-0x0A267BC0,-0x0F1090A0,-0x0FB14B80,-0x0BDAEFD0,-0x04A50060, 0x03E341F0,
+    ],
+    [
+       -0x0B504EF0,-0x025901CC, 0x078AD770, 0x0E76BD80, 0x0FB14BF0, 0x0ABEB400, 0x019179AE,-0x0839C460,
+       -0x0EC83620,-0x0F853F60,-0x0A267890,-0x00C8F9F4, 0x08E39E90, 0x0F1090E0, 0x0F4FA060, 0x0987FB50,
+       -0x000000B8,-0x0987FC80,-0x0F4FA0D0,-0x0F109060,-0x08E39D60, 0x00C8FB65, 0x0A2679A0, 0x0F853F80,
+        0x0EC835F0, 0x0839C400,-0x01917C1C,-0x0ABEB5D0,-0x0FB14C30,-0x0E76BCE0,-0x078AD628, 0x02590338,
+        0x0B504FF0, 0x0FD3AAE0, 0x0E1C5910, 0x06D74350,-0x031F19A8,-0x0BDAEFF0,-0x0FEC4710,-0x0DB94170,
+       -0x061F7690, 0x03E33F58, 0x0C5E4180, 0x0FFB10F0, 0x0D4DB210, 0x0563E6D8,-0x04A50320,-0x0CD9EFE0,
+       -0x10000000,-0x0CD9F090,-0x04A50060, 0x0563E990, 0x0D4DB3A0, 0x0FFB10D0, 0x0C5E3FB0, 0x03E33C8C,
+       -0x061F7940,-0x0DB942F0,-0x0FEC46C0,-0x0BDAEE00,-0x031F16D4, 0x06D745E8, 0x0E1C5980, 0x0FD3AA70,
+    ],
+    [
+        0x0B504F40, 0x0FFB10F0, 0x0C5E3FF0, 0x02590178,-0x08E39E40,-0x0F853F80,-0x0E1C5960,-0x0563E648,
+        0x061F7928, 0x0E76BDD0, 0x0F4FA060, 0x0839C2D0,-0x031F1850,-0x0CD9F100,-0x0FEC46E0,-0x0ABEB4B0,
+        0x00000010, 0x0ABEB4D0, 0x0FEC46E0, 0x0CD9EFC0, 0x031F1638,-0x0839C4B0,-0x0F4FA100,-0x0E76BCE0,
+       -0x061F7738, 0x0563E848, 0x0E1C5970, 0x0F853F70, 0x08E39D50,-0x0259048C,-0x0C5E40B0,-0x0FFB10D0,
+       -0x0B504E70,-0x00C8FBE7, 0x0A267AB0, 0x0FD3AAB0, 0x0D4DB210, 0x03E33F24,-0x078AD948,-0x0F1090A0,
+       -0x0EC834E0,-0x06D74348, 0x04A50468, 0x0DB94240, 0x0FB14C00, 0x0987FAC0,-0x01917A2C,-0x0BDAF0E0,
+       -0x10000000,-0x0BDAEE00,-0x019179EC, 0x0987FE30, 0x0FB14C10, 0x0DB94010, 0x04A50058,-0x06D74720,
+       -0x0EC83690,-0x0F109090,-0x078AD588, 0x03E33F64, 0x0D4DB470, 0x0FD3AAB0, 0x0A267770,-0x00C8FC27,
+    ],
+    [
+        0x0B504EB0,-0x00C8FB5E,-0x0C5E40A0,-0x0FD3AAC0,-0x08E39DF0, 0x03E33F4C, 0x0E1C59C0, 0x0F109020,
+        0x061F7738,-0x06D74408,-0x0F4FA0D0,-0x0DB94120,-0x031F1594, 0x0987FBF0, 0x0FEC46E0, 0x0BDAEEF0,
+       -0x00000167,-0x0BDAEF80,-0x0FEC46D0,-0x0987FB50, 0x031F1854, 0x0DB94290, 0x0F4FA090, 0x06D74350,
+       -0x061F77F8,-0x0F109120,-0x0E1C5960,-0x03E33C90, 0x08E39E90, 0x0FD3AAB0, 0x0C5E3EE0, 0x00C8FA90,
+       -0x0B505160,-0x0FFB10E0,-0x0A267980, 0x02590494, 0x0D4DB3A0, 0x0F853FA0, 0x078AD588,-0x0563E710,
+       -0x0EC83710,-0x0E76BCE0,-0x04A501A0, 0x0839C5E0, 0x0FB14C10, 0x0CD9EE20, 0x01917896,-0x0ABEB4E0,
+       -0x10000000,-0x0ABEB3A0, 0x01917A32, 0x0CD9F180, 0x0FB14BC0, 0x0839C110,-0x04A50328,-0x0E76BD90,
+       -0x0EC834E0,-0x0563E588, 0x078ADA80, 0x0F854000, 0x0D4DB2C0, 0x025902FC,-0x0A267AC0,-0x0FFB1120,
+    ],
+    [
+       -0x0B504F70,-0x0FD3AAC0,-0x078AD760, 0x06D744A0, 0x0FB14C30, 0x0BDAEF70,-0x01917B6E,-0x0DB94180,
+       -0x0EC835B0,-0x03E33DE4, 0x0A2679A0, 0x0FFB10E0, 0x08E39C40,-0x0563E708,-0x0F4FA100,-0x0CD9F030,
+        0x000000BE, 0x0CD9F110, 0x0F4FA090, 0x0563E5A0,-0x08E39D80,-0x0FFB1110,-0x0A267880, 0x03E33F58,
+        0x0EC83700, 0x0DB940C0, 0x019179F2,-0x0BDAEF10,-0x0FB14B80,-0x06D74348, 0x078AD6F0, 0x0FD3AB10,
+        0x0B504E60,-0x025901F4,-0x0E1C5AC0,-0x0E76BCE0,-0x031F16D4, 0x0ABEB6D0, 0x0FEC46A0, 0x0839C350,
+       -0x061F7BB8,-0x0F854000,-0x0C5E3FB0, 0x00C8FAD0, 0x0D4DB470, 0x0F109020, 0x04A50198,-0x0987FE30,
+       -0x10000000,-0x0987FBC0, 0x04A50478, 0x0F109120, 0x0D4DB2C0, 0x00C8FBD4,-0x0C5E4190,-0x0F853E50,
+       -0x061F78F8, 0x0839C5E0, 0x0FEC4750, 0x0ABEB4A0,-0x031F19C0,-0x0E76BFE0,-0x0E1C5950,-0x0258FF00,
+    ],
+    [
+       -0x0B504E70, 0x03E33F4C, 0x0F4FA100, 0x0BDAEF70,-0x031F1850,-0x0F1090A0,-0x0C5E3F50, 0x0259028C,
+        0x0EC83680, 0x0CD9EFC0,-0x01917C1C,-0x0E76BDD0,-0x0D4DB210, 0x00C8FC14, 0x0E1C5970, 0x0DB94120,
+       -0x00000216,-0x0DB94240,-0x0E1C5960,-0x00C8FBE7, 0x0D4DB460, 0x0E76BCE0, 0x019179F2,-0x0CD9EFE0,
+       -0x0EC834E0,-0x02590064, 0x0C5E40B0, 0x0F109090, 0x031F1438,-0x0BDAF0E0,-0x0F4FA060,-0x03E33F20,
+        0x0B505170, 0x0F853EF0, 0x04A50058,-0x0ABEB4E0,-0x0FB14B40,-0x0563E450, 0x0A267AC0, 0x0FD3AAB0,
+        0x061F7548,-0x0987FE30,-0x0FEC46A0,-0x06D74340, 0x08E39D90, 0x0FFB10D0, 0x078AD580,-0x0839C4C0,
+       -0x10000000,-0x0839C470, 0x078AD958, 0x0FFB1130, 0x08E39D40,-0x06D74730,-0x0FEC46B0,-0x0987FAB0,
+        0x061F7D00, 0x0FD3AAB0, 0x0A267760,-0x0563EC28,-0x0FB14C10,-0x0ABEB1A0, 0x04A500B0, 0x0F854000,
+    ],
+    [
+        0x0B504F00, 0x0F109020, 0x019179FE,-0x0DB94180,-0x0D4DB270, 0x0259028C, 0x0F4FA0A0, 0x0ABEB3B0,
+       -0x061F7930,-0x0FFB10F0,-0x078AD628, 0x0987FC80, 0x0FB14BE0, 0x03E33DE0,-0x0C5E40B0,-0x0E76BD70,
+       -0x00000092, 0x0E76BEB0, 0x0C5E3EE0,-0x03E340A4,-0x0FB14C10,-0x0987FBD0, 0x078AD6F0, 0x0FFB10D0,
+        0x061F7690,-0x0ABEB5D0,-0x0F4FA060,-0x025901B4, 0x0D4DB2F0, 0x0DB94010,-0x01917CD6,-0x0F109120,
+       -0x0B504E60, 0x0563E718, 0x0FEC46D0, 0x0839C110,-0x08E39FC0,-0x0FD3AA70,-0x04A50050, 0x0BDAF000,
+        0x0EC835E0, 0x00C8FBD4,-0x0E1C5AC0,-0x0CD9F150, 0x031F1870, 0x0F8540A0, 0x0A267970,-0x06D74730,
+       -0x10000000,-0x06D74200, 0x0A267DE0, 0x0F853F40, 0x031F12D4,-0x0CD9EFF0,-0x0E1C5810, 0x00C8F98C,
+        0x0EC83690, 0x0BDAEC30,-0x04A501F8,-0x0FD3AB50,-0x08E39E50, 0x0839C5F0, 0x0FEC4640, 0x0563E578,
+    ],
+    [
+        0x0B504EF0,-0x06D74408,-0x0FEC46E0,-0x03E33DE4, 0x0D4DB3A0, 0x0CD9EFC0,-0x04A501D0,-0x0FFB10F0,
+       -0x061F7738, 0x0BDAF060, 0x0E1C5910,-0x0259048C,-0x0FB14C50,-0x0839C240, 0x0A267AB0, 0x0F109020,
+       -0x000000C5,-0x0F1090A0,-0x0A267980, 0x0839C390, 0x0FB14C00, 0x0258FF14,-0x0E1C5AC0,-0x0BDAEE00,
+        0x061F7A78, 0x0FFB10E0, 0x04A50058,-0x0CD9F0B0,-0x0D4DB2C0, 0x03E33F64, 0x0FEC46D0, 0x06D740D8,
+       -0x0B505170,-0x0E76BC40, 0x01917CDE, 0x0F854000, 0x08E39C20,-0x0987FD20,-0x0F4F9F30,-0x00C8FA7D,
+        0x0EC83790, 0x0ABEB4A0,-0x078ADA88,-0x0FD3AAE0,-0x031F1424, 0x0DB940F0, 0x0C5E3ED0,-0x0563EC28,
+       -0x10000000,-0x0563E1B8, 0x0C5E40C0, 0x0DB93F50,-0x031F172C,-0x0FD3AB50,-0x078AD7D0, 0x0ABEB6E0,
+        0x0EC83660,-0x00C8FD91,-0x0F4FA270,-0x0987FAA0, 0x08E3A210, 0x0F853F40, 0x019175D0,-0x0E76BDA0,
+    ],
+    [
+       -0x0B504FF0,-0x0DB94120, 0x04A50270, 0x0FFB10E0, 0x031F1638,-0x0E76BDD0,-0x0A267900, 0x0987FC80,
+        0x0EC835B0,-0x0259048C,-0x0FEC4710,-0x0563E458, 0x0D4DB460, 0x0BDAEE00,-0x078AD948,-0x0F853EF0,
+        0x0000021C, 0x0F854000, 0x078AD588,-0x0BDAF0E0,-0x0D4DB210, 0x0563E850, 0x0FEC46A0, 0x02590060,
+       -0x0EC83690,-0x0987FAC0, 0x0A267AC0, 0x0E76BCE0,-0x031F1868,-0x0FFB1100,-0x04A50050, 0x0DB94240,
+        0x0B504E60,-0x0839C4C0,-0x0F4F9F30, 0x00C8FC2D, 0x0FB14CE0, 0x06D74338,-0x0C5E4340,-0x0CD9EFB0,
+        0x061F7D00, 0x0FD3AAA0, 0x019175DE,-0x0F1090B0,-0x08E399E0, 0x0ABEB4E0, 0x0E1C5770,-0x03E33F78,
+       -0x10000000,-0x03E33F08, 0x0E1C5B70, 0x0ABEB490,-0x08E3A0F0,-0x0F109090, 0x01917E46, 0x0FD3AAC0,
+        0x061F7530,-0x0CD9EFF0,-0x0C5E3DE0, 0x06D743A0, 0x0FB14B40, 0x00C8FBBA,-0x0F4FA1B0,-0x0839C460,
+    ],
+    [
+       -0x0B504F60, 0x0987FBF0, 0x0E1C5960,-0x0563E708,-0x0FB14BC0, 0x00C8FC14, 0x0FEC46F0, 0x03E33DE0,
+       -0x0EC83680,-0x0839C240, 0x0C5E4180, 0x0BDAEE00,-0x08E39FB0,-0x0E76BC50, 0x04A50468, 0x0FD3AA40,
+        0x0000008B,-0x0FD3AAB0,-0x04A501A0, 0x0E76BD90, 0x08E39D50,-0x0BDAF000,-0x0C5E3FB0, 0x0839C4C0,
+        0x0EC83560,-0x03E340B0,-0x0FEC46A0,-0x00C8F92C, 0x0FB14C60, 0x0563E808,-0x0E1C5AC0,-0x0987FCD0,
+        0x0B505170, 0x0CD9F080,-0x078ADA88,-0x0F109090, 0x031F1B10, 0x0FFB10F0, 0x019175DE,-0x0F853FB0,
+       -0x061F7400, 0x0DB94250, 0x0A267550,-0x0ABEB5E0,-0x0D4DAFC0, 0x06D74600, 0x0F4F9ED0,-0x025904B4,
+       -0x10000000,-0x0258FEF4, 0x0F4FA080, 0x06D740C0,-0x0D4DB300,-0x0ABEB190, 0x0A2679D0, 0x0DB93F50,
+       -0x061F7960,-0x0F853E40, 0x01917BA8, 0x0FFB1130, 0x031F155C,-0x0F109280,-0x078AD560, 0x0CD9F3F0,
+    ],
+    [
+        0x0B504F80, 0x0BDAEEF0,-0x0A267AB0,-0x0CD9F030, 0x08E39E00, 0x0DB94120,-0x078AD6E8,-0x0E76BD70,
+        0x061F7938, 0x0F109020,-0x04A50320,-0x0F853EF0, 0x031F19AC, 0x0FD3AA40,-0x01917A2C,-0x0FFB10F0,
+        0x000000CB, 0x0FFB1100, 0x01917896,-0x0FD3AB20,-0x031F1430, 0x0F854050, 0x04A50198,-0x0F1090B0,
+       -0x061F77C0, 0x0E76BE20, 0x078AD580,-0x0DB942F0,-0x08E39E60, 0x0CD9F250, 0x0A267970,-0x0BDAF2B0,
+       -0x0B504E60, 0x0ABEB8D0, 0x0C5E3ED0,-0x0987FB00,-0x0D4DB140, 0x0839C3A0, 0x0E1C5770,-0x06D744C8,
+       -0x0EC833D0, 0x0563E868, 0x0F4F9ED0,-0x03E34210,-0x0FB14C00, 0x02590608, 0x0FEC46C0,-0x00C9003F,
+       -0x10000000,-0x00C8F514, 0x0FEC4710, 0x025902E0,-0x0FB14CA0,-0x03E33EF8, 0x0F4FA210, 0x0563E570,
+       -0x0EC83820,-0x06D741E8, 0x0E1C5CB0, 0x0839C0F0,-0x0D4DB300,-0x0987F870, 0x0C5E40D0, 0x0ABEB090,
+    ],
+    [
+        0x0B504E70,-0x0BDAEF80,-0x0A267900, 0x0CD9F110, 0x08E39D50,-0x0DB94240,-0x078AD590, 0x0E76BEB0,
+        0x061F7908,-0x0F1090A0,-0x04A50060, 0x0F854000, 0x031F1438,-0x0FD3AAB0,-0x019179EC, 0x0FFB1100,
+       -0x00000223,-0x0FFB10D0, 0x01917A32, 0x0FD3AAB0,-0x031F1868,-0x0F853EF0, 0x04A50478, 0x0F109090,
+       -0x061F7948,-0x0E76BB20, 0x078AD958, 0x0DB94210,-0x08E3A0E0,-0x0CD9EFB0, 0x0A267DE0, 0x0BDAEDF0,
+       -0x0B504EA0,-0x0ABEB1A0, 0x0C5E40C0, 0x0987F770,-0x0D4DB470,-0x0839C460, 0x0E1C5B70, 0x06D74330,
+       -0x0EC83820,-0x0563E438, 0x0F4FA080, 0x03E33B20,-0x0FB14C20,-0x0258FC4C, 0x0FEC4710, 0x00C8FBBA,
+       -0x10000000, 0x00C8FC47, 0x0FEC4640,-0x025904C0,-0x0FB14C00, 0x03E3436C, 0x0F4FA050,-0x0563EC40,
+       -0x0EC834D0, 0x06D743A8, 0x0E1C5760,-0x0839C4E0,-0x0D4DAFB0, 0x0987FE50, 0x0C5E4060,-0x0ABEB7F0,
+    ],
+    [
+       -0x0B505070,-0x0987FB50, 0x0E1C5970, 0x0563E5A0,-0x0FB14C50,-0x00C8FBE7, 0x0FEC46D0,-0x03E340A4,
+       -0x0EC834E0, 0x0839C390, 0x0C5E3FB0,-0x0BDAF0E0,-0x08E39B10, 0x0E76BD90, 0x04A50058,-0x0FD3AB20,
+        0x0000037A, 0x0FD3AAB0,-0x04A50328,-0x0E76BC40, 0x08E39D90, 0x0BDAEEE0,-0x0C5E4190,-0x0839C470,
+        0x0EC83790, 0x03E33DC4,-0x0FEC46B0, 0x00C8FEDB, 0x0FB14BC0,-0x0563EC28,-0x0E1C5810, 0x0987FC10,
+        0x0B504B80,-0x0CD9F180,-0x078AD7D0, 0x0F109210, 0x031F1568,-0x0FFB10E0, 0x01917E46, 0x0F853F40,
+       -0x061F7E48,-0x0DB94000, 0x0A2679D0, 0x0ABEB090,-0x0D4DB480,-0x06D74458, 0x0F4FA210, 0x02590038,
+       -0x10000000, 0x02590614, 0x0F4FA050,-0x06D749B0,-0x0D4DB130, 0x0ABEB500, 0x0A267540,-0x0DB94310,
+       -0x061F78D0, 0x0F8540B0, 0x01917862,-0x0FFB1100, 0x031F1B38, 0x0F108D60,-0x078AD5F8,-0x0CD9EE00,
+    ],
+    [
+       -0x0B504EF0, 0x0DB94290, 0x04A50060,-0x0FFB1110, 0x031F170C, 0x0E76BCE0,-0x0A267BC0,-0x0987FBD0,
+        0x0EC83680, 0x0258FF14,-0x0FEC46C0, 0x0563E850, 0x0D4DB150,-0x0BDAF000,-0x078AD588, 0x0F854050,
+       -0x000000D2,-0x0F853EF0, 0x078ADA80, 0x0BDAEEE0,-0x0D4DB470,-0x0563E6C8, 0x0FEC4750,-0x025904A8,
+       -0x0EC835E0, 0x09880060, 0x0A267760,-0x0E76BD90,-0x031F1184, 0x0FFB10D0,-0x04A501F8,-0x0DB93EA0,
+        0x0B505180, 0x0839C340,-0x0F4FA270,-0x00C8F7C2, 0x0FB14BC0,-0x06D749A8,-0x0C5E3DE0, 0x0CD9F0C0,
+        0x061F72B8,-0x0FD3AB50, 0x01917BA8, 0x0F108E40,-0x08E3A100,-0x0ABEB390, 0x0E1C5CB0, 0x03E33B14,
+       -0x10000000, 0x03E34604, 0x0E1C5760,-0x0ABEB600,-0x08E39790, 0x0F109210, 0x01917862,-0x0FD3AAD0,
+        0x061F7D28, 0x0CD9EA10,-0x0C5E4000,-0x06D73F70, 0x0FB14DF0,-0x00C8FB09,-0x0F4F9F20, 0x0839CCF0,
+    ],
+    [
+        0x0B504FF0, 0x06D74350,-0x0FEC4710, 0x03E33F58, 0x0D4DB210,-0x0CD9EFE0,-0x04A50060, 0x0FFB10D0,
+       -0x061F7940,-0x0BDAEE00, 0x0E1C5980, 0x02590060,-0x0FB14B40, 0x0839C4C0, 0x0A267770,-0x0F1090B0,
+        0x00000229, 0x0F109090,-0x0A267AC0,-0x0839C470, 0x0FB14CE0,-0x025904A8,-0x0E1C5950, 0x0BDAF3A0,
+        0x061F7540,-0x0FFB1100, 0x04A500B0, 0x0CD9ED50,-0x0D4DB470,-0x03E33F08, 0x0FEC4640,-0x06D74738,
+       -0x0B504E50, 0x0E76BD00, 0x019175D0,-0x0F853EF0, 0x08E39DA0, 0x0987F770,-0x0F4FA1B0, 0x00C8FC47,
+        0x0EC83350,-0x0ABEB7F0,-0x078AD560, 0x0FD3AAC0,-0x031F1C7C,-0x0DB93FF0, 0x0C5E40D0, 0x0563E060,
+       -0x10000000, 0x0563E880, 0x0C5E4060,-0x0DB94050,-0x031F1400, 0x0FD3A970,-0x078AD5F8,-0x0ABEB180,
+        0x0EC839B0, 0x00C8FBA0,-0x0F4F9F20, 0x098804D0, 0x08E39D10,-0x0F854100, 0x01918662, 0x0E76BCC0,
+    ],
+    [
+        0x0B504F60,-0x0F109120, 0x01917A24, 0x0DB940C0,-0x0D4DB2F0,-0x02590064, 0x0F4FA0C0,-0x0ABEB5D0,
+       -0x061F7900, 0x0FFB10E0,-0x078AD6F0,-0x0987FAC0, 0x0FB14CA0,-0x03E340B0,-0x0C5E3DF0, 0x0E76BE20,
+        0x0000007E,-0x0E76BB20, 0x0C5E4270, 0x03E33DC4,-0x0FB14C00, 0x09880060, 0x078AD448,-0x0FFB1100,
+        0x061F7818, 0x0ABEB0A0,-0x0F4FA1A0, 0x02590360, 0x0D4DB370,-0x0DB94460,-0x01917726, 0x0F109010,
+       -0x0B504EB0,-0x0563E1B0, 0x0FEC4680,-0x0839C4D0,-0x08E39E40, 0x0FD3AB80,-0x04A50490,-0x0BDAEED0,
+        0x0EC838A0,-0x00C9004C,-0x0E1C5800, 0x0CD9F0D0, 0x031F1018,-0x0F853E40, 0x0A267BF0, 0x06D74318,
+       -0x10000000, 0x06D74888, 0x0A267110,-0x0F853FC0, 0x031F1DD8, 0x0CD9F200,-0x0E1C5AE0,-0x00C8F24C,
+        0x0EC83650,-0x0BDAF2D0,-0x04A4F728, 0x0FD3AAA0,-0x08E3A350,-0x0839C690, 0x0FEC4710,-0x0563EEE0,
+    ],
+    [
+       -0x0B504F80,-0x03E33C90, 0x0F4FA060,-0x0BDAEF10,-0x031F1588, 0x0F109090,-0x0C5E4180,-0x025901B4,
+        0x0EC834E0,-0x0CD9F0B0,-0x01917740, 0x0E76BCE0,-0x0D4DB2F0,-0x00C8F92C, 0x0E1C5950,-0x0DB942F0,
+        0x000000D8, 0x0DB94210,-0x0E1C5C00, 0x00C8FEDB, 0x0D4DB200,-0x0E76BD90, 0x019178EE, 0x0CD9ED50,
+       -0x0EC83710, 0x02590360, 0x0C5E4070,-0x0F109280, 0x031F1B1C, 0x0BDAEDF0,-0x0F4FA0E0, 0x03E33E34,
+        0x0B504B80,-0x0F854060, 0x04A50348, 0x0ABEB490,-0x0FB14D20, 0x0563EB00, 0x0A267750,-0x0FD3AAF0,
+        0x061F7830, 0x0987F760,-0x0FEC4730, 0x06D74618, 0x08E39D20,-0x0FFB10E0, 0x078ADBD8, 0x0839BA00,
+       -0x10000000, 0x0839CAA0, 0x078AD8D8,-0x0FFB10C0, 0x08E3A690, 0x06D74308,-0x0FEC4620, 0x0987FA10,
+        0x061F7510,-0x0FD3A940, 0x0A2679F0, 0x0563E048,-0x0FB14C80, 0x0ABEB710, 0x04A4F868,-0x0F853F80,
+    ],
+    [
+       -0x0B504E70, 0x0FD3AAB0,-0x078AD948,-0x06D74348, 0x0FB14C00,-0x0BDAF0E0,-0x019179EC, 0x0DB94010,
+       -0x0EC83690, 0x03E33F64, 0x0A267770,-0x0FFB1100, 0x08E39D90, 0x0563E808,-0x0F4F9F30, 0x0CD9F250,
+       -0x00000230,-0x0CD9EFB0, 0x0F4FA080,-0x0563EC28,-0x08E399E0, 0x0FFB10D0,-0x0A267AD0,-0x03E33F08,
+        0x0EC83660,-0x0DB94460, 0x01917E46, 0x0BDAEDF0,-0x0FB14C20, 0x06D743A0, 0x078AD1E0,-0x0FD3AA10,
+        0x0B505180, 0x02590038,-0x0E1C5940, 0x0E76C070,-0x031F1C7C,-0x0ABEB190, 0x0FEC4710,-0x0839C4E0,
+       -0x061F78D0, 0x0F853DF0,-0x0C5E3E50,-0x00C8F7A8, 0x0D4DAD70,-0x0F1090C0, 0x04A50878, 0x0987FDC0,
+       -0x10000000, 0x098804D0, 0x04A50018,-0x0F108DD0, 0x0D4DB250,-0x00C90065,-0x0C5E38C0, 0x0F854010,
+       -0x061F80E8,-0x0839C440, 0x0FEC4640,-0x0ABEBDF0,-0x031F13E4, 0x0E76B950,-0x0E1C59A0, 0x025908E0,
+    ],
+    [
+        0x0B505070, 0x00C8FA90,-0x0C5E4090, 0x0FD3AB10,-0x08E39EA0,-0x03E33F20, 0x0E1C5810,-0x0F109120,
+        0x061F7940, 0x06D740D8,-0x0F4FA000, 0x0DB94240,-0x031F171C,-0x0987FCD0, 0x0FEC4640,-0x0BDAF2B0,
+        0x00000387, 0x0BDAEDF0,-0x0FEC46F0, 0x0987FC10, 0x031F180C,-0x0DB93EA0, 0x0F4FA200,-0x06D74738,
+       -0x061F7670, 0x0F109010,-0x0E1C5990, 0x03E33E34, 0x08E398C0,-0x0FD3AA10, 0x0C5E4280,-0x00C8FD9E,
+       -0x0B504E50, 0x0FFB10F0,-0x0A267F00,-0x0258FC40, 0x0D4DB070,-0x0F854060, 0x078AD978, 0x0563E560,
+       -0x0EC835D0, 0x0E76C080,-0x04A4FF88,-0x0839BFB0, 0x0FB149A0,-0x0CD9F1A0, 0x019183B6, 0x0ABEB470,
+       -0x10000000, 0x0ABEB310, 0x019175AA,-0x0CD9E930, 0x0FB14C60,-0x0839CBD0,-0x04A50158, 0x0E76BA70,
+       -0x0EC83520, 0x0563EB20, 0x078ACD08,-0x0F853EE0, 0x0D4DB850,-0x0259024C,-0x0A267410, 0x0FFB1110,
+    ],
+    [
+        0x0B504D80,-0x0FFB10E0, 0x0C5E40B0,-0x025901F4,-0x08E39B10, 0x0F853EF0,-0x0E1C5A20, 0x0563E718,
+        0x061F7548,-0x0E76BC40, 0x0F4FA140,-0x0839C4C0,-0x031F16C4, 0x0CD9F080,-0x0FEC46B0, 0x0ABEB8D0,
+       -0x000004DE,-0x0ABEB1A0, 0x0FEC4680,-0x0CD9F180, 0x031F187C, 0x0839C340,-0x0F4FA0C0, 0x0E76BD00,
+       -0x061F7E48,-0x0563E1B0, 0x0E1C5760,-0x0F854060, 0x08E39FE0, 0x02590038,-0x0C5E3F90, 0x0FFB10F0,
+       -0x0B505460, 0x00C901A3, 0x0A267540,-0x0FD3AA10, 0x0D4DB540,-0x03E34228,-0x078AD558, 0x0F108D60,
+       -0x0EC83620, 0x06D74AF0, 0x04A502A8,-0x0DB93E90, 0x0FB14B60,-0x0987FF70,-0x01916F0A, 0x0BDAEDD0,
+       -0x10000000, 0x0BDAF030,-0x0191826E,-0x0987FCA0, 0x0FB14AB0,-0x0DB94060, 0x04A50600, 0x06D73958,
+       -0x0EC834C0, 0x0F1093F0,-0x078AD868,-0x03E33704, 0x0D4DB350,-0x0FD3ABC0, 0x0A2677F0, 0x00C8F62B,
+    ],
+    [
+       -0x0B505160, 0x02590494, 0x078AD588,-0x0E76BCE0, 0x0FB14C10,-0x0ABEB4E0, 0x01917A32, 0x0839C110,
+       -0x0EC834E0, 0x0F854000,-0x0A267AC0, 0x00C8FC2D, 0x08E39D40,-0x0F109090, 0x0F4FA080,-0x0987FB00,
+        0x00000636, 0x0987F770,-0x0F4F9F30, 0x0F109210,-0x08E3A0F0,-0x00C8F7C2, 0x0A267760,-0x0F853EF0,
+        0x0EC83690,-0x0839C4D0,-0x019179C6, 0x0ABEB490,-0x0FB14C00, 0x0E76C070,-0x078ADCF8,-0x0258FC40,
+        0x0B504B70,-0x0FD3AA10, 0x0E1C5B70,-0x06D74750,-0x031F1400, 0x0BDAE880,-0x0FEC46A0, 0x0DB94680,
+       -0x061F7978,-0x03E33720, 0x0C5E4060,-0x0FFB1090, 0x0D4DB250,-0x0563EC58,-0x04A503E8, 0x0CD9ED30,
+       -0x10000000, 0x0CD9F270,-0x04A50C60,-0x0563E408, 0x0D4DAD70,-0x0FFB1100, 0x0C5E4600,-0x03E33FB4,
+       -0x061F7148, 0x0DB941F0,-0x0FEC4780, 0x0BDAEE70,-0x031F1CAC,-0x06D73810, 0x0E1C5750,-0x0FD3AC80,
+    ],
+    [
+       -0x0B504F60, 0x0F853FA0,-0x0F4FA1A0, 0x0ABEB6D0,-0x031F19B4,-0x0563E450, 0x0C5E3ED0,-0x0FD3AA70,
+        0x0EC83690,-0x0987FD20, 0x01917B8E, 0x06D74338,-0x0D4DB2C0, 0x0FFB10F0,-0x0E1C5980, 0x0839C3A0,
+        0x00000072,-0x0839C460, 0x0E1C59F0,-0x0FFB10E0, 0x0D4DB6B0,-0x06D749A8,-0x0191747A, 0x0987F770,
+       -0x0EC833D0, 0x0FD3AB80,-0x0C5E4350, 0x0563EB00, 0x031F12BC,-0x0ABEB190, 0x0F4F9F90,-0x0F854060,
+        0x0B505190,-0x03E34228,-0x04A4FEE0, 0x0BDAE880,-0x0FB14B70, 0x0F1093E0,-0x0A267AF0, 0x02590B70,
+        0x061F7788,-0x0CD9EAD0, 0x0FEC46C0,-0x0E76C110, 0x08E39DC0,-0x00C90314,-0x078AD7A0, 0x0DB93DE0,
+       -0x10000000, 0x0DB94520,-0x078AD610,-0x00C8F4E0, 0x08E39F40,-0x0E76BB00, 0x0FEC4690,-0x0CD9F340,
+        0x061F84A8, 0x0258FD60,-0x0A266FF0, 0x0F108F10,-0x0FB14E40, 0x0BDAF210,-0x04A50C78,-0x03E33C20,
+    ],
+    [
+        0x0B504F80,-0x0563E710,-0x019179EC, 0x0839C350,-0x0D4DB2C0, 0x0FD3AAB0,-0x0F4FA0E0, 0x0BDAF000,
+       -0x061F7948,-0x00C8FA7D, 0x078AD6A8,-0x0CD9EFB0, 0x0FB14BC0,-0x0F853FB0, 0x0C5E40C0,-0x06D744C8,
+        0x000000E5, 0x06D74330,-0x0C5E3FA0, 0x0F853F40,-0x0FB14C20, 0x0CD9F0C0,-0x078AD840, 0x00C8FC47,
+        0x061F77A0,-0x0BDAEED0, 0x0F4FA050,-0x0FD3AAF0, 0x0D4DB3C0,-0x0839C4E0, 0x01917BB4, 0x0563E560,
+       -0x0B504E40, 0x0F108D60,-0x0FEC46F0, 0x0DB94680,-0x08E39ED0, 0x02590B70, 0x04A50018,-0x0ABEAD90,
+        0x0EC83550,-0x0FFB1170, 0x0E1C5A40,-0x098803C0, 0x031F18AC, 0x03E335C4,-0x0A267840, 0x0E76B950,
+       -0x10000000, 0x0E76C1B0,-0x0A267B00, 0x03E348C4, 0x031F1528,-0x0987F400, 0x0E1C5890,-0x0FFB1070,
+        0x0EC836B0,-0x0ABEBC10, 0x04A50388, 0x0258F810,-0x08E39BD0, 0x0DB93C70,-0x0FEC46A0, 0x0F1093F0,
+    ],
+    [
+        0x0B504E60,-0x0E76BCE0, 0x0FEC46A0,-0x0F854000, 0x0D4DB470,-0x0987FE30, 0x04A50478, 0x00C8FBD4,
+       -0x061F78F8, 0x0ABEB4A0,-0x0E1C5950, 0x0FD3AAA0,-0x0FB14C10, 0x0DB94250,-0x0A267AD0, 0x0563E868,
+       -0x0000023C,-0x0563E438, 0x0A267760,-0x0DB94000, 0x0FB14B40,-0x0FD3AB50, 0x0E1C5B70,-0x0ABEB7F0,
+        0x061F7D18,-0x00C9004C,-0x04A4FC58, 0x0987F760,-0x0D4DAFB0, 0x0F853DF0,-0x0FEC46B0, 0x0E76C080,
+       -0x0B504EC0, 0x06D74AF0,-0x01917A6A,-0x03E33720, 0x08E39D10,-0x0CD9EAD0, 0x0F4FA050,-0x0FFB1170,
+        0x0EC836A0,-0x0BDAF670, 0x078AD990,-0x02590CD0,-0x031F13E4, 0x0839B9F0,-0x0C5E3DC0, 0x0F108C70,
+       -0x10000000, 0x0F1094D0,-0x0C5E4370, 0x0839CF50,-0x031F1CAC,-0x025903F4, 0x078AD1A8,-0x0BDAF070,
+        0x0EC83330,-0x0FFB1100, 0x0F4FA2E0,-0x0CD9F020, 0x08E3A480,-0x03E33FCC,-0x01917182, 0x06D742D8,
+    ],
+    [
+       -0x0B505070, 0x0839C5E0,-0x04A50470, 0x00C8FAD0, 0x031F16CC,-0x06D74340, 0x0A267870,-0x0CD9F150,
+        0x0EC83670,-0x0FD3AAE0, 0x0FEC46D0,-0x0F1090B0, 0x0D4DB3B0,-0x0ABEB5E0, 0x078AD960,-0x03E34210,
+        0x00000394, 0x03E33B20,-0x078AD310, 0x0ABEB090,-0x0D4DAFC0, 0x0F108E40,-0x0FEC46E0, 0x0FD3AAC0,
+       -0x0EC83610, 0x0CD9F0D0,-0x0A267AE0, 0x06D74618,-0x031F19E4,-0x00C8F7A8, 0x04A50540,-0x0839BFB0,
+        0x0B505110,-0x0DB93E90, 0x0F4FA110,-0x0FFB1090, 0x0FB14BE0,-0x0E76C110, 0x0C5E40E0,-0x098803C0,
+        0x061F7AC0,-0x02590CD0,-0x019176F4, 0x0563DB38,-0x08E399B0, 0x0BDAF160,-0x0E1C56B0, 0x0F853FE0,
+       -0x10000000, 0x0F853F70,-0x0E1C5D70, 0x0BDAF040,-0x08E3A5A0, 0x0563E8B8,-0x01918532,-0x0258FEA8,
+        0x061F6D88,-0x0987F840, 0x0C5E37D0,-0x0E76BAF0, 0x0FB14C30,-0x0FFB1150, 0x0F4FA090,-0x0DB945F0,
+    ],
+    [
+       -0x0B504D70, 0x0CD9EE20,-0x0E1C5960, 0x0F109020,-0x0FB14B80, 0x0FFB10D0,-0x0FEC4730, 0x0F8540A0,
+       -0x0EC83810, 0x0DB940F0,-0x0C5E3FE0, 0x0ABEB4E0,-0x08E39EB0, 0x06D74600,-0x04A50488, 0x02590608,
+       -0x000004EB,-0x0258FC4C, 0x04A4FB18,-0x06D74458, 0x08E39D30,-0x0ABEB390, 0x0C5E3EC0,-0x0DB93FF0,
+        0x0EC83450,-0x0F853E40, 0x0FEC4640,-0x0FFB10E0, 0x0FB14D70,-0x0F1090C0, 0x0E1C5E00,-0x0CD9F1A0,
+        0x0B505740,-0x0987FF70, 0x078AD4D0,-0x0563EC58, 0x031F160C,-0x00C90314,-0x0191799E, 0x03E335C4,
+       -0x061F7640, 0x0839B9F0,-0x0A267630, 0x0BDAF160,-0x0D4DAFA0, 0x0E76BDE0,-0x0F4F9E50, 0x0FD3AAA0,
+       -0x10000000, 0x0FD3AB20,-0x0F4FA410, 0x0E76BF70,-0x0D4DBA90, 0x0BDAF3D0,-0x0A267900, 0x0839CAD0,
+       -0x061F79A8, 0x03E348DC,-0x01917D4A,-0x00C8EF6B, 0x031F1270,-0x0563D9D0, 0x078AD190,-0x0987FC80,
+    ],
+    [
+        0x0B505170,-0x0ABEB4E0, 0x0A267AC0,-0x0987FE30, 0x08E39D90,-0x0839C4C0, 0x078AD958,-0x06D74730,
+        0x061F7D00,-0x0563EC28, 0x04A500B0,-0x03E33F78, 0x031F187C,-0x025904B4, 0x01917E46,-0x00C9003F,
+        0x00000643, 0x00C8FBBA,-0x019179C6, 0x02590038,-0x031F140C, 0x03E33B14,-0x04A4FC58, 0x0563E060,
+       -0x061F78D0, 0x06D74318,-0x078AD558, 0x0839BA00,-0x08E399C0, 0x0987FDC0,-0x0A267430, 0x0ABEB470,
+       -0x0B504890, 0x0BDAEDD0,-0x0C5E38C0, 0x0CD9ED30,-0x0D4DB410, 0x0DB93DE0,-0x0E1C5930, 0x0E76B950,
+       -0x0EC834C0, 0x0F108C70,-0x0F4F9F20, 0x0F853FE0,-0x0FB14A60, 0x0FD3AAA0,-0x0FEC45D0, 0x0FFB10C0,
+       -0x10000000, 0x0FFB1140,-0x0FEC46B0, 0x0FD3ABF0,-0x0FB14C30, 0x0F854210,-0x0F4FA1C0, 0x0F1094E0,
+       -0x0EC83840, 0x0E76BD30,-0x0E1C5D80, 0x0DB94280,-0x0D4DB920, 0x0CD9F290,-0x0C5E48A0, 0x0BDAF3E0,
+    ],
+];
+
+pub(crate) const D: [i64; 512] = [
+     0x00000000, 0x00000000, 0x00000000, 0x00000000, 0x00000000, 0x00000000, 0x00000000,-0x00001000,
+    -0x00001000,-0x00001000,-0x00001000,-0x00002000,-0x00002000,-0x00003000,-0x00003000,-0x00004000,
+    -0x00004000,-0x00005000,-0x00006000,-0x00006000,-0x00007000,-0x00008000,-0x00009000,-0x0000A000,
+    -0x0000C000,-0x0000D000,-0x0000F000,-0x00010000,-0x00012000,-0x00014000,-0x00017000,-0x00019000,
+    -0x0001C000,-0x0001E000,-0x00022000,-0x00025000,-0x00028000,-0x0002C000,-0x00030000,-0x00034000,
+    -0x00039000,-0x0003E000,-0x00043000,-0x00048000,-0x0004E000,-0x00054000,-0x0005A000,-0x00060000,
+    -0x00067000,-0x0006E000,-0x00074000,-0x0007C000,-0x00083000,-0x0008A000,-0x00092000,-0x00099000,
+    -0x000A0000,-0x000A8000,-0x000AF000,-0x000B6000,-0x000BD000,-0x000C3000,-0x000C9000,-0x000CF000,
+     0x000D5000, 0x000DA000, 0x000DE000, 0x000E1000, 0x000E3000, 0x000E4000, 0x000E4000, 0x000E3000,
+     0x000E0000, 0x000DD000, 0x000D7000, 0x000D0000, 0x000C8000, 0x000BD000, 0x000B1000, 0x000A3000,
+     0x00092000, 0x0007F000, 0x0006A000, 0x00053000, 0x00039000, 0x0001D000,-0x00001000,-0x00023000,
+    -0x00047000,-0x0006E000,-0x00098000,-0x000C4000,-0x000F3000,-0x00125000,-0x0015A000,-0x00190000,
+    -0x001CA000,-0x00206000,-0x00244000,-0x00284000,-0x002C6000,-0x0030A000,-0x0034F000,-0x00396000,
+    -0x003DE000,-0x00427000,-0x00470000,-0x004B9000,-0x00502000,-0x0054B000,-0x00593000,-0x005D9000,
+    -0x0061E000,-0x00661000,-0x006A1000,-0x006DE000,-0x00718000,-0x0074D000,-0x0077E000,-0x007A9000,
+    -0x007D0000,-0x007EF000,-0x00808000,-0x0081A000,-0x00824000,-0x00826000,-0x0081F000,-0x0080E000,
+     0x007F5000, 0x007D0000, 0x007A0000, 0x00765000, 0x0071E000, 0x006CB000, 0x0066C000, 0x005FF000,
+     0x00586000, 0x00500000, 0x0046B000, 0x003CA000, 0x0031A000, 0x0025D000, 0x00192000, 0x000B9000,
+    -0x0002C000,-0x0011F000,-0x00220000,-0x0032D000,-0x00446000,-0x0056B000,-0x0069B000,-0x007D5000,
+    -0x00919000,-0x00A66000,-0x00BBB000,-0x00D16000,-0x00E78000,-0x00FDE000,-0x01148000,-0x012B3000,
+    -0x01420000,-0x0158C000,-0x016F6000,-0x0185C000,-0x019BC000,-0x01B16000,-0x01C66000,-0x01DAC000,
+    -0x01EE5000,-0x02010000,-0x0212A000,-0x02232000,-0x02325000,-0x02402000,-0x024C7000,-0x02570000,
+    -0x025FE000,-0x0266D000,-0x026BB000,-0x026E6000,-0x026ED000,-0x026CE000,-0x02686000,-0x02615000,
+    -0x02577000,-0x024AC000,-0x023B2000,-0x02287000,-0x0212B000,-0x01F9B000,-0x01DD7000,-0x01BDD000,
+     0x019AE000, 0x01747000, 0x014A8000, 0x011D1000, 0x00EC0000, 0x00B77000, 0x007F5000, 0x0043A000,
+     0x00046000,-0x003E5000,-0x00849000,-0x00CE3000,-0x011B4000,-0x016B9000,-0x01BF1000,-0x0215B000,
+    -0x026F6000,-0x02CBE000,-0x032B3000,-0x038D3000,-0x03F1A000,-0x04586000,-0x04C15000,-0x052C4000,
+    -0x05990000,-0x06075000,-0x06771000,-0x06E80000,-0x0759F000,-0x07CCA000,-0x083FE000,-0x08B37000,
+    -0x09270000,-0x099A7000,-0x0A0D7000,-0x0A7FD000,-0x0AF14000,-0x0B618000,-0x0BD05000,-0x0C3D8000,
+    -0x0CA8C000,-0x0D11D000,-0x0D789000,-0x0DDC9000,-0x0E3DC000,-0x0E9BD000,-0x0EF68000,-0x0F4DB000,
+    -0x0FA12000,-0x0FF09000,-0x103BD000,-0x1082C000,-0x10C53000,-0x1102E000,-0x113BD000,-0x116FB000,
+    -0x119E8000,-0x11C82000,-0x11EC6000,-0x120B3000,-0x12248000,-0x12385000,-0x12467000,-0x124EF000,
+     0x1251E000, 0x124F0000, 0x12468000, 0x12386000, 0x12249000, 0x120B4000, 0x11EC7000, 0x11C83000,
+     0x119E9000, 0x116FC000, 0x113BE000, 0x1102F000, 0x10C54000, 0x1082D000, 0x103BE000, 0x0FF0A000,
+     0x0FA13000, 0x0F4DC000, 0x0EF69000, 0x0E9BE000, 0x0E3DD000, 0x0DDCA000, 0x0D78A000, 0x0D11E000,
+     0x0CA8D000, 0x0C3D9000, 0x0BD06000, 0x0B619000, 0x0AF15000, 0x0A7FE000, 0x0A0D8000, 0x099A8000,
+     0x09271000, 0x08B38000, 0x083FF000, 0x07CCB000, 0x075A0000, 0x06E81000, 0x06772000, 0x06076000,
+     0x05991000, 0x052C5000, 0x04C16000, 0x04587000, 0x03F1B000, 0x038D4000, 0x032B4000, 0x02CBF000,
+     0x026F7000, 0x0215C000, 0x01BF2000, 0x016BA000, 0x011B5000, 0x00CE4000, 0x0084A000, 0x003E6000,
+    -0x00045000,-0x00439000,-0x007F4000,-0x00B76000,-0x00EBF000,-0x011D0000,-0x014A7000,-0x01746000,
+     0x019AE000, 0x01BDE000, 0x01DD8000, 0x01F9C000, 0x0212C000, 0x02288000, 0x023B3000, 0x024AD000,
+     0x02578000, 0x02616000, 0x02687000, 0x026CF000, 0x026EE000, 0x026E7000, 0x026BC000, 0x0266E000,
+     0x025FF000, 0x02571000, 0x024C8000, 0x02403000, 0x02326000, 0x02233000, 0x0212B000, 0x02011000,
+     0x01EE6000, 0x01DAD000, 0x01C67000, 0x01B17000, 0x019BD000, 0x0185D000, 0x016F7000, 0x0158D000,
+     0x01421000, 0x012B4000, 0x01149000, 0x00FDF000, 0x00E79000, 0x00D17000, 0x00BBC000, 0x00A67000,
+     0x0091A000, 0x007D6000, 0x0069C000, 0x0056C000, 0x00447000, 0x0032E000, 0x00221000, 0x00120000,
+     0x0002D000,-0x000B8000,-0x00191000,-0x0025C000,-0x00319000,-0x003C9000,-0x0046A000,-0x004FF000,
+    -0x00585000,-0x005FE000,-0x0066B000,-0x006CA000,-0x0071D000,-0x00764000,-0x0079F000,-0x007CF000,
+     0x007F5000, 0x0080F000, 0x00820000, 0x00827000, 0x00825000, 0x0081B000, 0x00809000, 0x007F0000,
+     0x007D1000, 0x007AA000, 0x0077F000, 0x0074E000, 0x00719000, 0x006DF000, 0x006A2000, 0x00662000,
+     0x0061F000, 0x005DA000, 0x00594000, 0x0054C000, 0x00503000, 0x004BA000, 0x00471000, 0x00428000,
+     0x003DF000, 0x00397000, 0x00350000, 0x0030B000, 0x002C7000, 0x00285000, 0x00245000, 0x00207000,
+     0x001CB000, 0x00191000, 0x0015B000, 0x00126000, 0x000F4000, 0x000C5000, 0x00099000, 0x0006F000,
+     0x00048000, 0x00024000, 0x00002000,-0x0001C000,-0x00038000,-0x00052000,-0x00069000,-0x0007E000,
+    -0x00091000,-0x000A2000,-0x000B0000,-0x000BC000,-0x000C7000,-0x000CF000,-0x000D6000,-0x000DC000,
+    -0x000DF000,-0x000E2000,-0x000E3000,-0x000E3000,-0x000E2000,-0x000E0000,-0x000DD000,-0x000D9000,
+     0x000D5000, 0x000D0000, 0x000CA000, 0x000C4000, 0x000BE000, 0x000B7000, 0x000B0000, 0x000A9000,
+     0x000A1000, 0x0009A000, 0x00093000, 0x0008B000, 0x00084000, 0x0007D000, 0x00075000, 0x0006F000,
+     0x00068000, 0x00061000, 0x0005B000, 0x00055000, 0x0004F000, 0x00049000, 0x00044000, 0x0003F000,
+     0x0003A000, 0x00035000, 0x00031000, 0x0002D000, 0x00029000, 0x00026000, 0x00023000, 0x0001F000,
+     0x0001D000, 0x0001A000, 0x00018000, 0x00015000, 0x00013000, 0x00011000, 0x00010000, 0x0000E000,
+     0x0000D000, 0x0000B000, 0x0000A000, 0x00009000, 0x00008000, 0x00007000, 0x00007000, 0x00006000,
+     0x00005000, 0x00005000, 0x00004000, 0x00004000, 0x00003000, 0x00003000, 0x00002000, 0x00002000,
+     0x00002000, 0x00002000, 0x00001000, 0x00001000, 0x00001000, 0x00001000, 0x00001000, 0x00001000,
+];
+
+/// Bit allocation and scalefactors for one frame.
+pub(crate) struct Allocation {
+    classes: [[Option<QuantizeSpec>; 32]; 2],
+    scfsi: [[u32; 32]; 2],
+    scalefactors: [[[u32; 3]; 32]; 2],
+}
+
+impl Allocation {
+    /// Number of bits the allocation and scfsi took up in the frame, which
+    /// is what the frame CRC covers.
+    pub fn protected_bits(&self, layout: &FrameLayout) -> u32 {
+        let mut bits = 0;
+        for (sb, classes) in layout.table.iter().enumerate() {
+            let nbal = alloc_bits(classes);
+            let channels = if sb < layout.bound { layout.channels } else { 1 };
+            bits += nbal * channels as u32;
+
+            for ch in 0..layout.channels {
+                if self.classes[ch][sb].is_some() {
+                    bits += 2;
+                }
+            }
+        }
+        bits
+    }
+}
+
+/// How the subbands of a frame are laid out.
+pub(crate) struct FrameLayout {
+    /// Allocation table, one entry per coded subband.
+    pub table: &'static [&'static [u8]],
+    pub channels: usize,
+    /// First subband that is intensity coded, shared between both channels.
+    pub bound: usize,
+}
+
+pub(crate) fn alloc_bits(classes: &[u8]) -> u32 {
+    32 - (classes.len() as u32).leading_zeros()
+}
+
+/// Reads the bit allocation, scfsi and scalefactors of a frame.
+pub(crate) fn read_allocation<R>(reader: &mut BitReader<R>, layout: &FrameLayout) -> RadxResult<Allocation>
+    where R: Source
+{
+    let mut allocation = Allocation {
+        classes: [[None; 32]; 2],
+        scfsi: [[0; 32]; 2],
+        scalefactors: [[[0; 3]; 32]; 2],
+    };
+
+    for (sb, classes) in layout.table.iter().enumerate() {
+        let nbal = alloc_bits(classes);
+        if sb < layout.bound {
+            for ch in 0..layout.channels {
+                let alloc = reader.read(nbal)? as usize;
+                allocation.classes[ch][sb] = quant_class(classes, alloc);
+            }
+        }
+        else {
+            let alloc = reader.read(nbal)? as usize;
+            for ch in 0..layout.channels {
+                allocation.classes[ch][sb] = quant_class(classes, alloc);
+            }
+        }
+    }
+
+    for sb in 0..layout.table.len() {
+        for ch in 0..layout.channels {
+            if allocation.classes[ch][sb].is_some() {
+                allocation.scfsi[ch][sb] = reader.read(2)?;
+            }
+        }
+    }
+
+    for sb in 0..layout.table.len() {
+        for ch in 0..layout.channels {
+            if allocation.classes[ch][sb].is_none() {
+                continue;
+            }
+
+            let scalefactors = &mut allocation.scalefactors[ch][sb];
+            match allocation.scfsi[ch][sb] {
+                0 => {
+                    scalefactors[0] = reader.read(6)?;
+                    scalefactors[1] = reader.read(6)?;
+                    scalefactors[2] = reader.read(6)?;
+                }
+                1 => {
+                    let tmp = reader.read(6)?;
+                    scalefactors[0] = tmp;
+                    scalefactors[1] = tmp;
+                    scalefactors[2] = reader.read(6)?;
+                }
+                2 => {
+                    let tmp = reader.read(6)?;
+                    scalefactors[0] = tmp;
+                    scalefactors[1] = tmp;
+                    scalefactors[2] = tmp;
+                }
+                3 => {
+                    scalefactors[0] = reader.read(6)?;
+                    let tmp = reader.read(6)?;
+                    scalefactors[1] = tmp;
+                    scalefactors[2] = tmp;
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    Ok(allocation)
+}
+
+fn quant_class(classes: &[u8], alloc: usize) -> Option<QuantizeSpec> {
+    if alloc == 0 {
+        None
+    }
+    else {
+        Some(QUANT_CLASSES[classes[alloc - 1] as usize])
+    }
+}
+
+/// Reads the sample data of a frame and runs it through each channel's
+/// synthesis filterbank, writing 1152 samples per channel into `pcm`.
+pub(crate) fn read_samples<R>(reader: &mut BitReader<R>,
+                              layout: &FrameLayout,
+                              allocation: &Allocation,
+                              synthesizers: &mut [Synthesizer],
+                              pcm: &mut [[i16; 1152]]) -> RadxResult<()>
+    where R: Source
+{
+    for part in 0..3 {
+        for gr in 0..4 {
+            // Dequantized samples of each subband, for each channel
+            let mut sb_samples = [[[0; 3]; 2]; 32];
+
+            for (sb, band) in sb_samples.iter_mut().enumerate().take(layout.table.len()) {
+                let coded_channels = if sb < layout.bound { layout.channels } else { 1 };
+
+                for ch in 0..coded_channels {
+                    let quant = match allocation.classes[ch][sb] {
+                        Some(quant) => quant,
+                        None => continue,
+                    };
+
+                    let samples = read_quantized(reader, quant)?;
+
+                    // Intensity coded subbands share samples, but still
+                    // have their own scalefactors.
+                    let channels = if sb < layout.bound { ch..ch + 1 } else { 0..layout.channels };
+                    for scaled_ch in channels {
+                        let scalefactor = allocation.scalefactors[scaled_ch][sb][part] as usize;
+                        // Scalefactor 63 is invalid, so treat it as silence
+                        let scale = SF_TABLE.get(scalefactor).cloned().unwrap_or(0);
+                        for (sample, &code) in band[scaled_ch].iter_mut().zip(samples.iter()) {
+                            *sample = (Wrapping(code) * Wrapping(scale)).0 >> FRAC_BITS;
+                        }
+                    }
+                }
+            }
+
+            // Synthesis
+            for ch in 0..layout.channels {
+                for idx in 0..3 {
+                    let mut slot = [0; 32];
+                    for (sample, band) in slot.iter_mut().zip(sb_samples.iter()) {
+                        *sample = band[ch][idx];
+                    }
+
+                    let pcm_idx = part * 384 + gr * 96 + idx * 32;
+                    synthesizers[ch].synthesize(&slot, &mut pcm[ch][pcm_idx..pcm_idx + 32]);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn read_quantized<R>(reader: &mut BitReader<R>, quant: QuantizeSpec) -> RadxResult<[i64; 3]>
+    where R: Source
+{
+    let mut codes = [0; 3];
+    if quant.group != 0 {
+        codes[0] = reader.read(quant.bits)?;
+        Ok(dequantize(&codes[..1], quant))
+    }
+    else {
+        for code in codes.iter_mut() {
+            *code = reader.read(quant.bits)?;
+        }
+        Ok(dequantize(&codes, quant))
+    }
+}
+
+/// Turns the codes for three samples, either one grouped code or one code
+/// each, back into fractions of their scalefactor.
+pub(crate) fn dequantize(codes: &[u32], quant: QuantizeSpec) -> [i64; 3] {
+    let mut samples = [0; 3];
+    let num_bits;
+
+    if quant.group != 0 {
+        num_bits = quant.group;
+        let mut grouped = codes[0] as i64;
+
+        for sample in samples.iter_mut() {
+            *sample = grouped % quant.nlevels;
+            grouped /= quant.nlevels;
+        }
+    }
+    else {
+        num_bits = quant.bits;
+
+        for (sample, &code) in samples.iter_mut().zip(codes.iter()) {
+            *sample = code as i64;
+        }
+    }
+
+    for sample in samples.iter_mut() {
+        let mut requantized = *sample ^ (1 << (num_bits - 1));
+        requantized |= -(requantized & (1 << (num_bits - 1)));
+
+        requantized <<= FRAC_BITS - (num_bits - 1);
+
+        *sample = (Wrapping(requantized + quant.d) * Wrapping(quant.c)).0 >> FRAC_BITS;
+    }
+
+    samples
+}
+
+/// Polyphase synthesis filterbank state for one channel.
+#[derive(Clone)]
+pub(crate) struct Synthesizer {
+    v_off: usize,
+    v: [i64; 1024],
+}
+
+impl Synthesizer {
+    pub fn new() -> Synthesizer {
+        Synthesizer {
+            v_off: 0,
+            v: [0; 1024],
+        }
+    }
+
+    pub(crate) fn save(&self, writer: &mut StateWriter) {
+        writer.write_u32(self.v_off as u32);
+        for &v in self.v.iter() {
+            writer.write_i64(v);
+        }
+    }
+
+    pub(crate) fn restore(reader: &mut StateReader) -> RadxResult<Synthesizer> {
+        let v_off = reader.read_u32()? as usize;
+        if v_off >= 1024 || !v_off.is_multiple_of(64) {
+            return Err(RadxError::BadDecoderState("bad synthesis offset"));
+        }
+        let mut synthesizer = Synthesizer::new();
+        synthesizer.v_off = v_off;
+        for v in synthesizer.v.iter_mut() {
+            *v = reader.read_i64()?;
+        }
+        Ok(synthesizer)
+    }
+
+    /// Runs one slot of subband samples through the synthesis filterbank,
+    /// producing 32 PCM samples.
+    pub fn synthesize(&mut self, sb_samples: &[i64; 32], pcm: &mut [i16]) {
+        let table_idx = (Wrapping(self.v_off) - Wrapping(64)).0 % 1024;
+        self.v_off = table_idx;
+
+        // Matrixing. v[i] = Σ cos((16 + i)(2sb + 1)π / 64) sample[sb], which
+        // with n = 16 + i is entry n of the 32 point DCT-II of the samples,
+        // extended by X[32] = 0, X[64 - n] = -X[n] and X[n + 64] = -X[n].
+        let mut transformed = [0; 32];
+        dct32(sb_samples, &mut transformed);
+        for (i, v) in self.v[table_idx..table_idx + 64].iter_mut().enumerate() {
+            *v = match i + 16 {
+                n if n < 32 => transformed[n],
+                32 => 0,
+                n if n <= 64 => -transformed[64 - n],
+                n => -transformed[n - 64],
+            };
+        }
+
+        // Windowing. The window position u[i * 32 + sb] maps back to v:
+        // even rows take the first 32 values of a 128 value block, odd rows
+        // take the last 32.
+        for sb in 0..32 {
+            let mut sum = 0;
+            for i in 0..16 {
+                let v_idx = table_idx + (i >> 1) * 128 + (i & 1) * 96 + sb;
+                sum += (Wrapping(self.v[v_idx % 1024]) * Wrapping(D[i * 32 + sb])).0 >> FRAC_BITS;
+            }
+
+            sum >>= FRAC_BITS - 15;
+
+            if sum > i16::MAX as i64 {
+                sum = i16::MAX as i64;
+            }
+            else if sum < i16::MIN as i64 {
+                sum = i16::MIN as i64;
+            }
+
+            pcm[sb] = sum as i16;
+        }
+    }
+}
+
+/// Unscaled DCT-II, `output[k] = Σ input[m] cos((2m + 1)kπ / 2n)`, of up to
+/// 32 fixed point values.
+///
+/// The sums of mirrored input pairs give the even outputs through a half size
+/// transform. Their differences, scaled by the stage's secants, go through
+/// another one whose neighbouring outputs add up to the odd outputs.
+fn dct32(input: &[i64], output: &mut [i64]) {
+    let n = input.len();
+    if n == 1 {
+        output[0] = input[0];
+        return;
+    }
+
+    let half = n / 2;
+    let mut sums = [0; 16];
+    let mut differences = [0; 16];
+    for m in 0..half {
+        let (first, last) = (input[m], input[n - 1 - m]);
+        sums[m] = first + last;
+        let scaled = (first - last) as i128 * SECANTS[half - 1 + m] as i128;
+        differences[m] = ((scaled + (1 << (FRAC_BITS - 1))) >> FRAC_BITS) as i64;
+    }
+
+    // odd[half] stays 0 for the last odd output
+    let mut even = [0; 16];
+    let mut odd = [0; 17];
+    dct32(&sums[..half], &mut even[..half]);
+    dct32(&differences[..half], &mut odd[..half]);
+
+    for k in 0..half {
+        output[2 * k] = even[k];
+        output[2 * k + 1] = odd[k] + odd[k + 1];
+    }
+}
+
+pub struct BitReader<R> {
+    inner: R,
+    buffer: u8,
+    bits_left: u32,
+    position: u64,
+}
+
+impl<R> BitReader<R>
+    where R: Source
+{
+    pub fn new(inner: R) -> BitReader<R> {
+        BitReader {
+            inner: inner,
+            buffer: 0,
+            bits_left: 0,
+            position: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.bits_left = 0;
+    }
+
+    /// Number of bytes taken from the inner reader so far.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// For when the inner reader was moved to `position` from outside.
+    /// Anything left of the current byte is dropped.
+    pub(crate) fn set_position(&mut self, position: u64) {
+        self.position = position;
+        self.bits_left = 0;
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    fn read_from_buffer(&mut self, bits: u32) -> u32 {
+        assert!(bits <= 8);
+
+        let result = self.buffer >> (8 - bits);
+        self.buffer = self.buffer.checked_shl(bits).unwrap_or(0);
+        self.bits_left -= bits;
+        result as u32
+    }
+
+    pub fn read(&mut self, mut bits: u32) -> RadxResult<u32> {
+        assert!(bits <= 32);
+
+        let mut result = 0;
+
+        while bits != 0 {
+            if self.bits_left == 0 {
+                let mut buf_array = [0];
+                self.inner.read_all(&mut buf_array)?;
+                self.buffer = buf_array[0];
+                self.bits_left = 8;
+                self.position += 1;
+            }
+            let bits_to_read = cmp::min(bits, self.bits_left);
+            let data = self.read_from_buffer(bits_to_read);
+            result = (result << bits_to_read) | data;
+            bits -= bits_to_read;
+        }
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use std::num::Wrapping;
+
+    use from_slice;
+    use noise::Noise;
+    use super::{BitReader, Synthesizer, QUANT_CLASSES, N, D, FRAC_BITS, read_quantized};
+
+    /// Straightforward synthesis, straight from the spec.
+    fn reference_synthesize(v: &mut [i64; 1024], v_off: &mut usize, sb_samples: &[i64; 32]) -> [i16; 32] {
+        let table_idx = (Wrapping(*v_off) - Wrapping(64)).0 % 1024;
+        *v_off = table_idx;
+
+        for i in 0..64 {
+            let mut sum = 0;
+            for j in 0..32 {
+                sum += (Wrapping(N[j][i]) * Wrapping(sb_samples[j])).0 >> FRAC_BITS;
+            }
+            v[table_idx + i] = sum;
+        }
+
+        let mut u = [0; 512];
+        for i in 0..8 {
+            for sb in 0..32 {
+                u[(i * 64) + sb] = v[(table_idx + (i * 128) + sb) % 1024];
+                u[(i * 64) + sb + 32] = v[(table_idx + (i * 128) + sb + 96) % 1024];
+            }
+        }
+
+        for i in 0..512 {
+            u[i] = (Wrapping(u[i]) * Wrapping(D[i])).0 >> FRAC_BITS;
+        }
+
+        let mut pcm = [0; 32];
+        for sb in 0..32 {
+            let mut sum = 0;
+            for i in 0..16 {
+                sum += u[i * 32 + sb];
+            }
+            sum >>= FRAC_BITS - 15;
+            pcm[sb] = if sum > i16::MAX as i64 {
+                i16::MAX
+            }
+            else if sum < i16::MIN as i64 {
+                i16::MIN
+            }
+            else {
+                sum as i16
+            };
+        }
+        pcm
+    }
+
+    #[test]
+    fn output_unchanged() {
+        // Loud noise, decoded with the fast matrixing. Any change to how
+        // the decoder rounds shows up here.
+        let expected: Vec<i16> = include_bytes!("../../testdata/noise.mp2.pcm")
+            .chunks(2)
+            .map(|bytes| bytes[0] as u16 as i16 | (bytes[1] as i16) << 8)
+            .collect();
+        let decoder = from_slice(include_bytes!("../../testdata/noise.mp2"), false).unwrap();
+        let decoded: Vec<i16> = decoder.flatten().collect();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn synthesis_matches_reference() {
+        let mut synthesizer = Synthesizer::new();
+
+        let mut v = [0; 1024];
+        let mut v_off = 0;
+        let mut noise = Noise::new();
+        let mut worst = 0;
+        for slot in 0..200 {
+            let mut sb_samples = [0; 32];
+            for (sample, seed) in sb_samples.iter_mut().zip(&mut noise) {
+                // Leave some subbands silent, and push some past full scale
+                if seed & 3 != 0 {
+                    *sample = (seed as i32 as i64) >> (slot % 5);
+                }
+            }
+
+            let mut pcm = [0; 32];
+            synthesizer.synthesize(&sb_samples, &mut pcm);
+            let expected = reference_synthesize(&mut v, &mut v_off, &sb_samples);
+            for (&sample, &reference) in pcm.iter().zip(expected.iter()) {
+                worst = worst.max((sample as i32 - reference as i32).abs());
+            }
+        }
+
+        // The reference's single precision cosines are off by a few steps
+        // on subband samples this far past full scale, where the transform
+        // stays within one.
+        assert!(worst < 8, "worst difference {}", worst);
+    }
+
+    #[test]
+    fn five_level_midpoint() {
+        // Grouped code for three samples at the middle level
+        let code = 2 + 2 * 5 + 2 * 25;
+        let data = [(code << 1) as u8];
+        let samples = read_quantized(&mut BitReader::new(&data[..]), QUANT_CLASSES[1]).unwrap();
+        assert_eq!(samples, [0, 0, 0]);
+    }
+}
@@ -1,19 +1,236 @@
 pub mod standard_decoder;
 pub mod ahx_decoder;
+pub mod mp2_decoder;
+pub(crate) mod layer2;
+mod state;
 
 pub(crate) use self::standard_decoder::StandardDecoder;
 pub(crate) use self::ahx_decoder::AhxDecoder;
+pub(crate) use self::mp2_decoder::Mp2Decoder;
+pub use self::state::DecoderState;
 
+use alloc::vec::Vec;
+
+use error::{RadxResult, RadxError};
+use progress::Progress;
+use self::state::{StateReader, StateWriter};
 use {Sample, LoopInfo};
 
+/// What a decoder did to get past damaged data.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum RecoveryKind {
+    /// A block with a bad scale was replaced with silence.
+    BadBlock,
+    /// A frame failed its CRC and was replaced with silence.
+    BadCrc,
+    /// The data ran out before the stream said it would. Whatever was left
+    /// of the last frame was replaced with silence.
+    Truncated,
+    /// Data that wasn't a frame was skipped to get to the next frame sync.
+    Resync {
+        skipped: u64,
+    },
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct RecoveryEvent {
+    /// Byte offset of the damaged data. For decoders made through
+    /// `from_reader`, this is from the start of the file.
+    pub offset: u64,
+    pub kind: RecoveryKind,
+}
+
+/// Points in playback an engine might want to sync to.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum PlaybackEvent {
+    /// Playback jumped from the loop end back to the loop start. `sample` is
+    /// the position of the first sample after the jump, counting every
+    /// sample played so far. `loop_count` starts at 1 for the first wrap.
+    LoopWrap {
+        sample: u64,
+        loop_count: u32,
+    },
+    /// The stream ended after `sample` samples.
+    End {
+        sample: u64,
+    },
+}
+
+/// Counts played samples and loops for a decoder's `PlaybackEvent`s.
+pub(crate) struct PlaybackTracker {
+    played: u64,
+    loop_count: u32,
+    ended: bool,
+    events: Vec<PlaybackEvent>,
+}
+
+impl PlaybackTracker {
+    pub(crate) fn new() -> PlaybackTracker {
+        PlaybackTracker {
+            played: 0,
+            loop_count: 0,
+            ended: false,
+            events: Vec::new(),
+        }
+    }
+
+    /// Call with every result of `next_sample`.
+    pub(crate) fn advance(&mut self, sample: &Option<Sample>) {
+        if sample.is_some() {
+            self.played += 1;
+        }
+        else if !self.ended {
+            self.ended = true;
+            self.events.push(PlaybackEvent::End {
+                sample: self.played,
+            });
+        }
+    }
+
+    /// Call when the loop end jumps back to the loop start.
+    pub(crate) fn wrap(&mut self) {
+        self.loop_count += 1;
+        self.events.push(PlaybackEvent::LoopWrap {
+            sample: self.played,
+            loop_count: self.loop_count,
+        });
+    }
+
+    pub(crate) fn reset(&mut self) {
+        *self = PlaybackTracker::new();
+    }
+
+    pub(crate) fn played(&self) -> u64 {
+        self.played
+    }
+
+    pub(crate) fn save(&self, writer: &mut StateWriter) {
+        writer.write_u64(self.played);
+        writer.write_u32(self.loop_count);
+        writer.write_bool(self.ended);
+    }
+
+    /// Reads back what `save` wrote. Events from before the restore are
+    /// dropped.
+    pub(crate) fn restore(reader: &mut StateReader) -> RadxResult<PlaybackTracker> {
+        Ok(PlaybackTracker {
+            played: reader.read_u64()?,
+            loop_count: reader.read_u32()?,
+            ended: reader.read_bool()?,
+            events: Vec::new(),
+        })
+    }
+
+    pub(crate) fn take(&mut self) -> Vec<PlaybackEvent> {
+        self.events.drain(..).collect()
+    }
+}
+
 pub trait Decoder {
     fn channels(&self) -> u32;
     fn sample_rate(&self) -> u32;
 	fn loop_info(&self) -> Option<LoopInfo>;
     fn next_sample(&mut self) -> Option<Sample>;
+
+    /// Whether playback goes back to the loop start at the loop end.
+    /// `loop_info` reports the stream's loop either way.
+    fn looping(&self) -> bool {
+        false
+    }
+
+    /// Number of samples in one pass through the stream, if the stream says.
+    fn total_samples(&self) -> Option<u32> {
+        None
+    }
+
+    /// Starts decoding again from the first sample. Returns `false` if this
+    /// decoder can't go back.
+    fn rewind(&mut self) -> RadxResult<bool> {
+        Ok(false)
+    }
+
+    /// Keep decoding past corrupt or truncated frames instead of ending the
+    /// stream there. Off by default.
+    fn set_recovery(&mut self, _recovery: bool) {
+    }
+
+    /// Takes every recovery event since the last call.
+    fn take_recovery_events(&mut self) -> Vec<RecoveryEvent> {
+        Vec::new()
+    }
+
+    /// Captures exactly where decoding is, so `restore_state` can carry on
+    /// from here later.
+    fn save_state(&mut self) -> RadxResult<DecoderState> {
+        Err(RadxError::BadDecoderState("decoder can't save its state"))
+    }
+
+    /// Puts the decoder back where `save_state` was called. The decoder has
+    /// to be reading the same stream, set up the same way.
+    fn restore_state(&mut self, _state: &DecoderState) -> RadxResult<()> {
+        Err(RadxError::BadDecoderState("decoder can't restore its state"))
+    }
+
+    /// Takes every loop wrap and end of stream since the last call.
+    fn take_playback_events(&mut self) -> Vec<PlaybackEvent> {
+        Vec::new()
+    }
+
+    /// Reports the samples played so far, and checks for cancellation,
+    /// before every frame.
+    ///
+    /// A cancelled decoder stays where it stopped until the flag is cleared.
+    /// Meanwhile `try_next_sample` returns `Err(RadxError::Cancelled)`, and
+    /// `next_sample` returns `None` without ending the stream.
+    fn set_progress(&mut self, _progress: Progress) {
+    }
+
+    /// Whether the last `next_sample` came up empty because decoding was
+    /// cancelled rather than because the stream ended.
+    fn was_cancelled(&self) -> bool {
+        false
+    }
+
+    /// Like `next_sample`, but a cancelled decode is an error, so `None`
+    /// only ever means the end of the stream.
+    fn try_next_sample(&mut self) -> RadxResult<Option<Sample>> {
+        match self.next_sample() {
+            None if self.was_cancelled() => Err(RadxError::Cancelled),
+            sample => Ok(sample),
+        }
+    }
+
+    /// Fills `buf` with samples interleaved by channel, stopping early at the
+    /// end of the stream. Returns how many samples (not values) were read.
+    ///
+    /// A cancellation partway through returns the samples read up to it,
+    /// and the next call fails with `RadxError::Cancelled`.
+    fn try_read_block(&mut self, buf: &mut [i16]) -> RadxResult<usize> {
+        let channels = self.channels() as usize;
+        let mut read = 0;
+        for frame in buf.chunks_mut(channels) {
+            if frame.len() != channels {
+                break;
+            }
+            match self.try_next_sample() {
+                Ok(Some(sample)) => frame.copy_from_slice(&sample),
+                Ok(None) => break,
+                Err(_) if read != 0 => break,
+                Err(err) => return Err(err),
+            }
+            read += 1;
+        }
+        Ok(read)
+    }
+
+    /// Like `try_read_block`, but a cancelled decode reads 0 samples, same
+    /// as the end of the stream.
+    fn read_block(&mut self, buf: &mut [i16]) -> usize {
+        self.try_read_block(buf).unwrap_or(0)
+    }
 }
 
-impl Iterator for Decoder {
+impl<'a> Iterator for Decoder + 'a {
     type Item = Sample;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -0,0 +1,610 @@
+use decoder::{Decoder, PlaybackEvent, PlaybackTracker, RecoveryEvent, RecoveryKind};
+use decoder::layer2::{self, BitReader, FrameLayout, Synthesizer,
+                      ALLOC_TABLE_A, ALLOC_TABLE_B, ALLOC_TABLE_C, ALLOC_TABLE_D, ALLOC_TABLE_LSF};
+use error::{RadxResult, RadxError};
+use progress::Progress;
+use source::Source;
+use {Sample, LoopInfo};
+
+use alloc::vec::Vec;
+
+pub(crate) const BITRATE_TABLE_MPEG1: [u32; 15] = [0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384];
+pub(crate) const BITRATE_TABLE_LSF: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum MpegVersion {
+    Mpeg1,
+    Mpeg2,
+    Mpeg25,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum ChannelMode {
+    Stereo,
+    JointStereo,
+    DualChannel,
+    Mono,
+}
+
+#[derive(Clone,Copy,Debug)]
+pub struct Mp2FrameHeader {
+    pub version: MpegVersion,
+    pub protected: bool,
+    /// Bitrate in kbps
+    pub bitrate: u32,
+    pub sample_rate: u32,
+    pub padding: bool,
+    pub mode: ChannelMode,
+    pub mode_extension: u8,
+}
+
+impl Mp2FrameHeader {
+    pub fn from_u32(header: u32) -> RadxResult<Mp2FrameHeader> {
+        if header >> 21 != 0x7ff {
+            return Err(RadxError::BadMp2FrameHeader("bad sync"));
+        }
+
+        let version = match (header >> 19) & 0x3 {
+            0 => MpegVersion::Mpeg25,
+            2 => MpegVersion::Mpeg2,
+            3 => MpegVersion::Mpeg1,
+            _ => return Err(RadxError::BadMp2FrameHeader("bad version")),
+        };
+
+        if (header >> 17) & 0x3 != 2 {
+            return Err(RadxError::BadMp2FrameHeader("not layer ii"));
+        }
+
+        let protected = (header >> 16) & 0x1 == 0;
+
+        let bitrate_idx = ((header >> 12) & 0xf) as usize;
+        let bitrate = match bitrate_idx {
+            0 => return Err(RadxError::BadMp2FrameHeader("free format bitrate unsupported")),
+            15 => return Err(RadxError::BadMp2FrameHeader("bad bitrate")),
+            idx if version == MpegVersion::Mpeg1 => BITRATE_TABLE_MPEG1[idx],
+            idx => BITRATE_TABLE_LSF[idx],
+        };
+
+        let sample_rate = match (header >> 10) & 0x3 {
+            0 => 44100,
+            1 => 48000,
+            2 => 32000,
+            _ => return Err(RadxError::BadMp2FrameHeader("bad sample rate")),
+        };
+        let sample_rate = match version {
+            MpegVersion::Mpeg1 => sample_rate,
+            MpegVersion::Mpeg2 => sample_rate / 2,
+            MpegVersion::Mpeg25 => sample_rate / 4,
+        };
+
+        let padding = (header >> 9) & 0x1 != 0;
+
+        let mode = match (header >> 6) & 0x3 {
+            0 => ChannelMode::Stereo,
+            1 => ChannelMode::JointStereo,
+            2 => ChannelMode::DualChannel,
+            _ => ChannelMode::Mono,
+        };
+        let mode_extension = ((header >> 4) & 0x3) as u8;
+
+        Ok(Mp2FrameHeader {
+            version: version,
+            protected: protected,
+            bitrate: bitrate,
+            sample_rate: sample_rate,
+            padding: padding,
+            mode: mode,
+            mode_extension: mode_extension,
+        })
+    }
+
+    /// Packs the header back into its 32 bits, with the private, copyright,
+    /// original and emphasis bits clear.
+    ///
+    /// # Panics
+    ///
+    /// If the bitrate or sample rate isn't one the header can hold.
+    pub fn to_u32(&self) -> u32 {
+        let (version, bitrates, base_rate) = match self.version {
+            MpegVersion::Mpeg1 => (3, &BITRATE_TABLE_MPEG1, self.sample_rate),
+            MpegVersion::Mpeg2 => (2, &BITRATE_TABLE_LSF, self.sample_rate * 2),
+            MpegVersion::Mpeg25 => (0, &BITRATE_TABLE_LSF, self.sample_rate * 4),
+        };
+        let bitrate_idx = bitrates[1..].iter().position(|&bitrate| bitrate == self.bitrate).expect("bad bitrate") + 1;
+        let sample_rate_idx = match base_rate {
+            44100 => 0,
+            48000 => 1,
+            32000 => 2,
+            _ => panic!("bad sample rate"),
+        };
+        let mode = match self.mode {
+            ChannelMode::Stereo => 0,
+            ChannelMode::JointStereo => 1,
+            ChannelMode::DualChannel => 2,
+            ChannelMode::Mono => 3,
+        };
+
+        0x7ff << 21
+            | version << 19
+            | 2 << 17
+            | (if self.protected { 0 } else { 1 }) << 16
+            | (bitrate_idx as u32) << 12
+            | sample_rate_idx << 10
+            | (if self.padding { 1 } else { 0 }) << 9
+            | mode << 6
+            | (self.mode_extension as u32) << 4
+    }
+
+    pub fn channels(&self) -> usize {
+        if self.mode == ChannelMode::Mono {
+            1
+        }
+        else {
+            2
+        }
+    }
+
+    /// Length of the whole frame in bytes, header included.
+    pub fn frame_len(&self) -> usize {
+        let padding = if self.padding { 1 } else { 0 };
+        (144 * 1000 * self.bitrate / self.sample_rate) as usize + padding
+    }
+
+    pub(crate) fn layout(&self) -> FrameLayout {
+        let table = if self.version != MpegVersion::Mpeg1 {
+            ALLOC_TABLE_LSF
+        }
+        else {
+            let channel_bitrate = self.bitrate / self.channels() as u32;
+            if channel_bitrate >= 56 && (self.sample_rate == 48000 || channel_bitrate <= 80) {
+                ALLOC_TABLE_A
+            }
+            else if self.sample_rate != 48000 && channel_bitrate >= 96 {
+                ALLOC_TABLE_B
+            }
+            else if self.sample_rate != 32000 && channel_bitrate <= 48 {
+                ALLOC_TABLE_C
+            }
+            else {
+                ALLOC_TABLE_D
+            }
+        };
+
+        let bound = if self.mode == ChannelMode::JointStereo {
+            (4 + self.mode_extension as usize * 4).min(table.len())
+        }
+        else {
+            table.len()
+        };
+
+        FrameLayout {
+            table: table,
+            channels: self.channels(),
+            bound: bound,
+        }
+    }
+}
+
+/// Decoder for plain MPEG-1/2 Layer II streams.
+pub struct Mp2Decoder<R> {
+    inner: R,
+    header: Mp2FrameHeader,
+    next_header: Option<u32>,
+    synthesizers: Vec<Synthesizer>,
+    buffer: Vec<[i16; 1152]>,
+    buffer_idx: usize,
+    /// Bytes read from the start of the stream.
+    position: u64,
+    recovery: bool,
+    events: Vec<RecoveryEvent>,
+    playback: PlaybackTracker,
+    progress: Progress,
+}
+
+impl<R> Mp2Decoder<R>
+    where R: Source
+{
+    /// Makes a decoder from the start of an mp2 stream, skipping over an
+    /// ID3v2 tag if there is one.
+    pub fn new(mut inner: R) -> RadxResult<Mp2Decoder<R>> {
+        let mut position = 4;
+        let mut first = [0; 4];
+        inner.read_all(&mut first)?;
+
+        if &first[0..3] == b"ID3" {
+            let mut tag_header = [0; 6];
+            inner.read_all(&mut tag_header)?;
+
+            // Size is stored as a 28-bit syncsafe integer
+            let mut tag_len = tag_header[2..6].iter().fold(0, |acc, &byte| (acc << 7) | (byte & 0x7f) as u64);
+            // Footer present
+            if tag_header[1] & 0x10 != 0 {
+                tag_len += 10;
+            }
+            let mut skip = [0; 256];
+            let mut left = tag_len;
+            while left != 0 {
+                let len = if left < 256 { left as usize } else { 256 };
+                inner.read_all(&mut skip[..len])?;
+                left -= len as u64;
+            }
+            inner.read_all(&mut first)?;
+            position += 6 + tag_len + 4;
+        }
+
+        let raw_header = (first[0] as u32) << 24 | (first[1] as u32) << 16 | (first[2] as u32) << 8 | first[3] as u32;
+        let header = Mp2FrameHeader::from_u32(raw_header)?;
+        let channels = header.channels();
+
+        Ok(Mp2Decoder {
+            inner: inner,
+            header: header,
+            next_header: Some(raw_header),
+            synthesizers: vec![Synthesizer::new(); channels],
+            buffer: vec![[0; 1152]; channels],
+            buffer_idx: 1152,
+            position: position,
+            recovery: false,
+            events: Vec::new(),
+            playback: PlaybackTracker::new(),
+            progress: Progress::new(),
+        })
+    }
+
+    /// Header of the first frame in the stream.
+    pub fn header(&self) -> Mp2FrameHeader {
+        self.header
+    }
+
+    /// Reads until `buf` is full or the stream ends, returning how much was
+    /// read.
+    fn read_bytes(&mut self, buf: &mut [u8]) -> RadxResult<usize> {
+        let len = self.inner.read_bytes(buf)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn is_next_frame(&self, raw_header: u32) -> bool {
+        match Mp2FrameHeader::from_u32(raw_header) {
+            Ok(header) => header.channels() == self.header.channels() && header.sample_rate == self.header.sample_rate,
+            Err(_) => false,
+        }
+    }
+
+    fn decode_sample(&mut self) -> Option<Sample> {
+        if self.buffer_idx == 1152 {
+            if self.progress.should_stop(self.playback.played()) {
+                return None;
+            }
+            if !self.read_frame().unwrap_or(false) {
+                return None;
+            }
+            self.buffer_idx = 0;
+        }
+
+        let sample = self.buffer.iter().map(|channel| channel[self.buffer_idx]).collect();
+        self.buffer_idx += 1;
+        Some(sample)
+    }
+
+    fn read_frame(&mut self) -> RadxResult<bool> {
+        let frame_start = self.position - if self.next_header.is_some() { 4 } else { 0 };
+        let mut raw_header = match self.next_header.take() {
+            Some(raw_header) => raw_header,
+            None => {
+                let mut buf = [0; 4];
+                let len = self.read_bytes(&mut buf)?;
+                if len == 0 {
+                    return Ok(false);
+                }
+                // Anything that isn't a frame (an ID3v1 tag, say) ends the stream
+                if len < 4 || &buf[0..3] == b"TAG" {
+                    return Ok(false);
+                }
+                (buf[0] as u32) << 24 | (buf[1] as u32) << 16 | (buf[2] as u32) << 8 | buf[3] as u32
+            }
+        };
+
+        if !self.is_next_frame(raw_header) {
+            if !self.recovery {
+                return Ok(false);
+            }
+
+            // Slide along a byte at a time until we're at a frame again
+            let mut skipped = 0;
+            while !self.is_next_frame(raw_header) {
+                let mut byte = [0];
+                if self.read_bytes(&mut byte)? == 0 {
+                    self.events.push(RecoveryEvent {
+                        offset: frame_start,
+                        kind: RecoveryKind::Resync {
+                            skipped: skipped + 4,
+                        },
+                    });
+                    return Ok(false);
+                }
+                raw_header = (raw_header << 8) | byte[0] as u32;
+                skipped += 1;
+            }
+
+            self.events.push(RecoveryEvent {
+                offset: frame_start,
+                kind: RecoveryKind::Resync {
+                    skipped: skipped,
+                },
+            });
+        }
+
+        let header_offset = self.position - 4;
+        let header = Mp2FrameHeader::from_u32(raw_header)?;
+        let mut frame = vec![0; header.frame_len() - 4];
+        let frame_len = self.read_bytes(&mut frame)?;
+
+        let truncated = frame_len != frame.len();
+        if truncated {
+            if !self.recovery {
+                return Ok(false);
+            }
+
+            self.events.push(RecoveryEvent {
+                offset: self.position,
+                kind: RecoveryKind::Truncated,
+            });
+
+            // Whatever we can't decode out of what's left stays silent
+            frame.truncate(frame_len);
+            for channel in self.buffer.iter_mut() {
+                *channel = [0; 1152];
+            }
+        }
+
+        match self.decode_frame(raw_header, &header, &frame) {
+            Ok(()) => Ok(true),
+            Err(RadxError::BadMp2FrameCrc) if self.recovery => {
+                self.events.push(RecoveryEvent {
+                    offset: header_offset,
+                    kind: RecoveryKind::BadCrc,
+                });
+                for channel in self.buffer.iter_mut() {
+                    *channel = [0; 1152];
+                }
+                Ok(true)
+            }
+            // Keep what we got out of the start of a cut off frame
+            Err(_) if truncated => Ok(true),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Decodes the frame after its header into the sample buffer.
+    fn decode_frame(&mut self, raw_header: u32, header: &Mp2FrameHeader, frame: &[u8]) -> RadxResult<()> {
+        let layout = header.layout();
+        let mut reader = BitReader::new(frame);
+        if header.protected {
+            reader.read(16)?;
+        }
+
+        let allocation = layer2::read_allocation(&mut reader, &layout)?;
+
+        if header.protected {
+            let stored_crc = (frame[0] as u16) << 8 | frame[1] as u16;
+            let crc = crc16(0xffff, &[(raw_header >> 8) as u8, raw_header as u8], 16);
+            let crc = crc16(crc, &frame[2..], allocation.protected_bits(&layout));
+            if crc != stored_crc {
+                return Err(RadxError::BadMp2FrameCrc);
+            }
+        }
+
+        layer2::read_samples(&mut reader, &layout, &allocation, &mut self.synthesizers, &mut self.buffer)
+    }
+}
+
+impl<R> Decoder for Mp2Decoder<R>
+    where R: Source
+{
+    fn channels(&self) -> u32 {
+        self.header.channels() as u32
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.header.sample_rate
+    }
+
+    fn loop_info(&self) -> Option<LoopInfo> {
+        None
+    }
+
+    fn next_sample(&mut self) -> Option<Sample> {
+        self.progress.resume();
+        let sample = self.decode_sample();
+        if !self.progress.stopped() {
+            self.playback.advance(&sample);
+        }
+        sample
+    }
+
+    fn set_recovery(&mut self, recovery: bool) {
+        self.recovery = recovery;
+    }
+
+    fn take_recovery_events(&mut self) -> Vec<RecoveryEvent> {
+        self.events.drain(..).collect()
+    }
+
+    fn take_playback_events(&mut self) -> Vec<PlaybackEvent> {
+        self.playback.take()
+    }
+
+    fn set_progress(&mut self, progress: Progress) {
+        self.progress = progress;
+    }
+
+    fn was_cancelled(&self) -> bool {
+        self.progress.stopped()
+    }
+}
+
+/// CRC-16 as used by MPEG audio, over the first `bits` bits of `data`.
+fn crc16(mut crc: u16, data: &[u8], bits: u32) -> u16 {
+    for bit_idx in 0..bits as usize {
+        let bit = (data[bit_idx / 8] >> (7 - bit_idx % 8)) & 1;
+        let msb = (crc >> 15) as u8 & 1;
+        crc <<= 1;
+        if msb ^ bit != 0 {
+            crc ^= 0x8005;
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "std")]
+    use std::io::Cursor;
+
+    #[cfg(feature = "std")]
+    use adx_header::AdxHeader;
+    #[cfg(feature = "std")]
+    use decoder::{AhxDecoder, Decoder};
+    #[cfg(feature = "std")]
+    use decoder::layer2::{self, BitReader, Synthesizer};
+    #[cfg(feature = "std")]
+    use encoder::ahx_encoder::AhxEncoder;
+    #[cfg(feature = "std")]
+    use {Sample, AHX_DELAY};
+    #[cfg(feature = "std")]
+    use super::{Mp2Decoder, ChannelMode};
+    use super::{Mp2FrameHeader, crc16};
+
+    #[cfg(feature = "std")]
+    fn decode_all<D>(mut decoder: D) -> Vec<Sample>
+        where D: Decoder
+    {
+        let mut samples = Vec::new();
+        while let Some(sample) = decoder.next_sample() {
+            samples.push(sample);
+        }
+        samples
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc16(0xffff, b"123456789", 72), 0xaee7);
+    }
+
+    #[test]
+    fn header_round_trip() {
+        for &raw_header in &[0xfff5e0c0, 0xfffca040, 0xfffd9260, 0xffe5a2c0] {
+            assert_eq!(Mp2FrameHeader::from_u32(raw_header).unwrap().to_u32(), raw_header);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn ahx_frames_as_mp2() {
+        let input: Vec<i16> = (0..5000)
+            .map(|i| ((i as f64 * 0.05).sin() * 8000.0 + (i as f64 * 0.31).sin() * 3000.0) as i16)
+            .collect();
+
+        let mut ahx = Cursor::new(Vec::new());
+        {
+            let mut encoder = AhxEncoder::new(&mut ahx).unwrap();
+            encoder.encode_data(input.iter().cloned()).unwrap();
+            encoder.finalize().unwrap();
+        }
+        let ahx = ahx.into_inner();
+
+        // AHX frames are packed tight. Pad each one out to the size its
+        // header says it should be to make a plain mp2 stream.
+        let mut mp2 = Vec::new();
+        let mut cursor = Cursor::new(&ahx[0x24..]);
+        loop {
+            let start = cursor.position() as usize;
+            let mut reader = BitReader::new(&mut cursor);
+            let raw_header = reader.read(32).unwrap();
+            let header = match Mp2FrameHeader::from_u32(raw_header) {
+                Ok(header) => header,
+                Err(_) => break,
+            };
+            let layout = header.layout();
+            let allocation = layer2::read_allocation(&mut reader, &layout).unwrap();
+            let mut pcm = [[0; 1152]];
+            layer2::read_samples(&mut reader, &layout, &allocation, &mut [Synthesizer::new()], &mut pcm).unwrap();
+
+            let end = cursor.position() as usize;
+            let frame_start = mp2.len();
+            mp2.extend_from_slice(&ahx[0x24 + start..0x24 + end]);
+            mp2.resize(frame_start + header.frame_len(), 0);
+        }
+
+        let header = AdxHeader::read_header(Cursor::new(&ahx)).unwrap();
+        let ahx_output: Vec<i16> = decode_all(AhxDecoder::from_header(header, Cursor::new(&ahx[0x24..])))
+            .iter()
+            .map(|sample| sample[0])
+            .collect();
+
+        let mp2_decoder = Mp2Decoder::new(Cursor::new(mp2)).unwrap();
+        assert_eq!(mp2_decoder.channels(), 1);
+        assert_eq!(mp2_decoder.sample_rate(), 22050);
+        let mp2_output: Vec<i16> = decode_all(mp2_decoder).iter().map(|sample| sample[0]).collect();
+
+        assert_eq!(ahx_output.len(), input.len());
+        assert_eq!(&mp2_output[AHX_DELAY..AHX_DELAY + input.len()], &ahx_output[..]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn protected_joint_stereo() {
+        // MPEG-1, 192kbps, 44100Hz, joint stereo, with a CRC
+        let raw_header = 0xfffca040;
+        let header = Mp2FrameHeader::from_u32(raw_header).unwrap();
+        assert!(header.protected);
+        assert_eq!(header.channels(), 2);
+        assert_eq!(header.frame_len(), 626);
+
+        // Every subband silent
+        let layout = header.layout();
+        let mut frame = vec![0; header.frame_len()];
+        frame[0..4].copy_from_slice(&[0xff, 0xfc, 0xa0, 0x40]);
+        let allocation = layer2::read_allocation(&mut BitReader::new(&frame[6..]), &layout).unwrap();
+        let crc = crc16(0xffff, &frame[2..4], 16);
+        let crc = crc16(crc, &frame[6..], allocation.protected_bits(&layout));
+        frame[4] = (crc >> 8) as u8;
+        frame[5] = crc as u8;
+
+        let mut stream = b"ID3\x03\x00\x00\x00\x00\x00\x02\x00\x00".to_vec();
+        stream.extend_from_slice(&frame);
+        stream.extend_from_slice(&frame);
+        let decoder = Mp2Decoder::new(Cursor::new(stream.clone())).unwrap();
+        assert_eq!(decoder.sample_rate(), 44100);
+        let output = decode_all(decoder);
+        assert_eq!(output.len(), 2304);
+        assert!(output.iter().all(|sample| sample == &[0, 0]));
+
+        // Break the CRC on the second frame
+        stream[12 + 626 + 5] ^= 1;
+        let decoder = Mp2Decoder::new(Cursor::new(stream)).unwrap();
+        assert_eq!(decode_all(decoder).len(), 1152);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn joint_stereo_matches_reference() {
+        // Noise coded at 48kHz and 192kbps in joint stereo, stepping through
+        // all four bounds with audio above each, decoded by minimp3. That
+        // works in floating point, so the samples can be off by a little.
+        let data = include_bytes!("../../testdata/joint.mp2");
+        let expected: Vec<i16> = include_bytes!("../../testdata/joint.mp2.pcm")
+            .chunks(2)
+            .map(|bytes| bytes[0] as u16 as i16 | (bytes[1] as i16) << 8)
+            .collect();
+
+        let decoder = Mp2Decoder::new(Cursor::new(&data[..])).unwrap();
+        assert_eq!(decoder.header().mode, ChannelMode::JointStereo);
+        let decoded: Vec<i16> = decode_all(decoder).iter().flat_map(|sample| sample.iter().cloned()).collect();
+        assert_eq!(decoded.len(), expected.len());
+        for (idx, (&sample, &reference)) in decoded.iter().zip(expected.iter()).enumerate() {
+            assert!((sample as i32 - reference as i32).abs() <= 2, "sample {}: {} vs {}", idx, sample, reference);
+        }
+    }
+}
@@ -1,12 +1,15 @@
+use alloc::vec::Vec;
 use std::cmp;
 use std::i16;
-use std::io::{Seek, Read, SeekFrom};
 use std::iter;
 
 use adx_header::{AdxHeader, AdxVersion};
 use adx_reader::AdxReader;
-use decoder::Decoder;
-use error::RadxResult;
+use decoder::{Decoder, DecoderState, PlaybackEvent, PlaybackTracker, RecoveryEvent, RecoveryKind};
+use decoder::state::{StateReader, StateWriter, STANDARD_STATE};
+use error::{RadxResult, RadxError};
+use progress::Progress;
+use source::{Source, SeekSource};
 use {Sample, LoopInfo, gen_coeffs};
 
 struct LoopReadInfo {
@@ -27,40 +30,38 @@ pub struct StandardDecoder<S> {
     alignment_samples: u32,
     current_sample: u32,
     loop_info: Option<LoopReadInfo>,
+    /// Where the first frame starts, if we could find out.
+    data_offset: Option<u64>,
+    recovery: bool,
+    truncated: bool,
+    events: Vec<RecoveryEvent>,
+    playback: PlaybackTracker,
+    progress: Progress,
 }
 
 impl<S> StandardDecoder<S>
-    where S: Read + Seek
+    where S: SeekSource
 {
-    pub fn from_header(header: AdxHeader, inner: S, looping: bool) -> StandardDecoder<S> {
+    pub fn from_header(header: AdxHeader, mut inner: S, looping: bool) -> StandardDecoder<S> {
+        let data_offset = inner.position().ok();
         let (coeff1, coeff2) = gen_coeffs(header.highpass_frequency as u32, header.sample_rate);
         let prev_sample = iter::repeat(0).take(header.channel_count as usize).collect();
         let prev_prev_sample = iter::repeat(0).take(header.channel_count as usize).collect();
 
-        let alignment_samples;
+        let alignment_samples = alignment_samples(&header);
         let loop_info = if looping {
             match header.version {
                 AdxVersion::Version3(Some(loop_info)) => {
-                    alignment_samples = loop_info.alignment_samples as u32;
                     Some(LoopReadInfo {
                         begin_byte: loop_info.begin_byte as usize,
                         begin_sample: loop_info.begin_sample as usize,
                         end_sample: loop_info.end_sample as usize,
                     })
                 }
-                _ => {
-                    alignment_samples = 0;
-                    None
-                }
+                _ => None,
             }
         }
         else {
-            match header.version {
-                AdxVersion::Version3(Some(loop_info)) =>
-                    alignment_samples = loop_info.alignment_samples as u32,
-                _ =>
-                    alignment_samples = 0,
-            }
             None
         };
 
@@ -76,21 +77,105 @@ impl<S> StandardDecoder<S>
             alignment_samples: alignment_samples,
             current_sample: 0,
             loop_info: loop_info,
+            data_offset: data_offset,
+            recovery: false,
+            truncated: false,
+            events: Vec::new(),
+            playback: PlaybackTracker::new(),
+            progress: Progress::new(),
         }
     }
 
+    fn decode_sample(&mut self) -> Option<Sample> {
+        if let Some(ref mut loop_info) = self.loop_info {
+            if self.current_sample as usize == loop_info.end_sample {
+                self.inner.seek_to(loop_info.begin_byte as u64).unwrap();
+                // Signal a reload of samples.
+                self.sample_vec_idx = self.samples.len();
+                self.current_sample = loop_info.begin_sample as u32;
+                self.playback.wrap();
+            }
+        }
+
+        if self.current_sample == self.header.total_samples {
+            return None;
+        }
+
+        while self.sample_vec_idx == self.samples.len() {
+            if self.progress.should_stop(self.playback.played()) {
+                return None;
+            }
+            self.samples = match self.read_frame().unwrap_or(None) {
+                Some(v) => v,
+                None => return None,
+            };
+
+            // Take account of alignment samples
+            let skip = cmp::min(self.alignment_samples as usize, self.samples.len());
+            self.sample_vec_idx = skip;
+            self.current_sample += skip as u32;
+            self.alignment_samples -= skip as u32;
+        }
+
+        let result = self.samples[self.sample_vec_idx].clone();
+        self.sample_vec_idx += 1;
+        self.current_sample += 1;
+        Some(result)
+    }
+
     fn read_frame(&mut self) -> RadxResult<Option<Vec<Sample>>> {
+        // Only worth a seek when we might need to report where we are
+        let frame_start = if self.recovery {
+            self.inner.position()?
+        }
+        else {
+            0
+        };
+
         let mut bitreader = BitReader::new(&mut self.inner);
         let samples_per_block = ((self.header.block_size as u32 - 2) * 8) / self.header.sample_bitdepth as u32;
         let mut samples: Vec<Sample> = iter::repeat(iter::repeat(0).take(self.header.channel_count as usize).collect())
             .take(samples_per_block as usize).collect();
 
-        for channel in 0..self.header.channel_count as usize {
-            let raw_scale = bitreader.read(16)?;
+        'channels: for channel in 0..self.header.channel_count as usize {
+            let raw_scale = match bitreader.read(16) {
+                Ok(raw_scale) => raw_scale,
+                Err(ref err) if self.recovery && is_eof(err) => {
+                    self.record_truncation(frame_start);
+                    if channel == 0 {
+                        // Nothing left of this frame to salvage
+                        return Ok(None);
+                    }
+                    break 'channels;
+                }
+                Err(err) => return Err(err),
+            };
             if raw_scale == 0x8001 {
                 return Ok(None);
             }
 
+            // Scales never use the top bit outside of the end marker, so
+            // this block is garbage. Skip it and leave it silent.
+            if self.recovery && raw_scale & 0x8000 != 0 {
+                self.events.push(RecoveryEvent {
+                    offset: frame_start + (channel * self.header.block_size as usize) as u64,
+                    kind: RecoveryKind::BadBlock,
+                });
+                self.prev_sample[channel] = 0;
+                self.prev_prev_sample[channel] = 0;
+
+                for _ in 0..samples_per_block {
+                    if let Err(err) = bitreader.read(self.header.sample_bitdepth as u32) {
+                        if is_eof(&err) {
+                            self.record_truncation(frame_start);
+                            break 'channels;
+                        }
+                        return Err(err);
+                    }
+                }
+                continue;
+            }
+
             let scale = raw_scale as i32;
 
             for sample_idx in 0..samples_per_block as usize {
@@ -102,7 +187,15 @@ impl<S> StandardDecoder<S>
                 let prediction = prediction_fixed_point >> 12;
 
                 // Get delta
-                let delta = scale * sign_extend(bitreader.read(self.header.sample_bitdepth as u32)?, self.header.sample_bitdepth as u32);
+                let nibble = match bitreader.read(self.header.sample_bitdepth as u32) {
+                    Ok(nibble) => nibble,
+                    Err(ref err) if self.recovery && is_eof(err) => {
+                        self.record_truncation(frame_start);
+                        break 'channels;
+                    }
+                    Err(err) => return Err(err),
+                };
+                let delta = scale * sign_extend(nibble, self.header.sample_bitdepth as u32);
 
                 // Calculate sample
                 let unclamped_sample = prediction as i32 + delta;
@@ -126,19 +219,22 @@ impl<S> StandardDecoder<S>
             }
         }
 
-        // Take account of alignment samples
-        if self.alignment_samples != 0 {
-            self.sample_vec_idx = self.alignment_samples as usize;
-            self.current_sample = self.alignment_samples;
-            self.alignment_samples = 0;
-        }
-
         Ok(Some(samples))
     }
+
+    fn record_truncation(&mut self, offset: u64) {
+        if !self.truncated {
+            self.truncated = true;
+            self.events.push(RecoveryEvent {
+                offset: offset,
+                kind: RecoveryKind::Truncated,
+            });
+        }
+    }
 }
 
 impl<S> Decoder for StandardDecoder<S>
-    where S: Seek + Read
+    where S: SeekSource
 {
     fn channels(&self) -> u32 {
         self.header.channel_count as u32
@@ -152,59 +248,181 @@ impl<S> Decoder for StandardDecoder<S>
         match self.header.version {
             AdxVersion::Version3(Some(loop_info)) => {
                 Some(LoopInfo {
-                    start_sample: loop_info.begin_sample - loop_info.alignment_samples as u32,
-                    end_sample: loop_info.end_sample - loop_info.alignment_samples as u32,
+                    start_sample: loop_info.begin_sample.saturating_sub(loop_info.alignment_samples as u32),
+                    end_sample: loop_info.end_sample.saturating_sub(loop_info.alignment_samples as u32),
                 })
             }
             _ => None,
         }
     }
 
+    fn looping(&self) -> bool {
+        self.loop_info.is_some()
+    }
+
     fn next_sample(&mut self) -> Option<Sample> {
-        if let Some(ref mut loop_info) = self.loop_info {
-            if self.current_sample as usize == loop_info.end_sample {
-                self.inner.seek(SeekFrom::Start(loop_info.begin_byte as u64)).unwrap();
-                // Signal a reload of samples.
-                self.sample_vec_idx = self.samples.len();
-                self.current_sample = loop_info.begin_sample as u32;
-            }
+        self.progress.resume();
+        let sample = self.decode_sample();
+        if !self.progress.stopped() {
+            self.playback.advance(&sample);
         }
+        sample
+    }
 
-        if self.sample_vec_idx == self.samples.len() {
-            self.samples = match self.read_frame().unwrap_or(None) {
-                Some(v) => v,
-                None => return None,
-            };
-            self.sample_vec_idx = 0;
+    fn total_samples(&self) -> Option<u32> {
+        // A damaged header can claim more alignment than there are samples
+        Some(self.header.total_samples.saturating_sub(alignment_samples(&self.header)))
+    }
+
+    fn rewind(&mut self) -> RadxResult<bool> {
+        let data_offset = match self.data_offset {
+            Some(data_offset) => data_offset,
+            None => return Ok(false),
+        };
+        self.inner.seek_to(data_offset)?;
+
+        for channel in 0..self.header.channel_count as usize {
+            self.prev_sample[channel] = 0;
+            self.prev_prev_sample[channel] = 0;
         }
+        self.samples.clear();
+        self.sample_vec_idx = 0;
+        self.alignment_samples = alignment_samples(&self.header);
+        self.current_sample = 0;
+        self.truncated = false;
+        self.playback.reset();
+        Ok(true)
+    }
 
-        if self.current_sample == self.header.total_samples {
-            None
+    fn set_recovery(&mut self, recovery: bool) {
+        self.recovery = recovery;
+    }
+
+    fn take_recovery_events(&mut self) -> Vec<RecoveryEvent> {
+        self.events.drain(..).collect()
+    }
+
+    fn take_playback_events(&mut self) -> Vec<PlaybackEvent> {
+        self.playback.take()
+    }
+
+    fn set_progress(&mut self, mut progress: Progress) {
+        // Looping streams go on forever
+        if self.loop_info.is_none() {
+            progress.default_total(self.total_samples().map(|total| total as u64));
         }
-        else {
-            let result = self.samples[self.sample_vec_idx].clone();
-            self.sample_vec_idx += 1;
-            self.current_sample += 1;
-            Some(result)
+        self.progress = progress;
+    }
+
+    fn was_cancelled(&self) -> bool {
+        self.progress.stopped()
+    }
+
+    fn save_state(&mut self) -> RadxResult<DecoderState> {
+        let mut writer = StateWriter::new(STANDARD_STATE);
+        writer.write_u8(self.header.channel_count);
+        writer.write_u32(self.header.total_samples);
+        writer.write_bool(self.loop_info.is_some());
+        writer.write_u64(self.inner.position()?);
+        for channel in 0..self.header.channel_count as usize {
+            writer.write_i16(self.prev_sample[channel]);
+            writer.write_i16(self.prev_prev_sample[channel]);
+        }
+        writer.write_u32(self.samples.len() as u32);
+        for sample in self.samples.iter() {
+            for &channel_sample in sample.iter() {
+                writer.write_i16(channel_sample);
+            }
         }
+        writer.write_u32(self.sample_vec_idx as u32);
+        writer.write_u32(self.current_sample);
+        writer.write_u32(self.alignment_samples);
+        writer.write_bool(self.truncated);
+        self.playback.save(&mut writer);
+        Ok(writer.finish())
+    }
+
+    fn restore_state(&mut self, state: &DecoderState) -> RadxResult<()> {
+        let channels = self.header.channel_count as usize;
+
+        let mut reader = StateReader::new(state, STANDARD_STATE)?;
+        if reader.read_u8()? != self.header.channel_count {
+            return Err(RadxError::BadDecoderState("state is for a different stream"));
+        }
+        reader.expect_u32(self.header.total_samples)?;
+        if reader.read_bool()? != self.loop_info.is_some() {
+            return Err(RadxError::BadDecoderState("state is from a decoder with different looping"));
+        }
+        let position = reader.read_u64()?;
+        let mut prev_sample = Vec::with_capacity(channels);
+        let mut prev_prev_sample = Vec::with_capacity(channels);
+        for _ in 0..channels {
+            prev_sample.push(reader.read_i16()?);
+            prev_prev_sample.push(reader.read_i16()?);
+        }
+        let sample_count = reader.read_u32()? as usize;
+        let samples_per_block = ((self.header.block_size as usize - 2) * 8) / self.header.sample_bitdepth as usize;
+        if sample_count > samples_per_block {
+            return Err(RadxError::BadDecoderState("too many buffered samples"));
+        }
+        let mut samples = Vec::with_capacity(sample_count);
+        for _ in 0..sample_count {
+            let mut sample = Vec::with_capacity(channels);
+            for _ in 0..channels {
+                sample.push(reader.read_i16()?);
+            }
+            samples.push(sample);
+        }
+        let sample_vec_idx = reader.read_u32()? as usize;
+        let current_sample = reader.read_u32()?;
+        let alignment_samples = reader.read_u32()?;
+        let truncated = reader.read_bool()?;
+        let playback = PlaybackTracker::restore(&mut reader)?;
+        reader.finish()?;
+
+        if sample_vec_idx > samples.len() || current_sample > self.header.total_samples {
+            return Err(RadxError::BadDecoderState("position out of range"));
+        }
+
+        self.inner.seek_to(position)?;
+        self.prev_sample = prev_sample;
+        self.prev_prev_sample = prev_prev_sample;
+        self.samples = samples;
+        self.sample_vec_idx = sample_vec_idx;
+        self.current_sample = current_sample;
+        self.alignment_samples = alignment_samples;
+        self.truncated = truncated;
+        self.playback = playback;
+        Ok(())
     }
 }
 
+fn alignment_samples(header: &AdxHeader) -> u32 {
+    match header.version {
+        AdxVersion::Version3(Some(loop_info)) => loop_info.alignment_samples as u32,
+        _ => 0,
+    }
+}
+
+fn is_eof(err: &RadxError) -> bool {
+    matches!(*err, RadxError::UnexpectedEof)
+}
+
 fn sign_extend(num: u32, bits: u32) -> i32 {
     let bits_to_shift = 32 - bits;
     (num << bits_to_shift) as i32 >> bits_to_shift
 }
 
-struct BitReader<R> {
-    inner: R,
+struct BitReader<'a, R: 'a + ?Sized> {
+    inner: &'a mut R,
     buffer: u8,
     bits_left: u32,
 }
 
-impl<R> BitReader<R>
-    where R: Read
+impl<'a, R> BitReader<'a, R>
+    where R: Source + ?Sized
 {
-    fn new(inner: R) -> BitReader<R> {
+    fn new(inner: &'a mut R) -> BitReader<'a, R> {
         BitReader {
             inner: inner,
             buffer: 0,
@@ -243,12 +461,216 @@ impl<R> BitReader<R>
 
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "std")]
+    use std::io::Cursor;
+    #[cfg(feature = "std")]
+    use std::sync::Arc;
+    #[cfg(feature = "std")]
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    #[cfg(feature = "std")]
+    use {AdxSpec, DEFAULT_HIGHPASS_FREQUENCY, LoopInfo, from_reader};
+    #[cfg(feature = "std")]
+    use decoder::{DecoderState, PlaybackEvent, RecoveryEvent, RecoveryKind};
+    #[cfg(feature = "std")]
+    use encoder::standard_encoder::StandardEncoder;
+    #[cfg(feature = "std")]
+    use error::RadxError;
+    #[cfg(feature = "std")]
+    use loudness;
+    #[cfg(feature = "std")]
+    use progress::Progress;
     use super::BitReader;
 
+    #[cfg(feature = "std")]
+    fn encode_stereo(samples: usize) -> Vec<u8> {
+        encode_stereo_looping(samples, None)
+    }
+
+    #[cfg(feature = "std")]
+    fn encode_stereo_looping(samples: usize, loop_info: Option<LoopInfo>) -> Vec<u8> {
+        let spec = AdxSpec {
+            channels: 2,
+            sample_rate: 32000,
+            loop_info: loop_info,
+            highpass_frequency: DEFAULT_HIGHPASS_FREQUENCY,
+        };
+        let mut buf = Cursor::new(Vec::new());
+        let mut encoder = StandardEncoder::new(&mut buf, spec).unwrap();
+        encoder.encode_data((0..samples).map(|i| {
+            let sample = ((i as f64 * 0.05).sin() * 10000.0) as i16;
+            vec![sample, -sample]
+        })).unwrap();
+        encoder.finish().unwrap();
+        buf.into_inner()
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn recover_bad_block() {
+        let mut data = encode_stereo(3200);
+        // Second channel of the sixth frame
+        let bad_block = 0x32 + 5 * 36 + 18;
+        data[bad_block] = 0xff;
+
+        let mut decoder = from_reader(Cursor::new(data), false).unwrap();
+        decoder.set_recovery(true);
+        let samples: Vec<_> = decoder.by_ref().collect();
+        assert_eq!(samples.len(), 3200);
+        assert!(samples[160..192].iter().all(|sample| sample[1] == 0));
+        assert!(samples[160..192].iter().any(|sample| sample[0] != 0));
+        assert_eq!(decoder.take_recovery_events(), vec![RecoveryEvent {
+            offset: bad_block as u64,
+            kind: RecoveryKind::BadBlock,
+        }]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn recover_truncated() {
+        let mut data = encode_stereo(3200);
+        // Partway through the second channel of the eleventh frame
+        data.truncate(0x32 + 10 * 36 + 20);
+
+        let decoder = from_reader(Cursor::new(data.clone()), false).unwrap();
+        assert_eq!(decoder.count(), 320);
+
+        let mut decoder = from_reader(Cursor::new(data), false).unwrap();
+        decoder.set_recovery(true);
+        assert_eq!(decoder.by_ref().count(), 352);
+        assert_eq!(decoder.take_recovery_events(), vec![RecoveryEvent {
+            offset: 0x32 + 10 * 36,
+            kind: RecoveryKind::Truncated,
+        }]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn playback_events() {
+        let data = encode_stereo_looping(1000, Some(LoopInfo {
+            start_sample: 100,
+            end_sample: 800,
+        }));
+        let mut decoder = from_reader(Cursor::new(data), true).unwrap();
+        let mut block = [0; 600];
+        for _ in 0..8 {
+            assert_eq!(decoder.read_block(&mut block), 300);
+        }
+        assert_eq!(decoder.take_playback_events(), vec![
+            PlaybackEvent::LoopWrap { sample: 800, loop_count: 1 },
+            PlaybackEvent::LoopWrap { sample: 1500, loop_count: 2 },
+            PlaybackEvent::LoopWrap { sample: 2200, loop_count: 3 },
+        ]);
+
+        let mut decoder = from_reader(Cursor::new(encode_stereo(1000)), false).unwrap();
+        assert_eq!(decoder.read_block(&mut block), 300);
+        assert!(decoder.take_playback_events().is_empty());
+        assert_eq!(decoder.by_ref().count(), 700);
+        assert_eq!(decoder.take_playback_events(), vec![PlaybackEvent::End { sample: 1000 }]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn cancel() {
+        let data = encode_stereo(1000);
+        let expected: Vec<_> = from_reader(Cursor::new(data.clone()), false).unwrap().collect();
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let reports = Arc::new(AtomicUsize::new(0));
+        let mut decoder = from_reader(Cursor::new(data), false).unwrap();
+        {
+            let cancel = cancel.clone();
+            let reports = reports.clone();
+            decoder.set_progress(Progress::new()
+                .cancel_flag(cancel.clone())
+                .callback(move |played, total| {
+                    assert_eq!(total, Some(1000));
+                    // Only the first time through 512
+                    if reports.fetch_add(1, Ordering::SeqCst) == 16 {
+                        assert_eq!(played, 512);
+                        cancel.store(true, Ordering::SeqCst);
+                    }
+                }));
+        }
+
+        let mut output: Vec<_> = decoder.by_ref().collect();
+        assert_eq!(output.len(), 512);
+        assert!(decoder.was_cancelled());
+        assert!(decoder.take_playback_events().is_empty());
+
+        // The fallible versions tell it apart from the end of the stream
+        match decoder.try_next_sample() {
+            Err(RadxError::Cancelled) => {}
+            other => panic!("{:?}", other),
+        }
+        match decoder.try_read_block(&mut [0; 64]) {
+            Err(RadxError::Cancelled) => {}
+            other => panic!("{:?}", other),
+        }
+        match loudness::measure(&mut *decoder) {
+            Err(RadxError::Cancelled) => {}
+            other => panic!("{:?}", other),
+        }
+
+        cancel.store(false, Ordering::SeqCst);
+        output.extend(decoder.by_ref());
+        assert!(!decoder.was_cancelled());
+        assert_eq!(output, expected);
+        assert_eq!(reports.load(Ordering::SeqCst), 1000 / 32 + 5);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn restore_state() {
+        let data = encode_stereo_looping(1000, Some(LoopInfo {
+            start_sample: 100,
+            end_sample: 800,
+        }));
+
+        // Save partway through a block, then keep going past a loop wrap
+        let mut decoder = from_reader(Cursor::new(data.clone()), true).unwrap();
+        let _: Vec<_> = decoder.by_ref().take(555).collect();
+        let state = decoder.save_state().unwrap();
+        let expected: Vec<_> = decoder.by_ref().take(1000).collect();
+        let expected_events = decoder.take_playback_events();
+
+        let mut restored = from_reader(Cursor::new(data), true).unwrap();
+        let state = DecoderState::from_bytes(state.into_bytes());
+        restored.restore_state(&state).unwrap();
+        let output: Vec<_> = restored.by_ref().take(1000).collect();
+        assert_eq!(output, expected);
+        assert_eq!(restored.take_playback_events(), expected_events);
+        assert_eq!(expected_events, vec![
+            PlaybackEvent::LoopWrap { sample: 800, loop_count: 1 },
+            PlaybackEvent::LoopWrap { sample: 1500, loop_count: 2 },
+        ]);
+
+        // Not a state for this stream
+        let other = from_reader(Cursor::new(encode_stereo(1000)), false).unwrap().save_state().unwrap();
+        assert!(restored.restore_state(&other).is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn alignment_skipped() {
+        // A loop start of 100 needs 28 alignment samples, which shouldn't be heard
+        let data = encode_stereo_looping(1000, Some(LoopInfo {
+            start_sample: 100,
+            end_sample: 800,
+        }));
+        let output: Vec<_> = from_reader(Cursor::new(data), false).unwrap().collect();
+        assert_eq!(output.len(), 1000);
+        for (i, sample) in output[..100].iter().enumerate() {
+            let expected = ((i as f64 * 0.05).sin() * 10000.0) as i32;
+            assert!((sample[0] as i32 - expected).abs() < 500, "sample {}: {} became {}", i, expected, sample[0]);
+        }
+    }
+
     #[test]
     fn bitreader() {
-        let data: Vec<u8> = vec![0xaa, 0xab, 0xa5, 0x80, 0xff, 0xff, 0x00, 0x00];
-        let mut br = BitReader::new(data.as_slice());
+        let data = [0xaa, 0xab, 0xa5, 0x80, 0xff, 0xff, 0x00, 0x00];
+        let mut slice = &data[..];
+        let mut br = BitReader::new(&mut slice);
 
         assert_eq!(br.read(16).unwrap(), 0xaaab);
         assert_eq!(br.read(4).unwrap(), 0xa);
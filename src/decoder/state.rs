@@ -0,0 +1,167 @@
+use alloc::vec::Vec;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use error::{RadxResult, RadxError};
+
+const STATE_MAGIC: &[u8; 4] = b"RDXS";
+const STATE_VERSION: u8 = 1;
+
+pub(crate) const STANDARD_STATE: u8 = 1;
+pub(crate) const AHX_STATE: u8 = 2;
+
+/// A snapshot of where a decoder is, from `Decoder::save_state`.
+///
+/// The bytes are self-contained, so they can go straight into a save file
+/// and come back with `from_bytes`. They only make sense to a decoder reading
+/// the same stream as the one that saved them.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct DecoderState {
+    bytes: Vec<u8>,
+}
+
+impl DecoderState {
+    pub fn from_bytes(bytes: Vec<u8>) -> DecoderState {
+        DecoderState {
+            bytes: bytes,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub(crate) struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub(crate) fn new(kind: u8) -> StateWriter {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(STATE_MAGIC);
+        bytes.push(STATE_VERSION);
+        bytes.push(kind);
+        StateWriter {
+            bytes: bytes,
+        }
+    }
+
+    pub(crate) fn write_u8(&mut self, val: u8) {
+        self.bytes.push(val);
+    }
+
+    pub(crate) fn write_bool(&mut self, val: bool) {
+        self.bytes.push(val as u8);
+    }
+
+    pub(crate) fn write_u32(&mut self, val: u32) {
+        let mut buf = [0; 4];
+        BigEndian::write_u32(&mut buf, val);
+        self.bytes.extend_from_slice(&buf);
+    }
+
+    pub(crate) fn write_u64(&mut self, val: u64) {
+        let mut buf = [0; 8];
+        BigEndian::write_u64(&mut buf, val);
+        self.bytes.extend_from_slice(&buf);
+    }
+
+    pub(crate) fn write_i16(&mut self, val: i16) {
+        let mut buf = [0; 2];
+        BigEndian::write_i16(&mut buf, val);
+        self.bytes.extend_from_slice(&buf);
+    }
+
+    pub(crate) fn write_i64(&mut self, val: i64) {
+        let mut buf = [0; 8];
+        BigEndian::write_i64(&mut buf, val);
+        self.bytes.extend_from_slice(&buf);
+    }
+
+    pub(crate) fn finish(self) -> DecoderState {
+        DecoderState::from_bytes(self.bytes)
+    }
+}
+
+pub(crate) struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub(crate) fn new(state: &'a DecoderState, kind: u8) -> RadxResult<StateReader<'a>> {
+        let bytes = state.as_bytes();
+        if bytes.len() < 6 || &bytes[0..4] != STATE_MAGIC {
+            return Err(RadxError::BadDecoderState("not a decoder state"));
+        }
+        if bytes[4] != STATE_VERSION {
+            return Err(RadxError::BadDecoderState("unknown state version"));
+        }
+        if bytes[5] != kind {
+            return Err(RadxError::BadDecoderState("state is for a different kind of decoder"));
+        }
+        Ok(StateReader {
+            bytes: &bytes[6..],
+        })
+    }
+
+    fn take(&mut self, len: usize) -> RadxResult<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(RadxError::BadDecoderState("state is cut short"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn read_u8(&mut self) -> RadxResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn read_bool(&mut self) -> RadxResult<bool> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(RadxError::BadDecoderState("bad flag value")),
+        }
+    }
+
+    pub(crate) fn read_u32(&mut self) -> RadxResult<u32> {
+        Ok(BigEndian::read_u32(self.take(4)?))
+    }
+
+    pub(crate) fn read_u64(&mut self) -> RadxResult<u64> {
+        Ok(BigEndian::read_u64(self.take(8)?))
+    }
+
+    pub(crate) fn read_i16(&mut self) -> RadxResult<i16> {
+        Ok(BigEndian::read_i16(self.take(2)?))
+    }
+
+    pub(crate) fn read_i64(&mut self) -> RadxResult<i64> {
+        Ok(BigEndian::read_i64(self.take(8)?))
+    }
+
+    /// Checks that a value saved from the stream matches this one.
+    pub(crate) fn expect_u32(&mut self, val: u32) -> RadxResult<()> {
+        if self.read_u32()? == val {
+            Ok(())
+        }
+        else {
+            Err(RadxError::BadDecoderState("state is for a different stream"))
+        }
+    }
+
+    pub(crate) fn finish(self) -> RadxResult<()> {
+        if self.bytes.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(RadxError::BadDecoderState("trailing data after state"))
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Bit allocation and quantization for writing Layer II frames, with a
+//! simple psychoacoustic model to decide where the bits go.
+
+use std::f64;
+
+use decoder::layer2::{QuantizeSpec, QUANT_CLASSES, alloc_bits};
+
+/// SNR each quantization class gives, in dB. ISO 11172-3 table C.5.
+const CLASS_SNR: [f64; 17] = [
+    7.00, 11.00, 16.00, 20.84, 25.28, 31.59, 37.75, 43.84, 49.89,
+    55.93, 61.96, 67.98, 74.01, 80.03, 86.05, 92.01, 98.01,
+];
+
+/// How far below a masker its masking starts, in dB. Noise masks more than
+/// tones do; this sits in between.
+const MASKING_OFFSET: f64 = 12.0;
+
+/// How quickly masking falls off towards lower frequencies, in dB per Bark.
+const SPREAD_DOWN: f64 = 27.0;
+
+/// How quickly masking falls off towards higher frequencies, in dB per Bark.
+const SPREAD_UP: f64 = 15.0;
+
+/// Where 0 dB SPL sits relative to a full scale signal. Puts the quietest
+/// sound anyone hears at about the level of 16-bit rounding noise.
+const SPL_OFFSET: f64 = -96.0;
+
+/// Frequency `freq` in Hz on the Bark scale.
+fn bark(freq: f64) -> f64 {
+    13.0 * (0.00076 * freq).atan() + 3.5 * (freq / 7500.0).powi(2).atan()
+}
+
+/// The quietest sound that can be heard at `freq` in Hz, in dB relative to
+/// full scale.
+fn threshold_in_quiet(freq: f64) -> f64 {
+    let khz = (freq / 1000.0).max(0.02);
+    let spl = 3.64 * khz.powf(-0.8) - 6.5 * (-0.6 * (khz - 3.3).powi(2)).exp() + 0.001 * khz.powi(4);
+    spl + SPL_OFFSET
+}
+
+/// Signal-to-mask ratio of each subband in dB, from the subband levels in
+/// dB relative to full scale.
+///
+/// Each subband masks the ones around it, less the further away they are,
+/// and nothing can be masked below the threshold in quiet. Silent subbands,
+/// with a level of negative infinity, get negative infinity back.
+pub(crate) fn signal_to_mask(levels: &[f64], sample_rate: u32) -> Vec<f64> {
+    let subband_width = sample_rate as f64 / 64.0;
+    let freqs: Vec<f64> = (0..levels.len()).map(|sb| (sb as f64 + 0.5) * subband_width).collect();
+    let barks: Vec<f64> = freqs.iter().map(|&freq| bark(freq)).collect();
+
+    levels.iter().enumerate().map(|(sb, &level)| {
+        let mut mask = 10.0f64.powf(threshold_in_quiet(freqs[sb]) / 10.0);
+        for (masker, &masker_level) in levels.iter().enumerate() {
+            if masker == sb || masker_level == f64::NEG_INFINITY {
+                continue;
+            }
+            let distance = barks[sb] - barks[masker];
+            let spread = if distance > 0.0 { SPREAD_UP * distance } else { SPREAD_DOWN * -distance };
+            mask += 10.0f64.powf((masker_level - MASKING_OFFSET - spread) / 10.0);
+        }
+        level - 10.0 * mask.log10()
+    }).collect()
+}
+
+/// Bits a subband's samples take in a frame with allocation `alloc` from
+/// `classes`.
+pub(crate) fn sample_bits(classes: &[u8], alloc: usize) -> u32 {
+    match class(classes, alloc) {
+        Some(quant) if quant.group != 0 => quant.bits * 12,
+        Some(quant) => quant.bits * 36,
+        None => 0,
+    }
+}
+
+fn class(classes: &[u8], alloc: usize) -> Option<QuantizeSpec> {
+    if alloc == 0 {
+        None
+    }
+    else {
+        Some(QUANT_CLASSES[classes[alloc - 1] as usize])
+    }
+}
+
+/// Bits the allocation values take for a frame with `table`, one channel.
+pub(crate) fn allocation_bits(table: &[&[u8]]) -> u32 {
+    table.iter().map(|classes| alloc_bits(classes)).sum()
+}
+
+/// Chooses an allocation value for every subband of `table`, spending at
+/// most `budget` bits on scfsi, scalefactors and samples.
+///
+/// `scalefactor_bits` are what each subband's scfsi and scalefactors take
+/// once it gets any bits. Following ISO 11172-3 annex C, bits keep going to
+/// whichever subband has the lowest mask-to-noise ratio until none fit.
+/// Silent subbands get nothing.
+pub(crate) fn allocate(table: &[&[u8]], smr: &[f64], scalefactor_bits: &[u32], budget: u32) -> Vec<usize> {
+    let mut allocation = vec![0; table.len()];
+    let mut used = 0;
+    loop {
+        let mut best: Option<(usize, f64, u32)> = None;
+        for (sb, classes) in table.iter().enumerate() {
+            let alloc = allocation[sb];
+            if smr[sb] == f64::NEG_INFINITY || alloc == classes.len() {
+                continue;
+            }
+
+            let cost = if alloc == 0 {
+                scalefactor_bits[sb] + sample_bits(classes, 1)
+            }
+            else {
+                sample_bits(classes, alloc + 1) - sample_bits(classes, alloc)
+            };
+            if used + cost > budget {
+                continue;
+            }
+
+            let snr = if alloc == 0 { 0.0 } else { CLASS_SNR[classes[alloc - 1] as usize] };
+            let mnr = snr - smr[sb];
+            if best.map(|(_, best_mnr, _)| mnr < best_mnr).unwrap_or(true) {
+                best = Some((sb, mnr, cost));
+            }
+        }
+
+        match best {
+            Some((sb, _, cost)) => {
+                allocation[sb] += 1;
+                used += cost;
+            }
+            None => return allocation,
+        }
+    }
+}
+
+/// Three quantized samples, ready to write.
+pub(crate) struct Codes {
+    /// One grouped code for the three samples, or a code for each.
+    pub codes: [u32; 3],
+    pub count: usize,
+    /// Bits in each code.
+    pub bits: u32,
+}
+
+/// Quantizes three samples of a subband, already divided by their
+/// scalefactor, with allocation `alloc` from `classes`.
+pub(crate) fn quantize(samples: [i64; 3], classes: &[u8], alloc: usize) -> Codes {
+    let quant = class(classes, alloc).expect("only allocated subbands get quantized");
+    let sample_bits = if quant.group != 0 { quant.group } else { quant.bits };
+
+    // ISO 11172-3 table C.6: A * x + B, keeping the top bits
+    let a = quant.nlevels << (28 - sample_bits);
+    let b = a - (1 << 28);
+    let mut codes = [0; 3];
+    for (code, &sample) in codes.iter_mut().zip(samples.iter()) {
+        let transformed = ((sample * a) >> 28) + b;
+        let quantized = transformed >> (28 - (sample_bits - 1));
+        *code = (quantized & ((1 << sample_bits) - 1)) ^ (1 << (sample_bits - 1));
+    }
+
+    if quant.group != 0 {
+        let grouped = codes[0] + codes[1] * quant.nlevels + codes[2] * quant.nlevels * quant.nlevels;
+        Codes {
+            codes: [grouped as u32, 0, 0],
+            count: 1,
+            bits: quant.bits,
+        }
+    }
+    else {
+        Codes {
+            codes: [codes[0] as u32, codes[1] as u32, codes[2] as u32],
+            count: 3,
+            bits: quant.bits,
+        }
+    }
+}
+
+/// What a decoder reads back from `codes`, as fractions of the scalefactor.
+pub(crate) fn dequantize(codes: &Codes, classes: &[u8], alloc: usize) -> [i64; 3] {
+    let quant = class(classes, alloc).expect("only allocated subbands get quantized");
+    ::decoder::layer2::dequantize(&codes.codes[..codes.count], quant)
+}
@@ -1,2 +1,369 @@
+use std::io::{Seek, SeekFrom};
+
+use convert::{self, Converter, Dither, Resampler};
+use error::{RadxResult, RadxError};
+use loudness::{LoudnessMeter, Normalization};
+use progress::Progress;
+use {Sample, LoopInfo, DEFAULT_HIGHPASS_FREQUENCY};
+use self::mp2_encoder::Mp2Profile;
+use self::standard_encoder::{Crossfade, NoiseShaping, Quality};
+
 pub mod standard_encoder;
 pub mod ahx_encoder;
+pub mod mp2_encoder;
+mod layer2;
+
+/// Anything `to_writer` makes, so callers don't have to care which codec
+/// they're encoding with.
+pub trait Encoder {
+    /// Encodes `samples`, each holding a value for every channel.
+    ///
+    /// # Panics
+    ///
+    /// If a sample has fewer values than the encoder has channels.
+    fn encode_samples(&mut self, samples: &[Sample]) -> RadxResult<()>;
+
+    /// Writes out the rest of the stream.
+    fn finish(self: Box<Self>) -> RadxResult<EncoderStats>;
+
+    /// Reports progress and checks for cancellation between frames. See
+    /// `StandardEncoder::set_progress`.
+    fn set_progress(&mut self, progress: Progress);
+}
+
+/// Which format an encoder writes.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Codec {
+    /// Standard 4 bit ADPCM ADX.
+    Standard,
+    /// MPEG audio in an ADX header. Always mono at 22050 Hz, and can't
+    /// loop. `to_writer` mixes down and resamples other input to fit.
+    Ahx,
+    /// A plain MPEG-1/2 Layer II stream, with no ADX header. Mono or stereo
+    /// at one of the MPEG sample rates, and can't loop.
+    Mp2,
+}
+
+/// How to set up an encoder made with `to_writer`.
+///
+/// Starts out as a non-looping stereo ADX at 44100 Hz, with the same
+/// defaults as `StandardEncoder`.
+#[derive(Clone,Copy,Debug)]
+pub struct EncoderOptions {
+    pub(crate) codec: Codec,
+    pub(crate) channels: u32,
+    pub(crate) sample_rate: u32,
+    pub(crate) loop_info: Option<LoopInfo>,
+    pub(crate) version: u8,
+    pub(crate) quality: Quality,
+    pub(crate) highpass_frequency: u16,
+    pub(crate) noise_shaping: NoiseShaping,
+    pub(crate) crossfade: Option<Crossfade>,
+    pub(crate) match_loop_entry: bool,
+    pub(crate) threads: usize,
+    pub(crate) bitrate: Option<u32>,
+    pub(crate) normalization: Option<Normalization>,
+}
+
+impl EncoderOptions {
+    pub fn new() -> EncoderOptions {
+        EncoderOptions {
+            codec: Codec::Standard,
+            channels: 2,
+            sample_rate: 44100,
+            loop_info: None,
+            version: 3,
+            quality: Quality::Fast,
+            highpass_frequency: DEFAULT_HIGHPASS_FREQUENCY,
+            noise_shaping: NoiseShaping::none(),
+            crossfade: None,
+            match_loop_entry: false,
+            threads: 1,
+            bitrate: None,
+            normalization: None,
+        }
+    }
+
+    pub fn codec(mut self, codec: Codec) -> EncoderOptions {
+        self.codec = codec;
+        self
+    }
+
+    pub fn channels(mut self, channels: u32) -> EncoderOptions {
+        self.channels = channels;
+        self
+    }
+
+    pub fn sample_rate(mut self, sample_rate: u32) -> EncoderOptions {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    pub fn loop_info(mut self, loop_info: Option<LoopInfo>) -> EncoderOptions {
+        self.loop_info = loop_info;
+        self
+    }
+
+    /// ADX header version to write: 3, the default and the only one that
+    /// can loop, 4 or 5. AHX headers are always version 6.
+    pub fn version(mut self, version: u8) -> EncoderOptions {
+        self.version = version;
+        self
+    }
+
+    pub fn quality(mut self, quality: Quality) -> EncoderOptions {
+        self.quality = quality;
+        self
+    }
+
+    pub fn highpass_frequency(mut self, highpass_frequency: u16) -> EncoderOptions {
+        self.highpass_frequency = highpass_frequency;
+        self
+    }
+
+    pub fn noise_shaping(mut self, noise_shaping: NoiseShaping) -> EncoderOptions {
+        self.noise_shaping = noise_shaping;
+        self
+    }
+
+    pub fn crossfade(mut self, crossfade: Option<Crossfade>) -> EncoderOptions {
+        self.crossfade = crossfade;
+        self
+    }
+
+    /// See `StandardEncoder::set_match_loop_entry`.
+    pub fn match_loop_entry(mut self, match_loop_entry: bool) -> EncoderOptions {
+        self.match_loop_entry = match_loop_entry;
+        self
+    }
+
+    /// Threads to encode ADX on, for `StandardEncoder::set_threads`. AHX
+    /// always encodes on one.
+    pub fn threads(mut self, threads: usize) -> EncoderOptions {
+        self.threads = threads;
+        self
+    }
+
+    /// Bitrate in kbps for mp2. Defaults to `Mp2Profile::default_bitrate`.
+    /// ADX and AHX ignore it.
+    pub fn bitrate(mut self, bitrate: u32) -> EncoderOptions {
+        self.bitrate = Some(bitrate);
+        self
+    }
+
+    /// Brings the loudness of the input to a target before it's encoded.
+    /// The encoder holds on to everything it's given until it finishes,
+    /// since it needs the whole track to measure.
+    pub fn normalize(mut self, normalization: Option<Normalization>) -> EncoderOptions {
+        self.normalization = normalization;
+        self
+    }
+
+    /// The mp2 profile these options ask for.
+    pub(crate) fn mp2_profile(&self) -> RadxResult<Mp2Profile> {
+        let bitrate = self.bitrate.unwrap_or(Mp2Profile::default_bitrate(self.channels, self.sample_rate));
+        Mp2Profile::new(self.channels, self.sample_rate, bitrate)
+    }
+
+    /// Checks the options make sense together.
+    pub(crate) fn validate(&self) -> RadxResult<()> {
+        if let Some(normalization) = self.normalization {
+            if !normalization.target.is_finite() || !normalization.peak_ceiling.is_finite() {
+                return Err(RadxError::BadEncoderOptions("loudness target and peak ceiling have to be finite"));
+            }
+        }
+
+        match self.codec {
+            Codec::Standard => {
+                if self.channels == 0 || self.channels > 255 {
+                    return Err(RadxError::BadEncoderOptions("adx needs between 1 and 255 channels"));
+                }
+                if self.version < 3 || self.version > 5 {
+                    return Err(RadxError::BadEncoderOptions("adx header version has to be 3, 4 or 5"));
+                }
+                if self.loop_info.is_some() && self.version != 3 {
+                    return Err(RadxError::BadEncoderOptions("only version 3 headers can loop"));
+                }
+            }
+            Codec::Ahx => {
+                if self.channels == 0 || self.sample_rate == 0 {
+                    return Err(RadxError::BadEncoderOptions("ahx input needs a channel and a sample rate"));
+                }
+                if self.loop_info.is_some() {
+                    return Err(RadxError::BadEncoderOptions("ahx can't loop"));
+                }
+            }
+            Codec::Mp2 => {
+                if self.loop_info.is_some() {
+                    return Err(RadxError::BadEncoderOptions("mp2 can't loop"));
+                }
+                self.mp2_profile()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for EncoderOptions {
+    fn default() -> EncoderOptions {
+        EncoderOptions::new()
+    }
+}
+
+/// Mixes input down to mono and resamples it on the way into an encoder for
+/// a format that's only mono at one rate.
+pub(crate) struct Reformat<'a> {
+    inner: Box<Encoder + 'a>,
+    downmix: bool,
+    resampler: Option<Resampler>,
+}
+
+impl<'a> Reformat<'a> {
+    pub fn new(inner: Box<Encoder + 'a>, channels: u32, sample_rate: u32, output_rate: u32) -> Reformat<'a> {
+        Reformat {
+            inner: inner,
+            downmix: channels != 1,
+            resampler: if sample_rate != output_rate { Some(Resampler::new(1, sample_rate, output_rate)) } else { None },
+        }
+    }
+}
+
+impl<'a> Encoder for Reformat<'a> {
+    fn encode_samples(&mut self, samples: &[Sample]) -> RadxResult<()> {
+        let mono: Vec<Sample>;
+        let mut samples = samples;
+        if self.downmix {
+            mono = samples.iter().map(|sample| vec![convert::downmix(sample)]).collect();
+            samples = &mono;
+        }
+
+        match self.resampler {
+            Some(ref mut resampler) => {
+                let resampled = resampler.process(samples);
+                self.inner.encode_samples(&resampled)
+            }
+            None => self.inner.encode_samples(samples),
+        }
+    }
+
+    fn finish(mut self: Box<Self>) -> RadxResult<EncoderStats> {
+        if let Some(ref mut resampler) = self.resampler {
+            let rest = resampler.finish();
+            self.inner.encode_samples(&rest)?;
+        }
+        self.inner.finish()
+    }
+
+    /// Progress counts samples after resampling.
+    fn set_progress(&mut self, progress: Progress) {
+        self.inner.set_progress(progress)
+    }
+}
+
+/// Measures a whole track on its way into an encoder, then passes it on
+/// with the gain that brings it to a target loudness.
+pub(crate) struct Normalize<'a> {
+    inner: Box<Encoder + 'a>,
+    normalization: Normalization,
+    meter: LoudnessMeter,
+    samples: Vec<Sample>,
+}
+
+impl<'a> Normalize<'a> {
+    pub fn new(inner: Box<Encoder + 'a>, channels: u32, sample_rate: u32, normalization: Normalization) -> Normalize<'a> {
+        Normalize {
+            inner: inner,
+            normalization: normalization,
+            meter: LoudnessMeter::new(channels as usize, sample_rate),
+            samples: Vec::new(),
+        }
+    }
+}
+
+impl<'a> Encoder for Normalize<'a> {
+    fn encode_samples(&mut self, samples: &[Sample]) -> RadxResult<()> {
+        self.meter.add_samples(samples);
+        self.samples.extend_from_slice(samples);
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> RadxResult<EncoderStats> {
+        let gain = 10.0f64.powf(self.normalization.gain(&self.meter.loudness()) / 20.0);
+        let channels = self.samples.first().map(|sample| sample.len()).unwrap_or(0);
+        let mut converter = Converter::new(channels, Dither::Tpdf);
+        for block in self.samples.chunks(4096) {
+            if gain == 1.0 {
+                // Already there, and dither would only add noise
+                self.inner.encode_samples(block)?;
+                continue;
+            }
+            let scaled: Vec<Sample> = block.iter().map(|sample| converter.convert_scaled(sample, gain)).collect();
+            self.inner.encode_samples(&scaled)?;
+        }
+        self.inner.finish()
+    }
+
+    /// Nothing gets encoded until `finish`, so that's when progress gets
+    /// reported.
+    fn set_progress(&mut self, progress: Progress) {
+        self.inner.set_progress(progress)
+    }
+}
+
+/// Seeks a writer that's known to be seekable. Encoders keep this around as
+/// a function pointer so the rest of them works on writers that can't seek.
+pub(crate) fn seek_writer<W>(writer: &mut W, position: u64) -> RadxResult<()>
+    where W: Seek
+{
+    writer.seek(SeekFrom::Start(position))?;
+    Ok(())
+}
+
+/// Signal to noise ratio in dB from the summed squares of a signal and of
+/// its error, for `EncoderStats`.
+pub(crate) fn snr(signal: f64, noise: f64) -> f64 {
+    if noise == 0.0 {
+        f64::INFINITY
+    }
+    else {
+        10.0 * (signal / noise).log10()
+    }
+}
+
+/// SNR in dB of one channel of `output` against `input`, over as many
+/// samples as both have.
+#[cfg(test)]
+pub(crate) fn measure_snr(input: &[Sample], output: &[Sample], channel: usize) -> f64 {
+    let (mut signal, mut noise) = (0.0, 0.0);
+    for (x, y) in input.iter().zip(output.iter()) {
+        let (x, y) = (x[channel] as f64, y[channel] as f64);
+        signal += x * x;
+        noise += (x - y) * (x - y);
+    }
+    snr(signal, noise)
+}
+
+/// How an encode went, from `StandardEncoder::finish`,
+/// `AhxEncoder::finalize` or `Mp2Encoder::finalize`.
+#[derive(Clone,Debug,PartialEq)]
+pub struct EncoderStats {
+    /// Samples encoded for each channel, not counting padding the encoder
+    /// adds itself.
+    pub samples: u32,
+    /// Bytes written, header included.
+    pub bytes: u64,
+    /// How often each scale was picked. For ADX, entry `n` counts the blocks
+    /// with an `n` bit scale, so entry 0 is blocks the predictor got exactly
+    /// right. For AHX and mp2, entry `n` counts subband scalefactors of index
+    /// `n`.
+    pub scale_histogram: Vec<u64>,
+    /// ADX nibbles that couldn't reach the input and were clipped to -8
+    /// or 7.
+    pub clipped_nibbles: u64,
+    /// ADX samples that a decoder has to clamp to 16 bits.
+    pub predictor_clamps: u64,
+    /// Signal to noise ratio of each channel in dB, comparing what a decoder
+    /// gets back with the samples that went in. Infinite when a channel
+    /// comes back exactly.
+    pub snr: Vec<f64>,
+}
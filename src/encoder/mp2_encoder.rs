@@ -0,0 +1,1074 @@
+//! Standard MPEG-1/2 Layer II encoding, the core AHX is built on.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::f64;
+use std::io::Write;
+use std::ops::Index;
+
+use decoder::layer2::{alloc_bits, Synthesizer};
+use decoder::mp2_decoder::{Mp2FrameHeader, MpegVersion, ChannelMode, BITRATE_TABLE_MPEG1, BITRATE_TABLE_LSF};
+use error::{RadxResult, RadxError};
+use progress::Progress;
+use {Sample, AHX_DELAY};
+use super::{Encoder, EncoderStats, snr};
+use super::layer2;
+
+lazy_static! {
+    /// 1 / (2 cos((2k + 1)π / 2n)) for each stage of `dct32`: the stage of
+    /// size n keeps its n / 2 values starting at index n / 2 - 1.
+    static ref SECANTS: [f64; 31] = {
+        let mut secants = [0.0; 31];
+        let mut n = 2;
+        while n <= 32 {
+            for k in 0..n / 2 {
+                let angle = (2 * k + 1) as f64 * f64::consts::PI / (2 * n) as f64;
+                secants[n / 2 - 1 + k] = 0.5 / angle.cos();
+            }
+            n *= 2;
+        }
+        secants
+    };
+}
+
+const ENWINDOW: [i64; 512] = [
+     0x000000,-0x000080,-0x000080,-0x000080,-0x000080,-0x000080,-0x000080,-0x000100,-0x000100,-0x000100,-0x000100,-0x000180,-0x000180,-0x000200,-0x000200,-0x000280,
+    -0x000280,-0x000300,-0x000380,-0x000380,-0x000400,-0x000480,-0x000500,-0x000580,-0x000680,-0x000700,-0x000800,-0x000880,-0x000980,-0x000A80,-0x000C00,-0x000D00,
+    -0x000E80,-0x000F80,-0x001180,-0x001300,-0x001480,-0x001680,-0x001880,-0x001A80,-0x001D00,-0x001F80,-0x002200,-0x002480,-0x002780,-0x002A80,-0x002D80,-0x003080,
+    -0x003400,-0x003780,-0x003A80,-0x003E80,-0x004200,-0x004580,-0x004980,-0x004D00,-0x005080,-0x005480,-0x005800,-0x005B80,-0x005F00,-0x006200,-0x006500,-0x006800,
+     0x006A80, 0x006D00, 0x006F00, 0x007080, 0x007180, 0x007200, 0x007200, 0x007180, 0x007000, 0x006E80, 0x006B80, 0x006800, 0x006400, 0x005E80, 0x005880, 0x005180,
+     0x004900, 0x003F80, 0x003500, 0x002980, 0x001C80, 0x000E80,-0x000100,-0x001200,-0x002400,-0x003780,-0x004C80,-0x006280,-0x007A00,-0x009300,-0x00AD80,-0x00C880,
+    -0x00E580,-0x010380,-0x012280,-0x014280,-0x016380,-0x018580,-0x01A800,-0x01CB80,-0x01EF80,-0x021400,-0x023880,-0x025D00,-0x028180,-0x02A600,-0x02CA00,-0x02ED00,
+    -0x030F80,-0x033100,-0x035100,-0x036F80,-0x038C80,-0x03A700,-0x03BF80,-0x03D500,-0x03E880,-0x03F800,-0x040480,-0x040D80,-0x041280,-0x041380,-0x041000,-0x040780,
+     0x03FA80, 0x03E800, 0x03D000, 0x03B280, 0x038F00, 0x036580, 0x033600, 0x02FF80, 0x02C300, 0x028000, 0x023580, 0x01E500, 0x018D00, 0x012E80, 0x00C900, 0x005C80,
+    -0x001680,-0x009000,-0x011080,-0x019700,-0x022380,-0x02B600,-0x034E00,-0x03EB00,-0x048D00,-0x053380,-0x05DE00,-0x068B80,-0x073C80,-0x07EF80,-0x08A480,-0x095A00,
+    -0x0A1080,-0x0AC680,-0x0B7B80,-0x0C2E80,-0x0CDE80,-0x0D8B80,-0x0E3380,-0x0ED680,-0x0F7300,-0x100880,-0x109580,-0x111980,-0x119300,-0x120180,-0x126400,-0x12B880,
+    -0x12FF80,-0x133700,-0x135E00,-0x137380,-0x137700,-0x136780,-0x134380,-0x130B00,-0x12BC00,-0x125680,-0x11D980,-0x114400,-0x109600,-0x0FCE00,-0x0EEC00,-0x0DEF00,
+     0x0CD700, 0x0BA380, 0x0A5400, 0x08E880, 0x076000, 0x05BB80, 0x03FA80, 0x021D00, 0x002300,-0x01F300,-0x042500,-0x067200,-0x08DA80,-0x0B5D00,-0x0DF900,-0x10AE00,
+    -0x137B80,-0x165F80,-0x195A00,-0x1C6A00,-0x1F8D80,-0x22C380,-0x260B00,-0x296280,-0x2CC880,-0x303B00,-0x33B900,-0x374080,-0x3AD000,-0x3E6580,-0x41FF80,-0x459C00,
+    -0x493880,-0x4CD400,-0x506C00,-0x53FF00,-0x578A80,-0x5B0C80,-0x5E8300,-0x61EC80,-0x654680,-0x688F00,-0x6BC500,-0x6EE500,-0x71EE80,-0x74DF00,-0x77B480,-0x7A6E00,
+    -0x7D0980,-0x7F8500,-0x81DF00,-0x841680,-0x862A00,-0x881780,-0x89DF00,-0x8B7E00,-0x8CF480,-0x8E4180,-0x8F6380,-0x905A00,-0x912480,-0x91C300,-0x923400,-0x927800,
+     0x928F00, 0x927800, 0x923400, 0x91C300, 0x912480, 0x905A00, 0x8F6380, 0x8E4180, 0x8CF480, 0x8B7E00, 0x89DF00, 0x881780, 0x862A00, 0x841680, 0x81DF00, 0x7F8500,
+     0x7D0980, 0x7A6E00, 0x77B480, 0x74DF00, 0x71EE80, 0x6EE500, 0x6BC500, 0x688F00, 0x654680, 0x61EC80, 0x5E8300, 0x5B0C80, 0x578A80, 0x53FF00, 0x506C00, 0x4CD400,
+     0x493880, 0x459C00, 0x41FF80, 0x3E6580, 0x3AD000, 0x374080, 0x33B900, 0x303B00, 0x2CC880, 0x296280, 0x260B00, 0x22C380, 0x1F8D80, 0x1C6A00, 0x195A00, 0x165F80,
+     0x137B80, 0x10AE00, 0x0DF900, 0x0B5D00, 0x08DA80, 0x067200, 0x042500, 0x01F300,-0x002300,-0x021D00,-0x03FA80,-0x05BB80,-0x076000,-0x08E880,-0x0A5400,-0x0BA380,
+     0x0CD700, 0x0DEF00, 0x0EEC00, 0x0FCE00, 0x109600, 0x114400, 0x11D980, 0x125680, 0x12BC00, 0x130B00, 0x134380, 0x136780, 0x137700, 0x137380, 0x135E00, 0x133700,
+     0x12FF80, 0x12B880, 0x126400, 0x120180, 0x119300, 0x111980, 0x109580, 0x100880, 0x0F7300, 0x0ED680, 0x0E3380, 0x0D8B80, 0x0CDE80, 0x0C2E80, 0x0B7B80, 0x0AC680,
+     0x0A1080, 0x095A00, 0x08A480, 0x07EF80, 0x073C80, 0x068B80, 0x05DE00, 0x053380, 0x048D00, 0x03EB00, 0x034E00, 0x02B600, 0x022380, 0x019700, 0x011080, 0x009000,
+     0x001680,-0x005C80,-0x00C900,-0x012E80,-0x018D00,-0x01E500,-0x023580,-0x028000,-0x02C300,-0x02FF80,-0x033600,-0x036580,-0x038F00,-0x03B280,-0x03D000,-0x03E800,
+     0x03FA80, 0x040780, 0x041000, 0x041380, 0x041280, 0x040D80, 0x040480, 0x03F800, 0x03E880, 0x03D500, 0x03BF80, 0x03A700, 0x038C80, 0x036F80, 0x035100, 0x033100,
+     0x030F80, 0x02ED00, 0x02CA00, 0x02A600, 0x028180, 0x025D00, 0x023880, 0x021400, 0x01EF80, 0x01CB80, 0x01A800, 0x018580, 0x016380, 0x014280, 0x012280, 0x010380,
+     0x00E580, 0x00C880, 0x00AD80, 0x009300, 0x007A00, 0x006280, 0x004C80, 0x003780, 0x002400, 0x001200, 0x000100,-0x000E80,-0x001C80,-0x002980,-0x003500,-0x003F80,
+    -0x004900,-0x005180,-0x005880,-0x005E80,-0x006400,-0x006800,-0x006B80,-0x006E80,-0x007000,-0x007180,-0x007200,-0x007200,-0x007180,-0x007080,-0x006F00,-0x006D00,
+     0x006A80, 0x006800, 0x006500, 0x006200, 0x005F00, 0x005B80, 0x005800, 0x005480, 0x005080, 0x004D00, 0x004980, 0x004580, 0x004200, 0x003E80, 0x003A80, 0x003780,
+     0x003400, 0x003080, 0x002D80, 0x002A80, 0x002780, 0x002480, 0x002200, 0x001F80, 0x001D00, 0x001A80, 0x001880, 0x001680, 0x001480, 0x001300, 0x001180, 0x000F80,
+     0x000E80, 0x000D00, 0x000C00, 0x000A80, 0x000980, 0x000880, 0x000800, 0x000700, 0x000680, 0x000580, 0x000500, 0x000480, 0x000400, 0x000380, 0x000380, 0x000300,
+     0x000280, 0x000280, 0x000200, 0x000200, 0x000180, 0x000180, 0x000100, 0x000100, 0x000100, 0x000100, 0x000080, 0x000080, 0x000080, 0x000080, 0x000080, 0x000080,
+];
+
+
+const SF_TABLE: [i64; 63] = [
+    0x20000000,
+    0x1965fea5,
+    0x1428a2fa,
+    0x10000000,
+    0x0cb2ff53,
+    0x0a14517d,
+    0x08000000,
+    0x06597fa9,
+    0x050a28be,
+    0x04000000,
+    0x032cbfd5,
+    0x0285145f,
+    0x02000000,
+    0x01965fea,
+    0x01428a30,
+    0x01000000,
+    0x00cb2ff5,
+    0x00a14518,
+    0x00800000,
+    0x006597fb,
+    0x0050a28c,
+    0x00400000,
+    0x0032cbfd,
+    0x00285146,
+    0x00200000,
+    0x001965ff,
+    0x001428a3,
+    0x00100000,
+    0x000cb2ff,
+    0x000a1451,
+    0x00080000,
+    0x00065980,
+    0x00050a29,
+    0x00040000,
+    0x00032cc0,
+    0x00028514,
+    0x00020000,
+    0x00019660,
+    0x0001428a,
+    0x00010000,
+    0x0000cb30,
+    0x0000a145,
+    0x00008000,
+    0x00006598,
+    0x000050a3,
+    0x00004000,
+    0x000032cc,
+    0x00002851,
+    0x00002000,
+    0x00001966,
+    0x00001429,
+    0x00001000,
+    0x00000cb3,
+    0x00000a14,
+    0x00000800,
+    0x00000659,
+    0x0000050a,
+    0x00000400,
+    0x0000032d,
+    0x00000285,
+    0x00000200,
+    0x00000196,
+    0x00000143,
+];
+
+// Inverse scalefactor table
+const ISF_TABLE: [i64; 63] = [
+    0x00000008000000,
+    0x0000000A14517C,
+    0x0000000CB2FF52,
+    0x00000010000000,
+    0x0000001428A2F8,
+    0x0000001965FEA4,
+    0x00000020000000,
+    0x000000285145F5,
+    0x00000032CBFD4E,
+    0x00000040000000,
+    0x00000050A28BDD,
+    0x0000006597FA9C,
+    0x00000080000000,
+    0x000000A14517ED,
+    0x000000CB2FF4E8,
+    0x00000100000000,
+    0x000001428A2FDB,
+    0x000001965FE9D1,
+    0x00000200000000,
+    0x00000285145C8A,
+    0x0000032CBFD3A3,
+    0x00000400000000,
+    0x0000050A28C5C7,
+    0x000006597FA747,
+    0x00000800000000,
+    0x00000A145158C2,
+    0x00000CB2FF4E8E,
+    0x00001000000000,
+    0x00001428A37CB4,
+    0x00001965FFDFA8,
+    0x00002000000000,
+    0x0000285143CCA8,
+    0x000032CBFAB527,
+    0x00004000000000,
+    0x000050A2879951,
+    0x000065980992F3,
+    0x00008000000000,
+    0x0000A1450F32A2,
+    0x0000CB301325E7,
+    0x00010000000000,
+    0x0001428A1E6544,
+    0x00019660264BCF,
+    0x00020000000000,
+    0x000285143CCA88,
+    0x00032CBB427564,
+    0x00040000000000,
+    0x00050A28799510,
+    0x0006598AAD93B4,
+    0x00080000000000,
+    0x000A1450F32A20,
+    0x000CB2C4B983B2,
+    0x00100000000000,
+    0x001428A1E65441,
+    0x001966CC01966C,
+    0x00200000000000,
+    0x00285470CC2B7B,
+    0x0032CD98032CD9,
+    0x00400000000000,
+    0x00509C2E9A4AF1,
+    0x00659B300659B3,
+    0x00800000000000,
+    0x00A16B312EA8FC,
+    0x00CAE5D85F1BBD,
+];
+
+struct Window {
+    window: [i16; 512],
+    window_idx: usize,
+}
+
+impl Window {
+    fn new() -> Window {
+        Window {
+            window: [0; 512],
+            window_idx: 0,
+        }
+    }
+
+    fn add_samples(&mut self, samples: &[i16]) {
+        self.window[self.window_idx..self.window_idx + 32].copy_from_slice(&samples[..32]);
+
+        self.window_idx += 32;
+        self.window_idx %= 512;
+    }
+
+    fn polyphase(&self) -> [i64; 32] {
+        // Precompute Y since it doesn't rely on subband
+        let mut y = [0; 64];
+        for i in 0..64 {
+            for j in 0..8 {
+                // Window the sample
+                // (15b * 28b) >> 15 = 28b
+                y[i] += (self[i + 64 * j] as i64 * ENWINDOW[i + 64 * j]) >> 15;
+            }
+        }
+
+        // The filterbank is a 64 point cosine transform of y at
+        // cos((16 + i)(2sb + 1)π / 64). With n = 16 + i those cosines are
+        // even around n == 0, odd around n == 32 and flip sign every 64, so
+        // y folds down to a 32 point DCT-III.
+        let mut folded = [0.0; 32];
+        folded[0] = -y[48] as f64;
+        for m in 1..16 {
+            folded[m] = (-y[48 - m] - y[48 + m]) as f64;
+        }
+        for m in 16..32 {
+            folded[m] = (y[m - 16] - y[48 - m]) as f64;
+        }
+
+        let mut transformed = [0.0; 32];
+        dct32(&folded, &mut transformed);
+
+        let mut polyphased = [0; 32];
+        for (out, &value) in polyphased.iter_mut().zip(transformed.iter()) {
+            *out = value.round() as i64;
+        }
+        polyphased
+    }
+}
+
+/// Unscaled DCT-III, `output[k] = Σ input[m] cos((2k + 1)mπ / 2n)`, of up to
+/// 32 values.
+///
+/// Splits into two half size transforms, one of the even inputs and one of
+/// the odd inputs summed pairwise, and combines them with a butterfly.
+fn dct32(input: &[f64], output: &mut [f64]) {
+    let n = input.len();
+    if n == 1 {
+        output[0] = input[0];
+        return;
+    }
+
+    let half = n / 2;
+    let mut even = [0.0; 16];
+    let mut odd = [0.0; 16];
+    even[0] = input[0];
+    odd[0] = input[1];
+    for j in 1..half {
+        even[j] = input[2 * j];
+        odd[j] = input[2 * j + 1] + input[2 * j - 1];
+    }
+
+    let mut even_out = [0.0; 16];
+    let mut odd_out = [0.0; 16];
+    dct32(&even[..half], &mut even_out[..half]);
+    dct32(&odd[..half], &mut odd_out[..half]);
+
+    for k in 0..half {
+        let odd_part = odd_out[k] * SECANTS[half - 1 + k];
+        output[k] = even_out[k] + odd_part;
+        output[n - 1 - k] = even_out[k] - odd_part;
+    }
+}
+
+impl Index<usize> for Window {
+    type Output = i16;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.window[(self.window_idx + index) % 512]
+    }
+}
+
+struct BitWriter<W> {
+    inner: W,
+    byte: u8,
+    bit: u32,
+    /// Bytes written to `inner` so far.
+    written: u64,
+}
+
+impl<W> BitWriter<W>
+    where W: Write
+{
+    fn new(inner: W) -> BitWriter<W> {
+        BitWriter {
+            inner: inner,
+            byte: 0,
+            bit: 0,
+            written: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        if self.bit != 0 {
+            self.bit = 8;
+        }
+    }
+
+    fn write_bit(&mut self, bit: u32) -> RadxResult<()> {
+        if self.bit == 8 {
+            let buf = [self.byte];
+            self.inner.write_all(&buf)?;
+            self.written += 1;
+            self.byte = 0;
+            self.bit = 0;
+        }
+        self.byte |= (bit as u8) << (7 - self.bit);
+        self.bit += 1;
+
+        Ok(())
+    }
+
+    fn write(&mut self, num: u32, mut bits: u32) -> RadxResult<()> {
+        while bits > 0 {
+            bits -= 1;
+            self.write_bit((num >> bits) & 1)?;
+        }
+
+        Ok(())
+    }
+
+    /// Bits written so far, counting the partial byte.
+    fn bits(&self) -> u64 {
+        self.written * 8 + self.bit as u64
+    }
+
+    /// Writes out the last partial byte, returning the writer and how many
+    /// bytes went to it.
+    fn inner(mut self) -> RadxResult<(W, u64)> {
+        if self.bit != 0 {
+            let buf = [self.byte];
+            self.inner.write_all(&buf)?;
+            self.written += 1;
+        }
+        Ok((self.inner, self.written))
+    }
+}
+
+/// Most bits an AHX frame takes, header included. The usual AHX allocation
+/// comes to this with every scalefactor written out, so frames never outgrow
+/// what game decoders expect.
+pub(crate) const AHX_FRAME_BITS: u32 = 3515;
+
+/// What kind of Layer II stream an `Mp2Encoder` writes.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct Mp2Profile {
+    channels: u32,
+    sample_rate: u32,
+    /// Bitrate in kbps, as written in every frame header.
+    bitrate: u32,
+    /// Most bits a frame can take when frames are packed end to end, rather
+    /// than each filling out the space its bitrate gives it.
+    packed_bits: Option<u32>,
+}
+
+impl Mp2Profile {
+    /// A standard mp2 stream with 1 or 2 channels at `bitrate` kbps.
+    ///
+    /// 32000, 44100 and 48000 Hz make MPEG-1 streams, and 16000, 22050 and
+    /// 24000 Hz make MPEG-2 ones. The bitrate has to be in that version's
+    /// table, and MPEG-1 doesn't allow stereo below 64 kbps (or at 80), or
+    /// mono above 192.
+    pub fn new(channels: u32, sample_rate: u32, bitrate: u32) -> RadxResult<Mp2Profile> {
+        if channels == 0 || channels > 2 {
+            return Err(RadxError::BadEncoderOptions("mp2 needs 1 or 2 channels"));
+        }
+        let mpeg1 = match sample_rate {
+            32000 | 44100 | 48000 => true,
+            16000 | 22050 | 24000 => false,
+            _ => return Err(RadxError::BadEncoderOptions("mp2 sample rate has to be 16000, 22050, 24000, 32000, 44100 or 48000")),
+        };
+        let bitrates = if mpeg1 { &BITRATE_TABLE_MPEG1 } else { &BITRATE_TABLE_LSF };
+        if bitrate == 0 || !bitrates.contains(&bitrate) {
+            return Err(RadxError::BadEncoderOptions("mp2 doesn't have that bitrate at that sample rate"));
+        }
+        if mpeg1 {
+            let allowed = if channels == 1 { bitrate <= 192 } else { bitrate >= 64 && bitrate != 80 };
+            if !allowed {
+                return Err(RadxError::BadEncoderOptions("mpeg-1 layer ii doesn't allow that bitrate with that many channels"));
+            }
+        }
+
+        Ok(Mp2Profile {
+            channels: channels,
+            sample_rate: sample_rate,
+            bitrate: bitrate,
+            packed_bits: None,
+        })
+    }
+
+    /// The AHX preset: mono MPEG-2 at 22050 Hz. Headers say 160 kbps, but
+    /// frames are packed tight and take no more than 3515 bits, about
+    /// 67 kbps.
+    pub fn ahx() -> Mp2Profile {
+        Mp2Profile {
+            channels: 1,
+            sample_rate: 22050,
+            bitrate: 160,
+            packed_bits: Some(AHX_FRAME_BITS),
+        }
+    }
+
+    /// A bitrate for `channels` channels at `sample_rate` that's good enough
+    /// for previews: 96 kbps a channel at MPEG-1 rates, 64 at MPEG-2 ones.
+    pub fn default_bitrate(channels: u32, sample_rate: u32) -> u32 {
+        if sample_rate >= 32000 {
+            96 * channels
+        }
+        else {
+            64 * channels
+        }
+    }
+
+    pub fn channels(&self) -> u32 {
+        self.channels
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Bitrate in kbps.
+    pub fn bitrate(&self) -> u32 {
+        self.bitrate
+    }
+
+    fn header(&self, padding: bool) -> Mp2FrameHeader {
+        Mp2FrameHeader {
+            version: if self.sample_rate >= 32000 { MpegVersion::Mpeg1 } else { MpegVersion::Mpeg2 },
+            protected: false,
+            bitrate: self.bitrate,
+            sample_rate: self.sample_rate,
+            padding: padding,
+            mode: if self.channels == 1 { ChannelMode::Mono } else { ChannelMode::Stereo },
+            mode_extension: 0,
+        }
+    }
+}
+
+/// One channel of a frame, split into subbands.
+struct Subbands {
+    /// 3 parts with 4 granules with 32 subbands with 3 samples
+    samples: [[[[i64; 3]; 32]; 4]; 3],
+    /// 3 parts with 32 subbands
+    scalefactors: [[usize; 32]; 3],
+    /// 1 scfsi per subband
+    scfsi: [u32; 32],
+}
+
+impl Subbands {
+    /// Runs a frame of samples through the filterbank and picks
+    /// scalefactors for the first `sblimit` subbands.
+    fn analyze(window: &mut Window, pcm: &[i16; 1152], sblimit: usize) -> Subbands {
+        let mut samples = [[[[0; 3]; 32]; 4]; 3];
+        let mut scalefactors = [[0; 32]; 3];
+
+        let mut sample_idx = 0;
+        for part in 0..3 {
+            // Read in samples
+            for granule in samples[part].iter_mut() {
+                for s in 0..3 {
+                    window.add_samples(&pcm[sample_idx..sample_idx + 32]);
+                    let polyphased = window.polyphase();
+                    sample_idx += 32;
+
+                    for (subband, &sample) in granule.iter_mut().zip(polyphased.iter()) {
+                        subband[s] = sample;
+                    }
+                }
+            }
+
+            // Analyze samples for scalefactors
+            for sb in 0..sblimit {
+                let max_sample = samples[part].iter()
+                    .flat_map(|granule| granule[sb].iter())
+                    .map(|sample| sample.abs())
+                    .max()
+                    .unwrap_or(0);
+
+                // Find best scalefactor
+                let mut sf_index = 0;
+                for i in 0..63 {
+                    sf_index = 62 - i;
+                    if max_sample < SF_TABLE[sf_index] {
+                        break;
+                    }
+                }
+
+                scalefactors[part][sb] = sf_index;
+            }
+        }
+
+        // Analyze scfsi info
+        let mut scfsi = [0; 32];
+        for sb in 0..sblimit {
+            if scalefactors[0][sb] == scalefactors[1][sb] {
+                if scalefactors[1][sb] == scalefactors[2][sb] {
+                    // All scalefactors the same
+                    scfsi[sb] = 2;
+                }
+                else {
+                    // First two same, last different
+                    scfsi[sb] = 1;
+                }
+            }
+            else {
+                if scalefactors[1][sb] == scalefactors[2][sb] {
+                    // Last two same, first different
+                    scfsi[sb] = 3;
+                }
+                else {
+                    // None same
+                    scfsi[sb] = 0;
+                }
+            }
+        }
+
+        Subbands {
+            samples: samples,
+            scalefactors: scalefactors,
+            scfsi: scfsi,
+        }
+    }
+
+    /// Level of subband `sb` in dB relative to full scale.
+    fn level(&self, sb: usize) -> f64 {
+        let mut energy = 0.0;
+        for part in 0..3 {
+            for gr in 0..4 {
+                for s in 0..3 {
+                    let sample = self.samples[part][gr][sb][s] as f64 / (1 << 28) as f64;
+                    energy += sample * sample;
+                }
+            }
+        }
+        10.0 * (energy / 36.0).log10()
+    }
+
+    /// Bits the scfsi and scalefactors of subband `sb` take.
+    fn scalefactor_bits(&self, sb: usize) -> u32 {
+        2 + 6 * match self.scfsi[sb] {
+            0 => 3,
+            2 => 1,
+            _ => 2,
+        }
+    }
+}
+
+/// Synthesizes frames the way a decoder will, comparing them with the input
+/// for `EncoderStats`.
+struct Analysis {
+    synthesizers: Vec<Synthesizer>,
+    /// Input whose decoded samples haven't come out yet, per channel.
+    pending: Vec<VecDeque<i16>>,
+    /// Decoded samples still to drop for the filterbank delay.
+    delay: usize,
+    signal: Vec<f64>,
+    noise: Vec<f64>,
+}
+
+impl Analysis {
+    fn new(channels: usize) -> Analysis {
+        Analysis {
+            synthesizers: vec![Synthesizer::new(); channels],
+            pending: vec![VecDeque::new(); channels],
+            delay: AHX_DELAY,
+            signal: vec![0.0; channels],
+            noise: vec![0.0; channels],
+        }
+    }
+
+    fn push_input(&mut self, sample: &[i16]) {
+        for (pending, &value) in self.pending.iter_mut().zip(sample.iter()) {
+            pending.push_back(value);
+        }
+    }
+
+    /// Takes the subband samples of a frame's 12 granules for each channel,
+    /// as a decoder reads them back.
+    fn analyze_frame(&mut self, decoded: &[[[[i64; 3]; 32]; 12]]) {
+        let skip = cmp::min(self.delay, 1152);
+        self.delay -= skip;
+
+        for (ch, subbands) in decoded.iter().enumerate() {
+            let mut pcm = [0; 1152];
+            let mut pcm_idx = 0;
+            for granule in subbands.iter() {
+                for s in 0..3 {
+                    let mut slot = [0; 32];
+                    for (sample, samples) in slot.iter_mut().zip(granule.iter()) {
+                        *sample = samples[s];
+                    }
+                    self.synthesizers[ch].synthesize(&slot, &mut pcm[pcm_idx..pcm_idx + 32]);
+                    pcm_idx += 32;
+                }
+            }
+
+            // Past the end of the input is only padding
+            for &sample in pcm[skip..].iter() {
+                let target = match self.pending[ch].pop_front() {
+                    Some(target) => target as f64,
+                    None => break,
+                };
+                self.signal[ch] += target * target;
+                self.noise[ch] += (sample as f64 - target).powi(2);
+            }
+        }
+    }
+
+    fn snr(&self) -> Vec<f64> {
+        self.signal.iter()
+            .zip(self.noise.iter())
+            .map(|(&signal, &noise)| snr(signal, noise))
+            .collect()
+    }
+}
+
+/// Encodes standard mp2 streams, or raw AHX frames with
+/// `Mp2Profile::ahx`.
+///
+/// Output starts with the filterbank delay, `AHX_DELAY` samples of near
+/// silence, same as any other Layer II encoder. The end gets flushed so no
+/// input goes missing.
+pub struct Mp2Encoder<W> {
+    inner: BitWriter<W>,
+    profile: Mp2Profile,
+    windows: Vec<Window>,
+    samples_encoded: u32,
+    buffer: Vec<[i16; 1152]>,
+    buffer_idx: usize,
+    /// Left over from rounding frame lengths down to whole bytes. Once it
+    /// adds up to a byte, the next frame gets padded.
+    padding_rest: u32,
+    /// How often each scalefactor index was used.
+    scalefactor_histogram: [u64; 63],
+    analysis: Analysis,
+    progress: Progress,
+}
+
+impl<W> Mp2Encoder<W>
+    where W: Write
+{
+    pub fn new(inner: W, profile: Mp2Profile) -> Mp2Encoder<W> {
+        let channels = profile.channels as usize;
+        let mut windows = Vec::with_capacity(channels);
+        for _ in 0..channels {
+            windows.push(Window::new());
+        }
+
+        Mp2Encoder {
+            inner: BitWriter::new(inner),
+            profile: profile,
+            windows: windows,
+            samples_encoded: 0,
+            buffer: vec![[0; 1152]; channels],
+            buffer_idx: 0,
+            padding_rest: 0,
+            scalefactor_histogram: [0; 63],
+            analysis: Analysis::new(channels),
+            progress: Progress::new(),
+        }
+    }
+
+    pub fn profile(&self) -> Mp2Profile {
+        self.profile
+    }
+
+    /// Samples taken in so far, for each channel.
+    pub fn samples_encoded(&self) -> u32 {
+        self.samples_encoded
+    }
+
+    /// Reports progress and checks for cancellation after every frame, the
+    /// same way as `StandardEncoder::set_progress`.
+    pub fn set_progress(&mut self, progress: Progress) {
+        self.progress = progress;
+    }
+
+    /// Encodes one sample, with a value for every channel.
+    ///
+    /// # Panics
+    ///
+    /// If `sample` has fewer values than the profile has channels.
+    pub fn encode_sample(&mut self, sample: &[i16]) -> RadxResult<()> {
+        let sample = &sample[..self.profile.channels as usize];
+        for (channel, &value) in self.buffer.iter_mut().zip(sample.iter()) {
+            channel[self.buffer_idx] = value;
+        }
+        self.buffer_idx += 1;
+        self.analysis.push_input(sample);
+
+        self.samples_encoded += 1;
+
+        if self.buffer_idx == 1152 {
+            self.encode_frame()?;
+            self.buffer_idx = 0;
+            self.progress.check(self.samples_encoded as u64)?;
+        }
+
+        Ok(())
+    }
+
+    fn next_padding(&mut self) -> bool {
+        if self.profile.packed_bits.is_some() {
+            return false;
+        }
+
+        self.padding_rest += 144 * 1000 * self.profile.bitrate % self.profile.sample_rate;
+        if self.padding_rest >= self.profile.sample_rate {
+            self.padding_rest -= self.profile.sample_rate;
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    fn encode_frame(&mut self) -> RadxResult<()> {
+        self.inner.reset();
+
+        let padding = self.next_padding();
+        let header = self.profile.header(padding);
+        let layout = header.layout();
+        let table = layout.table;
+        let sblimit = table.len();
+        let channels = layout.channels;
+
+        let subbands: Vec<Subbands> = self.windows.iter_mut()
+            .zip(self.buffer.iter())
+            .map(|(window, pcm)| Subbands::analyze(window, pcm, sblimit))
+            .collect();
+
+        // Decide where the bits go, from how loud each subband is against
+        // how much of it is masked. Both channels share one pool of bits,
+        // so allocate them as if they were one long list of subbands.
+        let mut tables = Vec::with_capacity(sblimit * channels);
+        let mut smr = Vec::with_capacity(sblimit * channels);
+        let mut scalefactor_bits = Vec::with_capacity(sblimit * channels);
+        for channel in subbands.iter() {
+            let levels: Vec<f64> = (0..sblimit).map(|sb| channel.level(sb)).collect();
+            tables.extend_from_slice(table);
+            smr.extend(layer2::signal_to_mask(&levels, self.profile.sample_rate));
+            scalefactor_bits.extend((0..sblimit).map(|sb| channel.scalefactor_bits(sb)));
+        }
+        let frame_bits = match self.profile.packed_bits {
+            Some(bits) => bits,
+            None => header.frame_len() as u32 * 8,
+        };
+        let budget = frame_bits - 32 - layer2::allocation_bits(&tables);
+        let allocation = layer2::allocate(&tables, &smr, &scalefactor_bits, budget);
+        let alloc = |ch: usize, sb: usize| allocation[ch * sblimit + sb];
+
+        // Write frame header
+        let frame_start = self.inner.bits();
+        self.inner.write(header.to_u32(), 32)?;
+
+        // Write bit allocations
+        for (sb, classes) in table.iter().enumerate() {
+            for ch in 0..channels {
+                self.inner.write(alloc(ch, sb) as u32, alloc_bits(classes))?;
+            }
+        }
+
+        // Write scfsi information
+        for sb in 0..sblimit {
+            for (ch, channel) in subbands.iter().enumerate() {
+                if alloc(ch, sb) != 0 {
+                    self.inner.write(channel.scfsi[sb], 2)?;
+                }
+            }
+        }
+
+        // Write scalefactor information
+        for sb in 0..sblimit {
+            for (ch, channel) in subbands.iter().enumerate() {
+                if alloc(ch, sb) == 0 {
+                    continue;
+                }
+                let scalefactors = &channel.scalefactors;
+                for part in scalefactors.iter() {
+                    self.scalefactor_histogram[part[sb]] += 1;
+                }
+
+                match channel.scfsi[sb] {
+                    0 => {
+                        // None the same, write all three scalefactors
+                        self.inner.write(scalefactors[0][sb] as u32, 6)?;
+                        self.inner.write(scalefactors[1][sb] as u32, 6)?;
+                        self.inner.write(scalefactors[2][sb] as u32, 6)?;
+                    }
+                    1 => {
+                        // First two same, last one different, write first and last
+                        self.inner.write(scalefactors[0][sb] as u32, 6)?;
+                        self.inner.write(scalefactors[2][sb] as u32, 6)?;
+                    }
+                    2 => {
+                        // All scalefactors the same, write the first
+                        self.inner.write(scalefactors[0][sb] as u32, 6)?;
+                    }
+                    3 => {
+                        // Last two the same, first one different, write first and last
+                        self.inner.write(scalefactors[0][sb] as u32, 6)?;
+                        self.inner.write(scalefactors[2][sb] as u32, 6)?;
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+
+        // Write sample data, keeping what a decoder will read back
+        let mut decoded = vec![[[[0; 3]; 32]; 12]; channels];
+        for part in 0..3 {
+            for gr in 0..4 {
+                for sb in 0..sblimit {
+                    for (ch, channel) in subbands.iter().enumerate() {
+                        if alloc(ch, sb) == 0 {
+                            continue;
+                        }
+
+                        let mut scaled = [0; 3];
+                        for (scaled, &sample) in scaled.iter_mut().zip(channel.samples[part][gr][sb].iter()) {
+                            *scaled = (sample * ISF_TABLE[channel.scalefactors[part][sb]]) >> 28;
+                        }
+                        let codes = layer2::quantize(scaled, table[sb], alloc(ch, sb));
+                        for &code in codes.codes[..codes.count].iter() {
+                            self.inner.write(code, codes.bits)?;
+                        }
+
+                        let scale = SF_TABLE[channel.scalefactors[part][sb]];
+                        let fractions = layer2::dequantize(&codes, table[sb], alloc(ch, sb));
+                        for (sample, &fraction) in decoded[ch][part * 4 + gr][sb].iter_mut().zip(fractions.iter()) {
+                            *sample = (fraction * scale) >> 28;
+                        }
+                    }
+                }
+            }
+        }
+
+        // Standard frames fill out the rest of their length
+        if self.profile.packed_bits.is_none() {
+            while self.inner.bits() < frame_start + frame_bits as u64 {
+                self.inner.write_bit(0)?;
+            }
+        }
+
+        self.analysis.analyze_frame(&decoded);
+        Ok(())
+    }
+
+    /// Flushes the filterbank delay and the last frame, returning the writer
+    /// and how an encode went. `bytes` only counts frames.
+    pub(crate) fn flush(mut self) -> RadxResult<(W, EncoderStats)> {
+        // Flush the filterbank delay so a decoder can trim it off the front
+        // and still get every input sample back.
+        let mut padding = AHX_DELAY;
+        while padding != 0 || self.buffer_idx != 0 {
+            for channel in self.buffer.iter_mut() {
+                channel[self.buffer_idx] = 0;
+            }
+            self.buffer_idx += 1;
+            padding = padding.saturating_sub(1);
+
+            if self.buffer_idx == 1152 {
+                self.encode_frame()?;
+                self.buffer_idx = 0;
+            }
+        }
+
+        let (inner, data_bytes) = self.inner.inner()?;
+        self.progress.report(self.samples_encoded as u64);
+        let stats = EncoderStats {
+            samples: self.samples_encoded,
+            bytes: data_bytes,
+            scale_histogram: self.scalefactor_histogram.to_vec(),
+            clipped_nibbles: 0,
+            predictor_clamps: 0,
+            snr: self.analysis.snr(),
+        };
+        Ok((inner, stats))
+    }
+
+    /// Writes out the rest of the stream, returning stats on how it was
+    /// encoded. The ADX nibble and predictor counts stay 0.
+    pub fn finalize(self) -> RadxResult<EncoderStats> {
+        let (_, stats) = self.flush()?;
+        Ok(stats)
+    }
+}
+
+impl<W> Encoder for Mp2Encoder<W>
+    where W: Write
+{
+    fn encode_samples(&mut self, samples: &[Sample]) -> RadxResult<()> {
+        for sample in samples {
+            self.encode_sample(sample)?;
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> RadxResult<EncoderStats> {
+        (*self).finalize()
+    }
+
+    fn set_progress(&mut self, progress: Progress) {
+        Mp2Encoder::set_progress(self, progress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64;
+    use std::io::Cursor;
+
+    use super::{Mp2Encoder, Mp2Profile, Window, ENWINDOW};
+    use decoder::{Decoder, Mp2Decoder};
+    use decoder::layer2::N;
+    use encoder::measure_snr;
+    use error::RadxError;
+    use noise::Noise;
+    use AHX_DELAY;
+
+    /// The analysis filterbank straight from the definition, a matrix
+    /// multiply with every product truncated.
+    fn reference_polyphase(window: &Window) -> [i64; 32] {
+        let mut y = [0; 64];
+        for i in 0..64 {
+            for j in 0..8 {
+                y[i] += (window[i + 64 * j] as i64 * ENWINDOW[i + 64 * j]) >> 15;
+            }
+        }
+
+        let mut polyphased = [0; 32];
+        for sb in 0..32 {
+            for i in 0..64 {
+                polyphased[sb] += (N[sb][i] * y[i]) >> 28;
+            }
+        }
+        polyphased
+    }
+
+    #[test]
+    fn polyphase_matches_reference() {
+        let mut window = Window::new();
+        let mut noise = Noise::new();
+        let mut worst = 0;
+        for slot in 0..400 {
+            let mut samples = [0; 32];
+            for (sample, seed) in samples.iter_mut().zip(&mut noise) {
+                // Quiet noise, loud noise and full scale square waves
+                *sample = match slot / 100 {
+                    0 => (seed as i16) >> 8,
+                    1 | 2 => seed as i16,
+                    _ => if seed & 1 == 0 { i16::MAX } else { i16::MIN },
+                };
+            }
+            window.add_samples(&samples);
+
+            let fast = window.polyphase();
+            let reference = reference_polyphase(&window);
+            for (&a, &b) in fast.iter().zip(reference.iter()) {
+                worst = worst.max((a - b).abs());
+            }
+        }
+
+        // The reference's single precision cosines are off by several hundred
+        // on loud input, where the transform rounds exactly. Either way it's
+        // well under a 16-bit sample step, 1 << 13 at this scale.
+        assert!(worst < 1024, "worst difference {}", worst);
+    }
+
+    #[test]
+    fn standard_streams() {
+        let profiles = [(1, 22050, 64), (1, 32000, 48), (2, 44100, 128), (2, 48000, 192), (2, 16000, 32)];
+        for &(channels, sample_rate, bitrate) in profiles.iter() {
+            let profile = Mp2Profile::new(channels, sample_rate, bitrate).unwrap();
+            let input: Vec<Vec<i16>> = (0..sample_rate as usize / 2).map(|i| {
+                let t = i as f64 / sample_rate as f64;
+                let left = ((t * 2.0 * f64::consts::PI * 440.0).sin() * 8000.0) as i16;
+                let right = ((t * 2.0 * f64::consts::PI * 1250.0).sin() * 5000.0) as i16;
+                vec![left, right]
+            }).collect();
+
+            let mut encoder = Mp2Encoder::new(Vec::new(), profile);
+            for sample in input.iter() {
+                encoder.encode_sample(sample).unwrap();
+            }
+            let (mp2, stats) = encoder.flush().unwrap();
+            assert_eq!(stats.bytes, mp2.len() as u64);
+
+            // Every frame has to be the length its header says for the
+            // decoder to find the next one. Walk them with the tables from
+            // the standard rather than with our own decoder.
+            let frames = (input.len() + AHX_DELAY).div_ceil(1152);
+            let (version, bitrates, sample_rates) = if sample_rate >= 32000 {
+                (3, [0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384], [44100, 48000, 32000])
+            }
+            else {
+                (2, [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160], [22050, 24000, 16000])
+            };
+            let mut pos = 0;
+            let mut count = 0;
+            while pos < mp2.len() {
+                let header = (mp2[pos] as u32) << 24 | (mp2[pos + 1] as u32) << 16 | (mp2[pos + 2] as u32) << 8 | mp2[pos + 3] as u32;
+                assert_eq!(header >> 21, 0x7ff, "no sync at {}", pos);
+                assert_eq!((header >> 19) & 3, version);
+                assert_eq!((header >> 17) & 3, 2, "not layer II");
+                assert_eq!(bitrates[((header >> 12) & 0xf) as usize], bitrate);
+                assert_eq!(sample_rates[((header >> 10) & 3) as usize], sample_rate);
+                assert_eq!((header >> 6) & 3 == 3, channels == 1, "mode");
+                let padding = ((header >> 9) & 1) as usize;
+                pos += 144000 * bitrate as usize / sample_rate as usize + padding;
+                count += 1;
+            }
+            assert_eq!((pos, count), (mp2.len(), frames));
+            // Padding keeps the average at the bitrate
+            let exact = frames as f64 * 144000.0 * bitrate as f64 / sample_rate as f64;
+            assert!((mp2.len() as f64 - exact).abs() < 1.0, "{} bytes, {} exactly", mp2.len(), exact);
+
+            let mut decoder = Mp2Decoder::new(Cursor::new(mp2)).unwrap();
+            assert_eq!((decoder.channels(), decoder.sample_rate()), (channels, sample_rate));
+            let mut output = Vec::new();
+            while let Some(sample) = decoder.next_sample() {
+                output.push(sample);
+            }
+            assert_eq!(output.len(), frames * 1152);
+
+            for ch in 0..channels as usize {
+                let snr = measure_snr(&input, &output[AHX_DELAY..], ch);
+                assert!(snr > 25.0, "{} Hz, {} kbps, channel {}: snr {}", sample_rate, bitrate, ch, snr);
+                assert!((stats.snr[ch] - snr).abs() < 1e-9, "{} {}", stats.snr[ch], snr);
+            }
+        }
+    }
+
+    #[test]
+    fn profiles() {
+        assert!(Mp2Profile::new(2, 44100, 192).is_ok());
+        assert!(Mp2Profile::new(1, 24000, 8).is_ok());
+        for &(channels, sample_rate, bitrate) in &[(3, 44100, 192), (2, 11025, 64), (2, 44100, 144), (2, 32000, 48), (1, 48000, 256)] {
+            match Mp2Profile::new(channels, sample_rate, bitrate) {
+                Err(RadxError::BadEncoderOptions(_)) => {}
+                other => panic!("{} channels, {} Hz, {} kbps: {:?}", channels, sample_rate, bitrate, other),
+            }
+        }
+        assert_eq!(Mp2Profile::ahx().header(false).to_u32(), 0xfff5e0c0);
+    }
+}
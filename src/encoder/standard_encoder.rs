@@ -1,24 +1,230 @@
-use std::io::{Write, Seek, SeekFrom};
+use std::cmp;
+use std::collections::VecDeque;
+use std::f64::consts::FRAC_PI_2;
+use std::io::{self, Write, Seek, SeekFrom};
 use std::iter;
+use std::mem;
 use std::i16;
+use std::ops::Range;
+use std::panic;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
-use {Sample, AdxSpec, gen_coeffs};
+use {Sample, AdxSpec, DEFAULT_HIGHPASS_FREQUENCY, gen_coeffs};
 use adx_header::{AdxHeader, AdxEncoding, AdxVersion, AdxVersion3LoopInfo, ADX_HEADER_LEN};
 use adx_writer::AdxWriter;
-use error::RadxResult;
+use error::{RadxResult, RadxError};
+use progress::Progress;
+use super::{Encoder, EncoderStats, seek_writer, snr};
 
-const HIGHPASS_FREQ: u32 = 0x01F4;
+/// Cutoffs `best_highpass_frequency` tries, in Hz.
+const HIGHPASS_CANDIDATES: [u16; 16] = [
+    100, 150, 200, 250, 300, 400, 500, 650, 800, 1000, 1300, 1600, 2000, 2500, 3200, 4000
+];
 
-#[derive(Clone,Copy,Debug)]
+/// How hard the encoder works at choosing each block's scale.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Quality {
+    /// Take the scale straight from the block's prediction residual.
+    Fast,
+    /// Try a range of scales around that estimate, quantizing the block for
+    /// each the way the decoder will see it, and keep whichever comes out
+    /// closest to the input. Several times slower than `Fast`.
+    Best,
+    /// Like `Best`, then instead of rounding each nibble on its own, keep
+    /// several candidate nibble sequences going and pick the one with the
+    /// least total error, carrying them across block boundaries. Output lags
+    /// one block behind the input while the search decides.
+    Trellis,
+}
+
+/// Candidate scales for `Quality::Best`, as multiples of the estimate in
+/// eighths.
+const SCALE_SEARCH_STEPS: [i32; 12] = [6, 7, 8, 9, 10, 11, 12, 13, 14, 16, 20, 24];
+
+/// Number of paths `Quality::Trellis` keeps after each sample.
+const TRELLIS_WIDTH: usize = 8;
+
+/// Most times the loop is encoded over looking for a predictor state that
+/// comes back around to itself.
+const LOOP_PASSES: usize = 16;
+
+/// Samples over which the frame before the loop start fades into the end of
+/// the loop, for `set_match_loop_entry`.
+const TRANSITION_SAMPLES: usize = 30;
+
+/// Fewest frames a thread gets when a channel is split into segments.
+const SEGMENT_FRAMES: usize = 256;
+
+/// Frames encoded ahead of a segment to guess the state it starts in.
+const WARMUP_FRAMES: usize = 8;
+
+/// Longest filter `NoiseShaping` takes.
+pub const NOISE_SHAPING_ORDER: usize = 4;
+
+/// A filter the quantization noise is fed back through, moving it towards
+/// frequencies where it's harder to hear.
+///
+/// With coefficients `f`, noise that would have been flat comes out shaped by
+/// `1 - f[0] z^-1 - f[1] z^-2 - ...`. Only the encoder's choice of nibbles
+/// changes, so any decoder plays the result. Shaping makes the noise louder
+/// overall in exchange for less of it where the ear is most sensitive, and
+/// `Quality::Best` and `Quality::Trellis` minimize the noise as weighted by
+/// the filter rather than the plain error.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct NoiseShaping {
+    /// 12 bit fixed point, like the predictor coefficients.
+    coeffs: [i32; NOISE_SHAPING_ORDER],
+}
+
+impl NoiseShaping {
+    /// Makes a filter from its feedback coefficients.
+    ///
+    /// # Panics
+    ///
+    /// If there are more than `NOISE_SHAPING_ORDER` coefficients.
+    pub fn new(coeffs: &[f64]) -> NoiseShaping {
+        assert!(coeffs.len() <= NOISE_SHAPING_ORDER, "noise shaping filter is too long");
+        let mut fixed = [0; NOISE_SHAPING_ORDER];
+        for (fixed, &coeff) in fixed.iter_mut().zip(coeffs.iter()) {
+            *fixed = (coeff * 4096.0).round() as i32;
+        }
+        NoiseShaping {
+            coeffs: fixed,
+        }
+    }
+
+    /// Flat noise, as if there were no shaping. The default.
+    pub fn none() -> NoiseShaping {
+        NoiseShaping::new(&[])
+    }
+
+    /// A gentle tilt towards high frequencies: `1 - 0.5 z^-1`.
+    pub fn light() -> NoiseShaping {
+        NoiseShaping::new(&[0.5])
+    }
+
+    /// `(1 - 0.5 z^-1)^2`, about 12 dB less noise in the bass and 7 dB
+    /// more at the top of the band.
+    pub fn medium() -> NoiseShaping {
+        NoiseShaping::new(&[1.0, -0.25])
+    }
+
+    /// `(1 - 0.8 z^-1)^2`, for quiet, bass-heavy material where flat noise
+    /// is most obvious. About 28 dB less noise in the bass and 10 dB more at
+    /// the top of the band.
+    pub fn strong() -> NoiseShaping {
+        NoiseShaping::new(&[1.6, -0.64])
+    }
+
+    /// Looks up a preset by name.
+    pub fn preset(name: &str) -> Option<NoiseShaping> {
+        match name {
+            "none" => Some(NoiseShaping::none()),
+            "light" => Some(NoiseShaping::light()),
+            "medium" => Some(NoiseShaping::medium()),
+            "strong" => Some(NoiseShaping::strong()),
+            _ => None,
+        }
+    }
+
+    fn is_none(&self) -> bool {
+        self.coeffs.iter().all(|&coeff| coeff == 0)
+    }
+
+    /// Amount to take off the next input sample, given past errors newest
+    /// first.
+    fn feedback(&self, errors: &[i32; NOISE_SHAPING_ORDER]) -> i32 {
+        let sum: i32 = self.coeffs.iter()
+            .zip(errors.iter())
+            .map(|(&coeff, &error)| coeff * error)
+            .sum();
+        (sum + 2048) >> 12
+    }
+
+    /// Adds the error of the sample just quantized to `errors`.
+    fn push_error(&self, errors: &mut [i32; NOISE_SHAPING_ORDER], error: i32, scale: i32) {
+        if self.is_none() {
+            return;
+        }
+        // Clipped nibbles make big errors, and feeding those back just
+        // clips the next ones too. Keep it to what rounding can produce.
+        let error = cmp::min(cmp::max(error, -scale), scale);
+        for idx in (1..NOISE_SHAPING_ORDER).rev() {
+            errors[idx] = errors[idx - 1];
+        }
+        errors[0] = error;
+    }
+}
+
+/// Shape of a loop crossfade.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum CrossfadeCurve {
+    /// Gains change in straight lines. Best when both sides are nearly the
+    /// same audio.
+    Linear,
+    /// Keeps the total power steady, so unrelated audio doesn't dip in the
+    /// middle of the fade.
+    EqualPower,
+}
+
+impl CrossfadeCurve {
+    /// Looks up a curve by name, `linear` or `equal-power`.
+    pub fn from_name(name: &str) -> Option<CrossfadeCurve> {
+        match name {
+            "linear" => Some(CrossfadeCurve::Linear),
+            "equal-power" => Some(CrossfadeCurve::EqualPower),
+            _ => None,
+        }
+    }
+
+    /// Gains for the outgoing and incoming audio, `t` of the way through.
+    fn gains(&self, t: f64) -> (f64, f64) {
+        match *self {
+            CrossfadeCurve::Linear => (1.0 - t, t),
+            CrossfadeCurve::EqualPower => ((t * FRAC_PI_2).cos(), (t * FRAC_PI_2).sin()),
+        }
+    }
+}
+
+/// Blends the end of a loop into the audio just before its start, so going
+/// around sounds like carrying on from the start for the first time.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct Crossfade {
+    /// Length of the fade in samples. It is cut short if the loop, or the
+    /// audio before it, is shorter.
+    pub length: u32,
+    pub curve: CrossfadeCurve,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
 struct Prev<T> {
     first: T,
     second: T,
 }
 
+impl Prev<i16> {
+    /// The sample a decoder in this state produces from `nibble`.
+    fn decode(&self, nibble: i32, scale: i32, coeffs: (i32, i32)) -> i16 {
+        let prediction = coeffs.0 * self.first as i32 + coeffs.1 * self.second as i32;
+        let unclamped_sample = ((nibble << 12) * scale + prediction) >> 12;
+        cmp::min(cmp::max(unclamped_sample, i16::MIN as i32), i16::MAX as i32) as i16
+    }
+
+    fn push(&mut self, sample: i16) {
+        self.second = self.first;
+        self.first = sample;
+    }
+}
+
 #[derive(Clone,Copy,Debug)]
 struct Block {
     prev: Prev<i16>,
     orig_prev: Prev<i16>,
+    /// Recent errors for noise shaping, newest first.
+    errors: [i32; NOISE_SHAPING_ORDER],
+    orig_errors: [i32; NOISE_SHAPING_ORDER],
     min: i32,
     max: i32,
     samples: [i16; 32],
@@ -36,6 +242,8 @@ impl Block {
                 first: 0,
                 second: 0,
             },
+            errors: [0; NOISE_SHAPING_ORDER],
+            orig_errors: [0; NOISE_SHAPING_ORDER],
             min: 0,
             max: 0,
             samples: [0; 32],
@@ -47,6 +255,8 @@ impl Block {
         Block {
             prev: other.prev,
             orig_prev: other.prev,
+            errors: other.errors,
+            orig_errors: other.errors,
             min: 0,
             max: 0,
             samples: [0; 32],
@@ -78,16 +288,39 @@ impl Block {
         self.size == 32
     }
 
-    fn to_writer<W>(&mut self, mut writer: W, coeffs: (i32, i32)) -> RadxResult<()>
+    fn to_writer<W>(&mut self, mut writer: W, coeffs: (i32, i32), quality: Quality, shaping: NoiseShaping) -> RadxResult<()>
         where W: Write
     {
-        if self.min == 0 && self.max == 0 {
+        let scale = self.choose_scale(coeffs, quality, shaping);
+        if scale == 0 {
             for _ in 0..18 {
                 writer.write_u8(0)?;
             }
             return Ok(());
         }
 
+        self.prev = self.orig_prev;
+        self.errors = self.orig_errors;
+
+        writer.write_u16(scale as u16)?;
+        for byte_idx in 0..self.samples.len() / 2 {
+            let sample1 = self.samples[byte_idx * 2];
+            let sample2 = self.samples[byte_idx * 2 + 1];
+            let upper_nibble = self.get_nibble(sample1, scale, coeffs, shaping);
+            let lower_nibble = self.get_nibble(sample2, scale, coeffs, shaping);
+            let byte = (upper_nibble << 4) | (lower_nibble & 0xF);
+            writer.write_u8(byte)?;
+        }
+        Ok(())
+    }
+
+    /// Scale to encode the block with, or 0 if the predictor alone gets every
+    /// sample right.
+    fn choose_scale(&self, coeffs: (i32, i32), quality: Quality, shaping: NoiseShaping) -> i32 {
+        if self.min == 0 && self.max == 0 {
+            return 0;
+        }
+
         let mut scale = if self.max / 7 > self.min / -8 {
             self.max / 7
         } else {
@@ -98,22 +331,61 @@ impl Block {
             scale = 1;
         }
 
+        if quality != Quality::Fast {
+            scale = self.search_scale(scale, coeffs, shaping);
+        }
+        scale
+    }
+
+    /// Picks the scale near `estimate` with the least squared error once the
+    /// block goes through the decoder's predictor.
+    fn search_scale(&self, estimate: i32, coeffs: (i32, i32), shaping: NoiseShaping) -> i32 {
+        let mut best_scale = estimate;
+        let mut best_error = self.quantization_error(estimate, coeffs, shaping);
+
+        for &step in SCALE_SEARCH_STEPS.iter() {
+            let scale = (estimate * step / 8).clamp(1, 0x7fff);
+            if scale == best_scale {
+                continue;
+            }
+            let error = self.quantization_error(scale, coeffs, shaping);
+            if error < best_error {
+                best_scale = scale;
+                best_error = error;
+            }
+        }
+
+        best_scale
+    }
+
+    /// Squared error of the block quantized with `scale`, measured against
+    /// the noise shaped input.
+    fn quantization_error(&self, scale: i32, coeffs: (i32, i32), shaping: NoiseShaping) -> u64 {
+        let mut trial = *self;
+        trial.quantize(scale, coeffs, shaping)
+    }
+
+    /// Quantizes the block with `scale` without writing it, leaving the
+    /// predictor where a decoder would be afterwards. Returns the squared
+    /// error.
+    fn quantize(&mut self, scale: i32, coeffs: (i32, i32), shaping: NoiseShaping) -> u64 {
         self.prev = self.orig_prev;
+        self.errors = self.orig_errors;
 
-        writer.write_u16(scale as u16)?;
-        for byte_idx in 0..self.samples.len() / 2 {
-            let sample1 = self.samples[byte_idx * 2];
-            let sample2 = self.samples[byte_idx * 2 + 1];
-            let upper_nibble = self.get_nibble(sample1, scale, coeffs);
-            let lower_nibble = self.get_nibble(sample2, scale, coeffs);
-            let byte = (upper_nibble << 4) | (lower_nibble & 0xF);
-            writer.write_u8(byte)?;
+        let mut error = 0;
+        for idx in 0..self.size {
+            let sample = self.samples[idx];
+            let target = sample as i32 - shaping.feedback(&self.errors);
+            self.get_nibble(sample, scale, coeffs, shaping);
+            let diff = self.prev.first as i64 - target as i64;
+            error += (diff * diff) as u64;
         }
-        Ok(())
+        error
     }
 
-    fn get_nibble(&mut self, sample: i16, scale: i32, coeffs: (i32, i32)) -> u8 {
-        let delta = (((sample as i32) << 12) - coeffs.0 * self.prev.first as i32 -
+    fn get_nibble(&mut self, sample: i16, scale: i32, coeffs: (i32, i32), shaping: NoiseShaping) -> u8 {
+        let target = sample as i32 - shaping.feedback(&self.errors);
+        let delta = ((target << 12) - coeffs.0 * self.prev.first as i32 -
                      coeffs.1 * self.prev.second as i32) >> 12;
 
         // Rounded div
@@ -146,11 +418,160 @@ impl Block {
 
         self.prev.second = self.prev.first;
         self.prev.first = simulated_sample;
+        shaping.push_error(&mut self.errors, simulated_sample as i32 - target, scale);
 
         nibble as u8
     }
 }
 
+/// A block's scale and nibbles, ready to write.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+struct EncodedBlock {
+    scale: u16,
+    nibbles: [i8; 32],
+}
+
+impl EncodedBlock {
+    fn to_writer<W>(&self, mut writer: W) -> RadxResult<()>
+        where W: Write
+    {
+        writer.write_u16(self.scale)?;
+        for pair in self.nibbles.chunks(2) {
+            writer.write_u8(((pair[0] as u8) << 4) | (pair[1] as u8 & 0xF))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone,Copy,Debug)]
+struct TrellisPath {
+    prev: Prev<i16>,
+    errors: [i32; NOISE_SHAPING_ORDER],
+    error: u64,
+    /// The block before `current`, written out once every surviving path
+    /// agrees on it.
+    held: Option<EncodedBlock>,
+    current: EncodedBlock,
+}
+
+/// Nibble search for one channel in `Quality::Trellis`.
+///
+/// Each path is a run of nibble choices along with the predictor state the
+/// decoder would end up in. For every sample, each path branches on the
+/// rounded nibble and its two neighbours, and only the `TRELLIS_WIDTH` paths
+/// with the least error survive. Paths run on past the end of a block, so a
+/// block is only written out when the next one has been searched too.
+#[derive(Clone,Debug)]
+struct Trellis {
+    /// Sorted by error, best first. Empty until the first block.
+    paths: Vec<TrellisPath>,
+}
+
+impl Trellis {
+    fn new() -> Trellis {
+        Trellis {
+            paths: Vec::new(),
+        }
+    }
+
+    /// Predictor and noise shaping state at the end of the best path.
+    fn best_state(&self) -> (Prev<i16>, [i32; NOISE_SHAPING_ORDER]) {
+        (self.paths[0].prev, self.paths[0].errors)
+    }
+
+    /// Searches `block` with `scale`, returning the block before it if it
+    /// can be written now.
+    fn push_block(&mut self, block: &Block, scale: i32, coeffs: (i32, i32), shaping: NoiseShaping) -> Option<EncodedBlock> {
+        if self.paths.is_empty() {
+            self.paths.push(TrellisPath {
+                prev: block.orig_prev,
+                errors: block.orig_errors,
+                error: 0,
+                held: None,
+                current: EncodedBlock {
+                    scale: 0,
+                    nibbles: [0; 32],
+                },
+            });
+        }
+        for path in self.paths.iter_mut() {
+            path.current.scale = scale as u16;
+        }
+
+        let mut next = Vec::with_capacity(TRELLIS_WIDTH * 3);
+        for (idx, &sample) in block.samples.iter().enumerate() {
+            let padding = idx >= block.size;
+            next.clear();
+            for path in self.paths.iter() {
+                let prediction = coeffs.0 * path.prev.first as i32 + coeffs.1 * path.prev.second as i32;
+                let target = sample as i32 - shaping.feedback(&path.errors);
+                let nearest = if scale == 0 {
+                    0
+                } else {
+                    let delta = ((target << 12) - prediction) >> 12;
+                    let unclipped = if delta > 0 {
+                        (delta + (scale >> 1)) / scale
+                    } else {
+                        (delta - (scale >> 1)) / scale
+                    };
+                    unclipped.clamp(-8, 7)
+                };
+                // Padding past the end of the stream isn't worth branching on
+                let (low, high) = if scale == 0 || padding {
+                    (nearest, nearest)
+                } else {
+                    (cmp::max(nearest - 1, -8), cmp::min(nearest + 1, 7))
+                };
+
+                for nibble in low..high + 1 {
+                    let simulated_sample = path.prev.decode(nibble, scale, coeffs);
+                    let diff = if padding { 0 } else { simulated_sample as i64 - target as i64 };
+
+                    let mut branch = *path;
+                    branch.prev.push(simulated_sample);
+                    shaping.push_error(&mut branch.errors, diff as i32, scale);
+                    branch.error += (diff * diff) as u64;
+                    branch.current.nibbles[idx] = nibble as i8;
+                    next.push(branch);
+                }
+            }
+
+            // Paths that reach the same state behave the same from here on,
+            // so only the better of them is worth keeping
+            next.sort_by_key(|path| path.error);
+            self.paths.clear();
+            for branch in next.iter() {
+                if self.paths.len() == TRELLIS_WIDTH {
+                    break;
+                }
+                let duplicate = self.paths.iter().any(|path| {
+                    path.prev.first == branch.prev.first && path.prev.second == branch.prev.second &&
+                        path.errors == branch.errors
+                });
+                if !duplicate {
+                    self.paths.push(*branch);
+                }
+            }
+        }
+
+        // Commit to the best path's previous block and drop the paths that
+        // disagree with it
+        let output = self.paths[0].held;
+        self.paths.retain(|path| path.held == output);
+        for path in self.paths.iter_mut() {
+            path.held = Some(path.current);
+        }
+        output
+    }
+
+    /// Ends the search, returning the last block of the best path.
+    fn take_held(&mut self) -> Option<EncodedBlock> {
+        let held = self.paths.first().and_then(|path| path.held);
+        self.paths.clear();
+        held
+    }
+}
+
 #[derive(Clone,Debug)]
 struct Frame {
     blocks: Vec<Block>,
@@ -185,14 +606,342 @@ impl Frame {
         self.blocks[0].is_full()
     }
 
-    fn to_writer<W>(&mut self, mut writer: W, coeffs: (i32, i32)) -> RadxResult<()>
+    fn to_writer<W>(&mut self, mut writer: W, coeffs: (i32, i32), quality: Quality, shaping: NoiseShaping) -> RadxResult<()>
         where W: Write
     {
         for block in self.blocks.iter_mut() {
-            block.to_writer(&mut writer, coeffs)?;
+            block.to_writer(&mut writer, coeffs, quality, shaping)?;
+        }
+        Ok(())
+    }
+
+    /// Encodes the frame, going through `trellises` for `Quality::Trellis`.
+    /// A trellis search holds each frame back until the next one.
+    fn encode<W>(&mut self, mut writer: W, trellises: &mut [Trellis], coeffs: (i32, i32), quality: Quality, shaping: NoiseShaping) -> RadxResult<()>
+        where W: Write
+    {
+        if quality != Quality::Trellis {
+            flush_trellises(trellises, &mut writer)?;
+            return self.to_writer(writer, coeffs, quality, shaping);
+        }
+
+        let mut held = Vec::new();
+        for (block, trellis) in self.blocks.iter_mut().zip(trellises.iter_mut()) {
+            let scale = block.choose_scale(coeffs, quality, shaping);
+            held.extend(trellis.push_block(block, scale, coeffs, shaping));
+            let (prev, errors) = trellis.best_state();
+            block.prev = prev;
+            block.errors = errors;
+        }
+        for block in held {
+            block.to_writer(&mut writer)?;
         }
         Ok(())
     }
+
+    /// Starts a frame where a decoder would be in `state`.
+    fn from_state(state: &[Prev<i16>]) -> Frame {
+        let mut blocks = Vec::new();
+
+        for &prev in state.iter() {
+            let mut block = Block::new();
+            block.prev = prev;
+            block.orig_prev = prev;
+            blocks.push(block);
+        }
+
+        Frame { blocks: blocks }
+    }
+
+    fn state(&self) -> Vec<Prev<i16>> {
+        self.blocks.iter().map(|block| block.prev).collect()
+    }
+}
+
+/// Writes out the blocks still held back by trellis searches.
+fn flush_trellises<W>(trellises: &mut [Trellis], mut writer: W) -> RadxResult<()>
+    where W: Write
+{
+    for trellis in trellises.iter_mut() {
+        if let Some(block) = trellis.take_held() {
+            block.to_writer(&mut writer)?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs a decoder starting in `state` over the first `frames` frames of
+/// `data`, returning the state it ends up in.
+fn decoded_state(data: &[u8], mut state: Vec<Prev<i16>>, coeffs: (i32, i32), frames: usize) -> Vec<Prev<i16>> {
+    let channels = state.len();
+    for (block_idx, block) in data.chunks(18).take(frames * channels).enumerate() {
+        let prev = &mut state[block_idx % channels];
+        let scale = ((block[0] as i32) << 8) | block[1] as i32;
+        for &byte in block[2..].iter() {
+            for &nibble in [byte >> 4, byte & 0xF].iter() {
+                let nibble = ((nibble << 4) as i8 >> 4) as i32;
+                let sample = prev.decode(nibble, scale, coeffs);
+                prev.push(sample);
+            }
+        }
+    }
+    state
+}
+
+/// Decodes the first frame of `data` for a decoder starting in `state`.
+fn decoded_frame(data: &[u8], mut state: Vec<Prev<i16>>, coeffs: (i32, i32)) -> Vec<Sample> {
+    let channels = state.len();
+    let mut samples = vec![vec![0; channels]; 32];
+    for (channel, block) in data.chunks(18).take(channels).enumerate() {
+        let prev = &mut state[channel];
+        let scale = ((block[0] as i32) << 8) | block[1] as i32;
+        for (byte_idx, &byte) in block[2..].iter().enumerate() {
+            for (half, &nibble) in [byte >> 4, byte & 0xF].iter().enumerate() {
+                let nibble = ((nibble << 4) as i8 >> 4) as i32;
+                let sample = prev.decode(nibble, scale, coeffs);
+                prev.push(sample);
+                samples[byte_idx * 2 + half][channel] = sample;
+            }
+        }
+    }
+    samples
+}
+
+/// Where one channel's encoding stands between frames.
+#[derive(Clone,Debug)]
+struct ChannelState {
+    block: Block,
+    trellis: Trellis,
+}
+
+impl ChannelState {
+    /// A guess at the state before sample `start`, from the input alone.
+    fn guess(samples: &[Sample], channel: usize, start: usize) -> ChannelState {
+        let mut block = Block::new();
+        for sample in samples[start.saturating_sub(2)..start].iter() {
+            block.prev.push(sample[channel]);
+        }
+        block.orig_prev = block.prev;
+        ChannelState {
+            block: block,
+            trellis: Trellis::new(),
+        }
+    }
+
+    /// Whether encoding from `other` writes the same as encoding from here.
+    fn same_as(&self, other: &ChannelState) -> bool {
+        if self.block.prev != other.block.prev || self.block.errors != other.block.errors {
+            return false;
+        }
+        let (paths, other_paths) = (&self.trellis.paths, &other.trellis.paths);
+        if paths.len() != other_paths.len() {
+            return false;
+        }
+        // Paths only compete on error, so totals that are all off by the same
+        // amount make no difference. The first path has the least.
+        let (base, other_base) = match (paths.first(), other_paths.first()) {
+            (Some(path), Some(other_path)) => (path.error, other_path.error),
+            _ => return true,
+        };
+        paths.iter().zip(other_paths.iter()).all(|(path, other_path)| {
+            path.prev == other_path.prev && path.errors == other_path.errors &&
+                path.held == other_path.held && path.current == other_path.current &&
+                path.error - base == other_path.error - other_base
+        })
+    }
+}
+
+/// A run of frames one thread encoded for one channel.
+struct Segment {
+    /// The state the segment was encoded from, a guess for all but the
+    /// first.
+    start: ChannelState,
+    /// What each frame wrote.
+    output: Vec<Vec<u8>>,
+    /// The state after each frame, kept when `start` is a guess.
+    states: Vec<ChannelState>,
+    end: ChannelState,
+}
+
+/// Encodes whole frames of one channel from `state`, returning what each
+/// frame wrote.
+fn encode_channel(samples: &[Sample], channel: usize, state: &mut ChannelState, coeffs: (i32, i32), quality: Quality, shaping: NoiseShaping,
+                  mut states: Option<&mut Vec<ChannelState>>) -> RadxResult<Vec<Vec<u8>>> {
+    let mut output = Vec::with_capacity(samples.len() / 32);
+    for frame_samples in samples.chunks(32) {
+        let mut frame = Frame { blocks: vec![state.block] };
+        for sample in frame_samples.iter() {
+            frame.blocks[0].push(sample[channel], coeffs);
+        }
+        let mut data = Vec::new();
+        frame.encode(&mut data, slice::from_mut(&mut state.trellis), coeffs, quality, shaping)?;
+        state.block = Block::from_prev(&frame.blocks[0]);
+        output.push(data);
+        if let Some(ref mut states) = states {
+            states.push(state.clone());
+        }
+    }
+    Ok(output)
+}
+
+/// Encodes `frames` of one channel from `start`, or without it from a guess
+/// warmed up over the frames before.
+fn encode_segment(samples: &[Sample], channel: usize, frames: Range<usize>, start: Option<&ChannelState>, coeffs: (i32, i32),
+                  quality: Quality, shaping: NoiseShaping) -> RadxResult<Segment> {
+    let (begin, end) = (frames.start, frames.end);
+    let guessed = start.is_none();
+    let mut state = if let Some(start) = start {
+        start.clone()
+    }
+    else {
+        let warmup = begin.saturating_sub(WARMUP_FRAMES);
+        let mut state = ChannelState::guess(samples, channel, warmup * 32);
+        encode_channel(&samples[warmup * 32..begin * 32], channel, &mut state, coeffs, quality, shaping, None)?;
+        state
+    };
+    let start = state.clone();
+    let mut states = Vec::new();
+    let output = {
+        let keep_states = if guessed { Some(&mut states) } else { None };
+        encode_channel(&samples[begin * 32..end * 32], channel, &mut state, coeffs, quality, shaping, keep_states)?
+    };
+    Ok(Segment {
+        start: start,
+        output: output,
+        states: states,
+        end: state,
+    })
+}
+
+/// Decodes frames as they get written, comparing them with the input for
+/// `EncoderStats`.
+#[derive(Clone,Debug)]
+struct Analysis {
+    coeffs: (i32, i32),
+    /// Where a decoder would be in each channel.
+    state: Vec<Prev<i16>>,
+    /// Input whose frames haven't been written yet.
+    pending: VecDeque<Sample>,
+    /// Bytes of the frame being written.
+    frame: Vec<u8>,
+    data_bytes: u64,
+    scale_histogram: [u64; 16],
+    clipped_nibbles: u64,
+    predictor_clamps: u64,
+    signal: Vec<f64>,
+    noise: Vec<f64>,
+}
+
+impl Analysis {
+    fn new(channels: usize, coeffs: (i32, i32)) -> Analysis {
+        Analysis {
+            coeffs: coeffs,
+            state: vec![Prev { first: 0, second: 0 }; channels],
+            pending: VecDeque::new(),
+            frame: Vec::with_capacity(18 * channels),
+            data_bytes: 0,
+            scale_histogram: [0; 16],
+            clipped_nibbles: 0,
+            predictor_clamps: 0,
+            signal: vec![0.0; channels],
+            noise: vec![0.0; channels],
+        }
+    }
+
+    fn push_input(&mut self, sample: Sample) {
+        self.pending.push_back(sample);
+    }
+
+    fn analyze(&mut self, data: &[u8]) {
+        self.data_bytes += data.len() as u64;
+        let frame_len = 18 * self.state.len();
+        for &byte in data.iter() {
+            self.frame.push(byte);
+            if self.frame.len() == frame_len {
+                self.analyze_frame();
+                self.frame.clear();
+            }
+        }
+    }
+
+    fn analyze_frame(&mut self) {
+        let coeffs = self.coeffs;
+        let input_len = cmp::min(self.pending.len(), 32);
+        let input: Vec<Sample> = self.pending.drain(..input_len).collect();
+
+        for (channel, block) in self.frame.chunks(18).enumerate() {
+            let scale = ((block[0] as i32) << 8) | block[1] as i32;
+            let scale_bits = 32 - (scale as u32).leading_zeros() as usize;
+            self.scale_histogram[cmp::min(scale_bits, 15)] += 1;
+
+            let prev = &mut self.state[channel];
+            for (idx, &byte) in block[2..].iter().enumerate() {
+                for (half, &nibble) in [byte >> 4, byte & 0xF].iter().enumerate() {
+                    let nibble = ((nibble << 4) as i8 >> 4) as i32;
+                    let prediction = coeffs.0 * prev.first as i32 + coeffs.1 * prev.second as i32;
+                    let unclamped_sample = ((nibble << 12) * scale + prediction) >> 12;
+                    let sample = prev.decode(nibble, scale, coeffs);
+
+                    // Past the end of the input is only padding
+                    if let Some(input_sample) = input.get(idx * 2 + half) {
+                        let target = input_sample[channel] as i32;
+                        if scale != 0 {
+                            let delta = ((target << 12) - prediction) >> 12;
+                            let unclipped = if delta > 0 {
+                                (delta + (scale >> 1)) / scale
+                            } else {
+                                (delta - (scale >> 1)) / scale
+                            };
+                            if !(-8..8).contains(&unclipped) {
+                                self.clipped_nibbles += 1;
+                            }
+                        }
+                        if unclamped_sample != sample as i32 {
+                            self.predictor_clamps += 1;
+                        }
+                        let diff = (sample as i32 - target) as f64;
+                        self.signal[channel] += target as f64 * target as f64;
+                        self.noise[channel] += diff * diff;
+                    }
+                    prev.push(sample);
+                }
+            }
+        }
+    }
+
+    fn stats(&self, samples: u32, bytes: u64) -> EncoderStats {
+        EncoderStats {
+            samples: samples,
+            bytes: bytes,
+            scale_histogram: self.scale_histogram.to_vec(),
+            clipped_nibbles: self.clipped_nibbles,
+            predictor_clamps: self.predictor_clamps,
+            snr: self.signal.iter()
+                .zip(self.noise.iter())
+                .map(|(&signal, &noise)| snr(signal, noise))
+                .collect(),
+        }
+    }
+}
+
+/// Passes data through to a writer, analyzing it on the way.
+struct AnalyzingWriter<'a, W: 'a> {
+    inner: &'a mut W,
+    analysis: &'a mut Analysis,
+}
+
+impl<'a, W> Write for AnalyzingWriter<'a, W>
+    where W: Write
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.analysis.analyze(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
 }
 
 #[derive(Clone,Debug)]
@@ -204,12 +953,63 @@ pub struct StandardEncoder<W> {
     coeffs: (i32, i32),
     samples_encoded: usize,
     current_frame: Frame,
+    quality: Quality,
+    shaping: NoiseShaping,
+    trellises: Vec<Trellis>,
+    /// Samples from the loop start on, kept back until `finish` so the loop
+    /// can be encoded to flow back into itself. Starts `held_samples` early.
+    loop_samples: Option<Vec<Sample>>,
+    /// Samples from before the loop start at the front of `loop_samples`.
+    held_samples: usize,
+    crossfade: Option<Crossfade>,
+    match_loop_entry: bool,
+    /// The samples just before `loop_samples`, for the crossfade.
+    lead_in: VecDeque<Sample>,
+    /// How to get back to the header at the end. `None` for streaming
+    /// encoders, which write it first.
+    seek: Option<fn(&mut W, u64) -> RadxResult<()>>,
+    /// Samples a streaming encoder was promised.
+    declared_samples: Option<u32>,
+    analysis: Analysis,
+    /// Header version, 3 unless `to_writer` was asked for another.
+    version: u8,
+    progress: Progress,
+    threads: usize,
+    /// Samples waiting to be encoded on `threads` threads.
+    batch: Vec<Sample>,
 }
 
 impl<W> StandardEncoder<W>
     where W: Write + Seek
 {
-    pub fn new(mut writer: W, mut spec: AdxSpec) -> RadxResult<StandardEncoder<W>> {
+    pub fn new(writer: W, spec: AdxSpec) -> RadxResult<StandardEncoder<W>> {
+        let mut encoder = StandardEncoder::from_spec(writer, spec);
+        encoder.inner.seek(SeekFrom::Start(encoder.header_size as u64))?;
+        encoder.seek = Some(seek_writer::<W>);
+        encoder.encode_alignment()?;
+        Ok(encoder)
+    }
+}
+
+impl<W> StandardEncoder<W>
+    where W: Write
+{
+    /// Makes an encoder for a writer that can't seek, such as stdout or a
+    /// socket.
+    ///
+    /// The header goes out first, so it needs `total_samples` up front.
+    /// Giving the encoder more samples than that, or finishing with fewer,
+    /// is a `SampleCountMismatch` error.
+    pub fn new_streaming(writer: W, spec: AdxSpec, total_samples: u32) -> RadxResult<StandardEncoder<W>> {
+        let mut encoder = StandardEncoder::from_spec(writer, spec);
+        encoder.declared_samples = Some(total_samples);
+        let header = encoder.header(total_samples + encoder.alignment_samples as u32);
+        header.to_writer(&mut encoder.inner, encoder.header_size)?;
+        encoder.encode_alignment()?;
+        Ok(encoder)
+    }
+
+    fn from_spec(writer: W, mut spec: AdxSpec) -> StandardEncoder<W> {
         let alignment_samples = spec.loop_info
             .as_mut()
             .map(|li| {
@@ -231,51 +1031,255 @@ impl<W> StandardEncoder<W>
 				fs_blocks * 0x800 - bytes_till_loop_start
 			})
 			.unwrap_or(ADX_HEADER_LEN);
+        let coeffs = gen_coeffs(spec.highpass_frequency as u32, spec.sample_rate);
 
-        writer.seek(SeekFrom::Start(header_size as u64))?;
-			
-        let mut encoder = StandardEncoder {
+        StandardEncoder {
             inner: writer,
             spec: spec,
 			header_size: header_size,
             alignment_samples: alignment_samples,
-            coeffs: gen_coeffs(HIGHPASS_FREQ, spec.sample_rate),
+            coeffs: coeffs,
             samples_encoded: 0,
             current_frame: Frame::new(spec.channels as usize),
-        };
-        encoder.encode_data(iter::repeat(iter::repeat(0)
-                    .take(spec.channels as usize)
+            quality: Quality::Fast,
+            shaping: NoiseShaping::none(),
+            trellises: iter::repeat(Trellis::new()).take(spec.channels as usize).collect(),
+            loop_samples: None,
+            held_samples: 0,
+            crossfade: None,
+            match_loop_entry: false,
+            lead_in: VecDeque::new(),
+            seek: None,
+            declared_samples: None,
+            analysis: Analysis::new(spec.channels as usize, coeffs),
+            version: 3,
+            progress: Progress::new(),
+            threads: 1,
+            batch: Vec::new(),
+        }
+    }
+
+    fn encode_alignment(&mut self) -> RadxResult<()> {
+        let channels = self.spec.channels as usize;
+        let alignment_samples = self.alignment_samples;
+        self.encode_data(iter::repeat(iter::repeat(0)
+                    .take(channels)
                     .collect::<Sample>())
-                .take(alignment_samples))?;
-        Ok(encoder)
+                .take(alignment_samples))
+    }
+
+    /// Only for seekable encoders, which write the header last, and only
+    /// without a loop for versions other than 3.
+    pub(crate) fn set_version(&mut self, version: u8) {
+        self.version = version;
+    }
+
+    /// Sets how carefully block scales are chosen. `Quality::Fast` unless
+    /// set.
+    pub fn set_quality(&mut self, quality: Quality) {
+        self.quality = quality;
+    }
+
+    /// Sets the noise shaping filter. `NoiseShaping::none()` unless set.
+    pub fn set_noise_shaping(&mut self, shaping: NoiseShaping) {
+        self.shaping = shaping;
+    }
+
+    /// Crossfades the end of the loop into the audio before its start. Has
+    /// to be set before the loop start is reached, and does nothing for
+    /// streams that don't loop. No crossfade unless set.
+    pub fn set_loop_crossfade(&mut self, crossfade: Option<Crossfade>) {
+        self.crossfade = crossfade;
+    }
+
+    /// Fades the last frame before the loop start into the end of the loop,
+    /// so the first time into the loop starts from the same predictor state
+    /// as going around it. Without this, the loop clicks once on the way in
+    /// unless the audio there already matches the loop end.
+    ///
+    /// That changes the 32 samples before the loop start. Has to be set
+    /// before they're reached, and does nothing for loops that start in the
+    /// first frame. Off unless set.
+    pub fn set_match_loop_entry(&mut self, match_loop_entry: bool) {
+        self.match_loop_entry = match_loop_entry;
+    }
+
+    /// Reports progress and checks for cancellation after every frame.
+    ///
+    /// A cancelled encoder stops between frames, before `encode_data` takes
+    /// the next sample, and returns `RadxError::Cancelled`. The samples taken
+    /// up to then are kept, so `finish` still writes a whole stream of them,
+    /// cutting short the search for a seamless loop. A streaming encoder
+    /// can't finish early, since its header promised more.
+    pub fn set_progress(&mut self, mut progress: Progress) {
+        progress.default_total(self.declared_samples.map(|declared| declared as u64));
+        self.progress = progress;
+    }
+
+    /// Encodes on up to `threads` threads, splitting the work by channel
+    /// and, for long enough input, by time. The output is the same as with
+    /// one thread, which is the default.
+    ///
+    /// More than one thread buffers the input, so samples only get written
+    /// a batch at a time. `set_quality` and `set_noise_shaping` apply to
+    /// the whole batch being buffered when they're called.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = cmp::max(threads, 1);
     }
 
     pub fn encode_data<I>(&mut self, samples: I) -> RadxResult<()>
         where I: IntoIterator<Item = Sample>
     {
         for sample in samples {
-            self.current_frame.push(sample, self.coeffs);
+            if let Some(declared) = self.declared_samples {
+                if self.samples_encoded == self.alignment_samples + declared as usize {
+                    return Err(RadxError::SampleCountMismatch(declared, declared + 1));
+                }
+            }
+            if self.loop_samples.is_none() {
+                if let Some(loop_info) = self.spec.loop_info {
+                    let start = loop_info.start_sample as usize;
+                    // The alignment samples go in before the option can be set
+                    let held = if self.match_loop_entry && start >= self.alignment_samples + 32 { 32 } else { 0 };
+                    if self.samples_encoded == start - held {
+                        // Loop starts are aligned, so the last frame has
+                        // just been written. When matching the loop entry,
+                        // the one after it gets held back too.
+                        self.held_samples = held;
+                        self.encode_batch()?;
+                        flush_trellises(&mut self.trellises, AnalyzingWriter {
+                            inner: &mut self.inner,
+                            analysis: &mut self.analysis,
+                        })?;
+                        self.loop_samples = Some(Vec::new());
+                    }
+                }
+            }
 			self.samples_encoded += 1;
-            if self.current_frame.is_full() {
-                self.current_frame.to_writer(&mut self.inner, self.coeffs)?;
-                let new_frame = Frame::from_prev(&self.current_frame);
-                self.current_frame = new_frame;
+            if let Some(ref mut loop_samples) = self.loop_samples {
+                loop_samples.push(sample);
+            }
+            else {
+                self.encode_sample(sample)?;
             }
+
+            if self.samples_encoded.is_multiple_of(32) {
+                let processed = self.samples_encoded.saturating_sub(self.alignment_samples);
+                self.progress.check(processed as u64)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_sample(&mut self, sample: Sample) -> RadxResult<()> {
+        self.analysis.push_input(sample.clone());
+        if let Some(crossfade) = self.crossfade {
+            if self.spec.loop_info.is_some() {
+                self.lead_in.push_back(sample.clone());
+                if self.lead_in.len() > crossfade.length as usize {
+                    self.lead_in.pop_front();
+                }
+            }
+        }
+
+        // A frame started on one thread gets finished on one thread
+        if self.threads > 1 && (self.current_frame.is_empty() || !self.batch.is_empty()) {
+            self.batch.push(sample);
+            let channels = self.spec.channels as usize;
+            if self.batch.len() >= cmp::max(self.threads / channels, 1) * SEGMENT_FRAMES * 32 {
+                self.encode_batch()?;
+            }
+            return Ok(());
+        }
+
+        self.current_frame.push(sample, self.coeffs);
+        if self.current_frame.is_full() {
+            let writer = AnalyzingWriter {
+                inner: &mut self.inner,
+                analysis: &mut self.analysis,
+            };
+            self.current_frame.encode(writer, &mut self.trellises, self.coeffs, self.quality, self.shaping)?;
+            let new_frame = Frame::from_prev(&self.current_frame);
+            self.current_frame = new_frame;
         }
         Ok(())
     }
 
-    pub fn finish(mut self) -> RadxResult<()> {
+    /// Encodes the whole frames waiting in the batch, leaving any samples
+    /// after them in the current frame.
+    fn encode_batch(&mut self) -> RadxResult<()> {
+        let full = self.batch.len() / 32 * 32;
+        if full > 0 {
+            let mut frame = self.current_frame.clone();
+            let mut trellises = mem::take(&mut self.trellises);
+            let mut data = Vec::new();
+            self.encode_frames(&mut data, &self.batch[..full], &mut frame, &mut trellises)?;
+            self.current_frame = frame;
+            self.trellises = trellises;
+            AnalyzingWriter {
+                inner: &mut self.inner,
+                analysis: &mut self.analysis,
+            }.write_all(&data)?;
+        }
+        for sample in self.batch.drain(..).skip(full) {
+            self.current_frame.push(sample, self.coeffs);
+        }
+        Ok(())
+    }
+
+    /// Writes out the rest of the stream, returning stats on how it was
+    /// encoded. The SNR is measured against the samples after any loop
+    /// crossfade.
+    pub fn finish(mut self) -> RadxResult<EncoderStats> {
+        if let Some(declared) = self.declared_samples {
+            let encoded = (self.samples_encoded - self.alignment_samples) as u32;
+            if encoded != declared {
+                return Err(RadxError::SampleCountMismatch(declared, encoded));
+            }
+        }
+
+        self.encode_batch()?;
 		if !self.current_frame.is_empty() {
-			self.current_frame.to_writer(&mut self.inner, self.coeffs)?;
+            let writer = AnalyzingWriter {
+                inner: &mut self.inner,
+                analysis: &mut self.analysis,
+            };
+			self.current_frame.encode(writer, &mut self.trellises, self.coeffs, self.quality, self.shaping)?;
 		}
+        flush_trellises(&mut self.trellises, AnalyzingWriter {
+            inner: &mut self.inner,
+            analysis: &mut self.analysis,
+        })?;
+        if let Some(mut loop_samples) = self.loop_samples.take() {
+            {
+                let held = cmp::min(self.held_samples, loop_samples.len());
+                let (held, samples) = loop_samples.split_at_mut(held);
+                self.crossfade_loop(held, samples);
+            }
+            let data = self.encode_loop(&loop_samples)?;
+            self.analysis.pending.extend(loop_samples);
+            self.analysis.analyze(&data);
+            self.inner.write_all(&data)?;
+        }
         self.inner.write_u16(0x8001)?;
         self.inner.write_u16(0x000e)?;
         for _ in 0..14 {
             self.inner.write_u8(0x00)?;
         }
-        self.inner.seek(SeekFrom::Start(0))?;
 
+        if let Some(seek) = self.seek {
+            seek(&mut self.inner, 0)?;
+            let header = self.header(self.samples_encoded as u32);
+            header.to_writer(&mut self.inner, self.header_size)?;
+        }
+
+        let samples = (self.samples_encoded - self.alignment_samples) as u32;
+        let bytes = self.header_size as u64 + self.analysis.data_bytes + 18;
+        self.progress.report(samples as u64);
+        Ok(self.analysis.stats(samples, bytes))
+    }
+
+    fn header(&self, total_samples: u32) -> AdxHeader {
         let loop_info = self.spec.loop_info.map(|li| {
             AdxVersion3LoopInfo {
                 alignment_samples: self.alignment_samples as u16,
@@ -288,21 +1292,240 @@ impl<W> StandardEncoder<W>
             }
         });
 
-        let header = AdxHeader {
+        AdxHeader {
             encoding: AdxEncoding::Standard,
             block_size: 18,
             sample_bitdepth: 4,
             channel_count: self.spec.channels as u8,
             sample_rate: self.spec.sample_rate,
-            total_samples: self.samples_encoded as u32,
-            highpass_frequency: HIGHPASS_FREQ as u16,
-            version: AdxVersion::Version3(loop_info),
+            total_samples: total_samples,
+            highpass_frequency: self.spec.highpass_frequency,
+            version: match self.version {
+                4 => AdxVersion::Version4,
+                5 => AdxVersion::Version5,
+                _ => AdxVersion::Version3(loop_info),
+            },
             flags: 0,
+        }
+    }
+	
+    /// Fades the end of the loop in `samples` into what came before the loop
+    /// start, the last of which is `held`.
+    fn crossfade_loop(&self, held: &[Sample], samples: &mut [Sample]) {
+        let (crossfade, loop_info) = match (self.crossfade, self.spec.loop_info) {
+            (Some(crossfade), Some(loop_info)) => (crossfade, loop_info),
+            _ => return,
         };
-        header.to_writer(self.inner, self.header_size)?;
+        let loop_len = cmp::min((loop_info.end_sample - loop_info.start_sample) as usize, samples.len());
+        let lead_len = self.lead_in.len() + held.len();
+        let length = cmp::min(crossfade.length as usize, cmp::min(lead_len, loop_len));
+        let lead_in = self.lead_in.iter().chain(held.iter()).skip(lead_len - length);
+
+        for (idx, (sample, lead)) in samples[loop_len - length..loop_len].iter_mut().zip(lead_in).enumerate() {
+            let t = (idx + 1) as f64 / (length + 1) as f64;
+            let (out_gain, in_gain) = crossfade.curve.gains(t);
+            for (channel_sample, &lead_sample) in sample.iter_mut().zip(lead.iter()) {
+                let mixed = (*channel_sample as f64 * out_gain + lead_sample as f64 * in_gain).round();
+                *channel_sample = mixed.max(i16::MIN as f64).min(i16::MAX as f64) as i16;
+            }
+        }
+    }
+
+    /// Encodes everything from the loop start on, along with the samples
+    /// held back before it.
+    ///
+    /// A decoder going around the loop carries its predictor state over from
+    /// the end of the loop, not from the samples before the start, and data
+    /// encoded for the wrong state clicks. So the loop gets encoded starting
+    /// from the state it ends in, over again until that stops changing. The
+    /// first time into the loop from the samples before it still starts from
+    /// a different state, but that happens only once, unless
+    /// `set_match_loop_entry` fades the frame before the loop into its end.
+    fn encode_loop(&self, samples: &[Sample]) -> RadxResult<Vec<u8>> {
+        let loop_info = self.spec.loop_info.expect("only looping streams hold samples back");
+        let start_frame = loop_info.start_sample as usize / 32;
+        let end_frame = (loop_info.end_sample as usize).saturating_sub(1) / 32;
+        let loop_frames = if end_frame >= start_frame { end_frame - start_frame + 1 } else { 0 };
+        let entry_state = self.current_frame.state();
+        let (held, samples) = samples.split_at(cmp::min(self.held_samples, samples.len()));
+        // The stream might stop before the loop end
+        let loop_frames = cmp::min(loop_frames, samples.len().div_ceil(32));
+        if loop_frames == 0 {
+            return self.encode_from(&[held, samples].concat(), &entry_state);
+        }
+
+        let mut state = entry_state.clone();
+        let mut data = self.encode_from(samples, &state)?;
+        for _ in 1..LOOP_PASSES {
+            // Every pass is a whole stream, just not as seamless
+            if self.progress.is_cancelled() {
+                break;
+            }
+            let end_state = decoded_state(&data, state.clone(), self.coeffs, loop_frames);
+            if end_state == state {
+                break;
+            }
+            state = end_state;
+            data = self.encode_from(samples, &state)?;
+        }
+        if held.is_empty() {
+            return Ok(data);
+        }
+
+        // Fade the held frame into the last frame of the loop, ending on the
+        // state the loop was encoded from, give or take the quantization error
+        let channels = state.len();
+        let before_last = decoded_state(&data, state.clone(), self.coeffs, loop_frames - 1);
+        let last = decoded_frame(&data[(loop_frames - 1) * 18 * channels..], before_last, self.coeffs);
+        let mut lead = held.to_vec();
+        for (idx, (sample, end)) in lead.iter_mut().zip(last.iter()).enumerate() {
+            let t = ((idx + 1) as f64 / TRANSITION_SAMPLES as f64).min(1.0);
+            for (channel_sample, &end_sample) in sample.iter_mut().zip(end.iter()) {
+                *channel_sample = (*channel_sample as f64 * (1.0 - t) + end_sample as f64 * t).round() as i16;
+            }
+        }
+        // Only the same without the loop coming all the way around
+        for (channel, prev) in state.iter().enumerate() {
+            lead[30][channel] = prev.second;
+            lead[31][channel] = prev.first;
+        }
+
+        let mut transition = self.encode_from(&lead, &entry_state)?;
+        transition.extend(data);
+        Ok(transition)
+    }
+
+    fn encode_from(&self, samples: &[Sample], state: &[Prev<i16>]) -> RadxResult<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut frame = Frame::from_state(state);
+        let mut trellises: Vec<_> = iter::repeat(Trellis::new()).take(state.len()).collect();
+
+        let mut samples = samples;
+        if self.threads > 1 {
+            let full = samples.len() / 32 * 32;
+            self.encode_frames(&mut data, &samples[..full], &mut frame, &mut trellises)?;
+            samples = &samples[full..];
+        }
+        for sample in samples.iter() {
+            frame.push(sample.clone(), self.coeffs);
+            if frame.is_full() {
+                frame.encode(&mut data, &mut trellises, self.coeffs, self.quality, self.shaping)?;
+                frame = Frame::from_prev(&frame);
+            }
+        }
+        if !frame.is_empty() {
+            frame.encode(&mut data, &mut trellises, self.coeffs, self.quality, self.shaping)?;
+        }
+        flush_trellises(&mut trellises, &mut data)?;
+        Ok(data)
+    }
+
+    /// Encodes the whole frames in `samples` on `set_threads` threads,
+    /// starting from `frame` and `trellises` and leaving them where the last
+    /// frame ends.
+    ///
+    /// Every channel gets its own threads, and with threads to spare the
+    /// slower qualities split channels into segments that start from a
+    /// guessed state. Once the segment before has been encoded, a wrong guess
+    /// gets encoded over from the real state until it meets up with the
+    /// guessed encoding, usually somewhere along the segment. Either way the
+    /// output is the same as encoding frame by frame.
+    fn encode_frames<V>(&self, mut writer: V, samples: &[Sample], frame: &mut Frame, trellises: &mut [Trellis]) -> RadxResult<()>
+        where V: Write
+    {
+        let (coeffs, quality, shaping, threads) = (self.coeffs, self.quality, self.shaping, self.threads);
+        // Frame by frame, this would come at the start of the first frame
+        if quality != Quality::Trellis {
+            flush_trellises(trellises, &mut writer)?;
+        }
+
+        let channels = frame.blocks.len();
+        let frames = samples.len() / 32;
+        let segments = if quality == Quality::Fast {
+            // Guesses take a while to meet up, which only pays off for the
+            // slower searches
+            1
+        }
+        else {
+            cmp::max(1, cmp::min(threads / channels, frames / SEGMENT_FRAMES))
+        };
+        let bounds: Vec<usize> = (0..segments + 1).map(|segment| segment * frames / segments).collect();
+        let starts: Vec<ChannelState> = frame.blocks.iter().zip(trellises.iter()).map(|(block, trellis)| {
+            ChannelState {
+                block: *block,
+                trellis: trellis.clone(),
+            }
+        }).collect();
+        let jobs = channels * segments;
+
+        let mut results: Vec<Option<RadxResult<Segment>>> = (0..jobs).map(|_| None).collect();
+        let next = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..cmp::min(threads, jobs)).map(|_| {
+                let (next, bounds, starts) = (&next, &bounds, &starts);
+                scope.spawn(move || {
+                    let mut done = Vec::new();
+                    loop {
+                        let job = next.fetch_add(1, Ordering::SeqCst);
+                        if job >= jobs {
+                            return done;
+                        }
+                        let (channel, segment) = (job / segments, job % segments);
+                        let start = if segment == 0 { Some(&starts[channel]) } else { None };
+                        let frames = bounds[segment]..bounds[segment + 1];
+                        done.push((job, encode_segment(samples, channel, frames, start, coeffs, quality, shaping)));
+                    }
+                })
+            }).collect();
+            for worker in workers {
+                let done = worker.join().unwrap_or_else(|err| panic::resume_unwind(err));
+                for (job, result) in done {
+                    results[job] = Some(result);
+                }
+            }
+        });
+
+        let mut outputs = Vec::with_capacity(channels);
+        let mut results = results.into_iter().map(|result| result.expect("every job gets run"));
+        for (channel, (block, trellis)) in frame.blocks.iter_mut().zip(trellises.iter_mut()).enumerate() {
+            let first = results.next().expect("every channel has a segment")?;
+            let mut output = first.output;
+            let mut state = first.end;
+            for &begin in bounds[1..segments].iter() {
+                let guessed = results.next().expect("every channel has a segment")?;
+                if state.same_as(&guessed.start) {
+                    output.extend(guessed.output);
+                    state = guessed.end;
+                    continue;
+                }
+
+                let mut met = None;
+                for (idx, guessed_state) in guessed.states.iter().enumerate() {
+                    let frame_samples = &samples[(begin + idx) * 32..(begin + idx + 1) * 32];
+                    output.extend(encode_channel(frame_samples, channel, &mut state, coeffs, quality, shaping, None)?);
+                    if state.same_as(guessed_state) {
+                        met = Some(idx + 1);
+                        break;
+                    }
+                }
+                if let Some(met) = met {
+                    output.extend(guessed.output.into_iter().skip(met));
+                    state = guessed.end;
+                }
+            }
+            *block = state.block;
+            *trellis = state.trellis;
+            outputs.push(output);
+        }
+
+        for frame_idx in 0..frames {
+            for output in outputs.iter() {
+                writer.write_all(&output[frame_idx])?;
+            }
+        }
         Ok(())
     }
-	
+
 	fn sample_to_byte(start_sample: u32, channels: u32) -> usize {
 		// (li.start_sample / 8) * 9 + ADX_HEADER_LEN as u32
 		let mut frames = start_sample / 32;
@@ -313,10 +1536,81 @@ impl<W> StandardEncoder<W>
 	}
 }
 
+impl<W> Encoder for StandardEncoder<W>
+    where W: Write
+{
+    fn encode_samples(&mut self, samples: &[Sample]) -> RadxResult<()> {
+        self.encode_data(samples.iter().cloned())
+    }
+
+    fn finish(self: Box<Self>) -> RadxResult<EncoderStats> {
+        (*self).finish()
+    }
+
+    fn set_progress(&mut self, progress: Progress) {
+        StandardEncoder::set_progress(self, progress)
+    }
+}
+
+/// Finds the predictor cutoff that encodes `samples` with the least error,
+/// for `AdxSpec::highpass_frequency`.
+///
+/// Each candidate cutoff gets a quick encode of the whole input, so this
+/// takes a while on long tracks.
+pub fn best_highpass_frequency(samples: &[Sample], channels: u32, sample_rate: u32) -> u16 {
+    let mut best_frequency = DEFAULT_HIGHPASS_FREQUENCY;
+    let mut best_error = None;
+
+    for &frequency in HIGHPASS_CANDIDATES.iter() {
+        if frequency as u32 * 2 >= sample_rate {
+            break;
+        }
+        let error = encoding_error(samples, channels as usize, gen_coeffs(frequency as u32, sample_rate));
+        let better = match best_error {
+            Some(best_error) => error < best_error,
+            None => true,
+        };
+        if better {
+            best_frequency = frequency;
+            best_error = Some(error);
+        }
+    }
+
+    best_frequency
+}
+
+/// Squared error of encoding `samples` with `coeffs` at `Quality::Fast`.
+fn encoding_error(samples: &[Sample], channels: usize, coeffs: (i32, i32)) -> u64 {
+    let mut frame = Frame::new(channels);
+    let mut error = 0;
+
+    for chunk in samples.chunks(32) {
+        for sample in chunk.iter() {
+            frame.push(sample.clone(), coeffs);
+        }
+        for block in frame.blocks.iter_mut() {
+            let scale = block.choose_scale(coeffs, Quality::Fast, NoiseShaping::none());
+            if scale != 0 {
+                error += block.quantize(scale, coeffs, NoiseShaping::none());
+            }
+        }
+        frame = Frame::from_prev(&frame);
+    }
+
+    error
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Block;
-    use gen_coeffs;
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::{Block, Crossfade, CrossfadeCurve, NoiseShaping, Quality, StandardEncoder, best_highpass_frequency};
+    use {AdxSpec, DEFAULT_HIGHPASS_FREQUENCY, LoopInfo, Sample, from_reader, gen_coeffs};
+    use encoder::{EncoderStats, measure_snr};
+    use error::RadxError;
+    use progress::Progress;
 
     #[test]
     fn test_block_write() {
@@ -326,15 +1620,392 @@ mod tests {
         for _ in 0..32 {
             block.push(100, coeffs);
         }
-        block.to_writer(&mut buf).unwrap();
-        println!("{:#?}", block);
+        block.to_writer(&mut buf, coeffs, Quality::Fast, NoiseShaping::none()).unwrap();
         block = Block::from_prev(&block);
         for _ in 0..32 {
             block.push(1, coeffs);
         }
-        block.to_writer(&mut buf).unwrap();
-        println!("{:#?}", block);
-        println!("{:?}", buf);
-        assert!(false);
+        block.to_writer(&mut buf, coeffs, Quality::Fast, NoiseShaping::none()).unwrap();
+
+        // Two blocks of a big endian scale and 32 nibbles. The first one's
+        // biggest step is the jump to 100, so its scale is 100 / 7.
+        assert_eq!(buf.len(), 36);
+        assert_eq!(&buf[..2], &[0, 14]);
+
+        // Decoded the way StandardDecoder does it, every sample lands within
+        // a step of what went in
+        let mut prev = (0, 0);
+        for (block, &expected) in buf.chunks(18).zip([100, 1].iter()) {
+            let scale = ((block[0] as i32) << 8) | block[1] as i32;
+            for &byte in block[2..].iter() {
+                for &nibble in [byte >> 4, byte & 0xf].iter() {
+                    let delta = (((nibble << 4) as i8) >> 4) as i32 * scale;
+                    let sample = ((coeffs.0 * prev.0 + coeffs.1 * prev.1) >> 12) + delta;
+                    assert!((sample - expected).abs() <= scale, "{} for {}, scale {}", sample, expected, scale);
+                    prev = (sample, prev.0);
+                }
+            }
+        }
+    }
+
+    const MONO: AdxSpec = AdxSpec {
+        channels: 1,
+        sample_rate: 32000,
+        loop_info: None,
+        highpass_frequency: DEFAULT_HIGHPASS_FREQUENCY,
+    };
+
+    /// Encodes `input` into a buffer, letting `setup` configure the encoder
+    /// first.
+    fn encode_with<I, F>(spec: AdxSpec, input: I, setup: F) -> (Vec<u8>, EncoderStats)
+        where I: IntoIterator<Item = Sample>,
+              F: FnOnce(&mut StandardEncoder<&mut Cursor<Vec<u8>>>)
+    {
+        let mut buf = Cursor::new(Vec::new());
+        let stats = {
+            let mut encoder = StandardEncoder::new(&mut buf, spec).unwrap();
+            setup(&mut encoder);
+            encoder.encode_data(input).unwrap();
+            encoder.finish().unwrap()
+        };
+        (buf.into_inner(), stats)
+    }
+
+    fn mono(input: &[i16]) -> Vec<Sample> {
+        input.iter().map(|&sample| vec![sample]).collect()
+    }
+
+    fn encode(input: &[i16], quality: Quality, shaping: NoiseShaping) -> Vec<i16> {
+        let (adx, _) = encode_with(MONO, mono(input), |encoder| {
+            encoder.set_quality(quality);
+            encoder.set_noise_shaping(shaping);
+        });
+
+        let decoder = from_reader(Cursor::new(adx), false).unwrap();
+        assert_eq!(decoder.total_samples(), Some(input.len() as u32));
+        decoder.map(|sample| sample[0]).collect()
+    }
+
+    fn encode_error(input: &[i16], quality: Quality) -> u64 {
+        encode(input, quality, NoiseShaping::none()).iter()
+            .zip(input.iter())
+            .map(|(&sample, &x)| {
+                let diff = sample as i64 - x as i64;
+                (diff * diff) as u64
+            })
+            .sum()
+    }
+
+    #[test]
+    fn scale_search() {
+        // Quiet tone with loud bursts cutting in partway through blocks
+        let input: Vec<i16> = (0..4000)
+            .map(|i| {
+                let tone = (i as f64 * 0.07).sin() * 500.0;
+                let burst = if i % 700 > 650 { ((i * 7919) % 20000) as f64 - 10000.0 } else { 0.0 };
+                (tone + burst) as i16
+            })
+            .collect();
+
+        let fast = encode_error(&input, Quality::Fast);
+        let best = encode_error(&input, Quality::Best);
+        assert!(best < fast, "best {} fast {}", best, fast);
+    }
+
+    #[test]
+    fn trellis() {
+        // Two tones and some noise, ending partway through a block
+        let input: Vec<i16> = (0..3001)
+            .map(|i| {
+                let tones = (i as f64 * 0.05).sin() * 6000.0 + (i as f64 * 0.9).sin() * 2000.0;
+                let noise = ((i * 7919) % 1000) as f64 - 500.0;
+                (tones + noise) as i16
+            })
+            .collect();
+
+        let best = encode_error(&input, Quality::Best);
+        let trellis = encode_error(&input, Quality::Trellis);
+        assert!(trellis < best, "trellis {} best {}", trellis, best);
+    }
+
+    #[test]
+    fn noise_shaping() {
+        let input: Vec<i16> = (0..8000)
+            .map(|i| ((i as f64 * 0.02).sin() * 3000.0 + (i as f64 * 0.3).sin() * 300.0) as i16)
+            .collect();
+
+        // Noise energy below about 500 Hz at 32 kHz, from sums over 32 samples
+        let low_noise = |shaping| {
+            let output = encode(&input, Quality::Best, shaping);
+            let noise: Vec<i64> = output.iter().zip(input.iter()).map(|(&y, &x)| y as i64 - x as i64).collect();
+            noise.chunks(32).map(|chunk| chunk.iter().sum::<i64>().pow(2)).sum::<i64>()
+        };
+
+        let flat = low_noise(NoiseShaping::none());
+        let shaped = low_noise(NoiseShaping::strong());
+        assert!(shaped * 4 < flat, "shaped {} flat {}", shaped, flat);
+    }
+
+    #[test]
+    fn seamless_loop() {
+        let input: Vec<i16> = (0..20000)
+            .map(|i| ((i as f64 * 0.013).sin() * 12000.0 + (i as f64 * 0.29).sin() * 2000.0) as i16)
+            .collect();
+        let (start, end) = (3000, 17000);
+        let looped = |match_loop_entry: bool| -> Vec<i16> {
+            let spec = AdxSpec {
+                loop_info: Some(LoopInfo {
+                    start_sample: start as u32,
+                    end_sample: end as u32,
+                }),
+                ..MONO
+            };
+            let (adx, _) = encode_with(spec, mono(&input), |encoder| encoder.set_match_loop_entry(match_loop_entry));
+            let decoder = from_reader(Cursor::new(adx), true).unwrap();
+            decoder.take(end + 2 * (end - start)).map(|sample| sample[0]).collect()
+        };
+        let largest_error = |output: &[i16], input: &[i16]| -> i32 {
+            output.iter().zip(input.iter()).map(|(&y, &x)| (y as i32 - x as i32).abs()).max().unwrap()
+        };
+
+        // Anywhere in the loop is within about 100 of the input. A state
+        // mismatch clicks by more than 1000.
+        let output = looped(false);
+        assert!(largest_error(&output[..start], &input[..start]) < 200);
+        assert!(largest_error(&output[start + 64..end], &input[start + 64..end]) < 200);
+        // Going around picks up from the state the loop was encoded from
+        for &wrap in [end, 2 * end - start].iter() {
+            let error = largest_error(&output[wrap..wrap + 64], &input[start..start + 64]);
+            assert!(error < 200, "{} off by {}", wrap, error);
+        }
+
+        // Coming in the first time can start from that state too, at the
+        // cost of changing the frame before the loop
+        let output = looped(true);
+        assert!(largest_error(&output[..start - 32], &input[..start - 32]) < 200);
+        for &entry in [start, end, 2 * end - start].iter() {
+            let error = largest_error(&output[entry..entry + 64], &input[start..start + 64]);
+            assert!(error < 200, "{} off by {}", entry, error);
+        }
+        assert!(largest_error(&output[start..start + 64], &output[end..end + 64]) < 100);
+    }
+
+    #[test]
+    fn loop_crossfade() {
+        // The tone is partway through a cycle at the loop end, so it jumps
+        // going around unless faded
+        let input: Vec<i16> = (0..12000)
+            .map(|i| ((i as f64 * 0.01).sin() * 10000.0) as i16)
+            .collect();
+        // 3000 gets padded out to a frame boundary, and so does 10008
+        let (start, end) = (3000, 10008);
+
+        let encode = |end: usize, crossfade| {
+            let spec = AdxSpec {
+                loop_info: Some(LoopInfo {
+                    start_sample: start as u32,
+                    end_sample: end as u32,
+                }),
+                ..MONO
+            };
+            encode_with(spec, mono(&input), |encoder| encoder.set_loop_crossfade(crossfade)).0
+        };
+        let largest_step = |crossfade| {
+            let decoder = from_reader(Cursor::new(encode(end, crossfade)), true).unwrap();
+            // Around the second wrap, coming from a full pass of the loop
+            let wrap = 2 * end - start;
+            let output: Vec<i16> = decoder.take(wrap + 32).map(|sample| sample[0]).collect();
+            output[wrap - 32..].windows(2).map(|pair| (pair[1] as i32 - pair[0] as i32).abs()).max().unwrap()
+        };
+
+        assert!(largest_step(None) > 1000);
+        for &curve in [CrossfadeCurve::Linear, CrossfadeCurve::EqualPower].iter() {
+            let step = largest_step(Some(Crossfade {
+                length: 512,
+                curve: curve,
+            }));
+            assert!(step < 200, "{:?} step {}", curve, step);
+        }
+
+        // Played without looping, the song after the loop end is left alone,
+        // even in the frame the loop ends in. The crossfaded tail steps back
+        // to the song there, which costs some quantization error, but nothing
+        // like the ~5000 it would be off by if the loop start had been copied in
+        let end = 10000;
+        let adx = encode(end, Some(Crossfade {
+            length: 512,
+            curve: CrossfadeCurve::EqualPower,
+        }));
+        let output: Vec<i16> = from_reader(Cursor::new(adx), false).unwrap().map(|sample| sample[0]).collect();
+        for idx in end..end + 64 {
+            assert!((output[idx] as i32 - input[idx] as i32).abs() < 500, "sample {}: {} became {}", idx, input[idx], output[idx]);
+        }
+    }
+
+    #[test]
+    fn highpass_search() {
+        let error = |input: &[i16], highpass_frequency| {
+            let spec = AdxSpec {
+                highpass_frequency: highpass_frequency,
+                ..MONO
+            };
+            let (adx, _) = encode_with(spec, mono(input), |_| {});
+            let decoder = from_reader(Cursor::new(adx), false).unwrap();
+            decoder.zip(input.iter()).map(|(y, &x)| (y[0] as i64 - x as i64).pow(2)).sum::<i64>()
+        };
+
+        let bass: Vec<i16> = (0..8000)
+            .map(|i| ((i as f64 * 0.004).sin() * 12000.0 + (i as f64 * 0.011).sin() * 6000.0) as i16)
+            .collect();
+        let bright: Vec<i16> = (0..8000)
+            .map(|i| ((i as f64 * 0.4).sin() * 6000.0 + (i as f64 * 0.7).sin() * 3000.0) as i16)
+            .collect();
+
+        // The decoder has to pick up the cutoff from the header for the
+        // search to pay off
+        for input in [bass, bright].iter() {
+            let frequency = best_highpass_frequency(&mono(input), 1, 32000);
+            assert!(error(input, frequency) <= error(input, DEFAULT_HIGHPASS_FREQUENCY));
+        }
+    }
+
+    #[test]
+    fn stats() {
+        let input: Vec<Sample> = (0..5000)
+            .map(|i| {
+                let sample = ((i as f64 * 0.02).sin() * 8000.0 + (i as f64 * 0.7).sin() * 3000.0) as i16;
+                vec![sample, sample / 4]
+            })
+            .collect();
+        let spec = AdxSpec {
+            channels: 2,
+            sample_rate: 44100,
+            loop_info: Some(LoopInfo {
+                start_sample: 1024,
+                end_sample: 4500,
+            }),
+            highpass_frequency: DEFAULT_HIGHPASS_FREQUENCY,
+        };
+
+        let (adx, stats) = encode_with(spec, input.iter().cloned(), |encoder| encoder.set_quality(Quality::Trellis));
+        assert_eq!(stats.samples, 5000);
+        assert_eq!(stats.bytes, adx.len() as u64);
+        assert_eq!(stats.scale_histogram.iter().sum::<u64>(), 157 * 2);
+
+        // The SNR should be what decoding it gets
+        let output: Vec<Sample> = from_reader(Cursor::new(adx), false).unwrap().collect();
+        for channel in 0..2 {
+            let snr = measure_snr(&input, &output, channel);
+            assert!((stats.snr[channel] - snr).abs() < 1e-9, "{} {}", stats.snr[channel], snr);
+        }
+    }
+
+    #[test]
+    fn cancel() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut buf = Cursor::new(Vec::new());
+        {
+            let mut encoder = StandardEncoder::new(&mut buf, MONO).unwrap();
+            let flag = cancel.clone();
+            encoder.set_progress(Progress::new()
+                .total(5000)
+                .cancel_flag(cancel.clone())
+                .callback(move |processed, total| {
+                    assert_eq!(total, Some(5000));
+                    if processed >= 1000 {
+                        flag.store(true, Ordering::SeqCst);
+                    }
+                }));
+            let mut input = (0..5000).map(|i| vec![((i as f64 * 0.02).sin() * 8000.0) as i16]);
+            match encoder.encode_data(input.by_ref()) {
+                Err(RadxError::Cancelled) => {}
+                other => panic!("{:?}", other),
+            }
+            // Nothing past the frame it stopped at was taken
+            assert_eq!(input.next(), Some(vec![((1024.0f64 * 0.02).sin() * 8000.0) as i16]));
+            assert_eq!(encoder.finish().unwrap().samples, 1024);
+        }
+
+        // What was encoded still makes a whole stream
+        let decoder = from_reader(Cursor::new(buf.into_inner()), false).unwrap();
+        assert_eq!(decoder.total_samples(), Some(1024));
+        assert_eq!(decoder.count(), 1024);
+    }
+
+    #[test]
+    fn threads() {
+        // Long enough for the channels to be split into segments, with a
+        // loop and a partial frame at the end
+        let input: Vec<Sample> = (0..26001)
+            .map(|i| {
+                let tone = (i as f64 * 0.031).sin() * 9000.0 + (i as f64 * 0.7).sin() * 1500.0;
+                let noise = ((i * 7919) % 2000) as i16 - 1000;
+                vec![tone as i16 + noise, (tone / 2.0) as i16 - noise]
+            })
+            .collect();
+        let spec = AdxSpec {
+            channels: 2,
+            sample_rate: 44100,
+            loop_info: Some(LoopInfo {
+                start_sample: 24000,
+                end_sample: 25500,
+            }),
+            highpass_frequency: DEFAULT_HIGHPASS_FREQUENCY,
+        };
+        let encode = |threads, quality| {
+            encode_with(spec, input.iter().cloned(), |encoder| {
+                encoder.set_threads(threads);
+                encoder.set_quality(quality);
+                encoder.set_noise_shaping(NoiseShaping::light());
+            }).0
+        };
+
+        for &quality in [Quality::Fast, Quality::Best, Quality::Trellis].iter() {
+            let single = encode(1, quality);
+            assert!(encode(2, quality) == single, "{:?} on 2 threads", quality);
+            assert!(encode(8, quality) == single, "{:?} on 8 threads", quality);
+        }
+    }
+
+    #[test]
+    fn streaming() {
+        let input: Vec<Sample> = (0..5000)
+            .map(|i| {
+                let sample = ((i as f64 * 0.02).sin() * 8000.0) as i16;
+                vec![sample, sample / 3]
+            })
+            .collect();
+        let spec = AdxSpec {
+            channels: 2,
+            sample_rate: 44100,
+            loop_info: Some(LoopInfo {
+                start_sample: 1000,
+                end_sample: 4500,
+            }),
+            highpass_frequency: DEFAULT_HIGHPASS_FREQUENCY,
+        };
+
+        let (seekable, _) = encode_with(spec, input.iter().cloned(), |_| {});
+
+        // A Vec can't seek
+        let mut streamed = Vec::new();
+        {
+            let mut encoder = StandardEncoder::new_streaming(&mut streamed, spec, 5000).unwrap();
+            encoder.encode_data(input.iter().cloned()).unwrap();
+            encoder.finish().unwrap();
+        }
+        assert_eq!(streamed, seekable);
+
+        let mut encoder = StandardEncoder::new_streaming(Vec::new(), spec, 4000).unwrap();
+        match encoder.encode_data(input.iter().cloned()) {
+            Err(RadxError::SampleCountMismatch(4000, 4001)) => {}
+            other => panic!("{:?}", other),
+        }
+        let mut encoder = StandardEncoder::new_streaming(Vec::new(), spec, 5000).unwrap();
+        encoder.encode_data(input[..10].iter().cloned()).unwrap();
+        match encoder.finish() {
+            Err(RadxError::SampleCountMismatch(5000, 10)) => {}
+            other => panic!("{:?}", other),
+        }
     }
 }
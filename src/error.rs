@@ -1,42 +1,82 @@
+#[cfg(feature = "std")]
 use std::error::Error;
 use std::fmt;
+#[cfg(feature = "std")]
 use std::io;
 
 #[derive(Debug)]
 pub enum RadxError {
+    #[cfg(feature = "std")]
     IoError(io::Error),
+    UnexpectedEof,
     BadAhxFrameHeader,
     BadAdxHeader(&'static str),
+    BadMp2FrameHeader(&'static str),
+    BadMp2FrameCrc,
+    BadDecoderState(&'static str),
+    /// A streaming encoder got a different number of samples than it was
+    /// told to expect: the declared count, then the count given. Too many
+    /// stops at the first one over.
+    SampleCountMismatch(u32, u32),
+    /// `to_writer` was given options that don't go together.
+    BadEncoderOptions(&'static str),
+    /// Work stopped because a `Progress` cancel flag was set.
+    Cancelled,
 }
 
 impl fmt::Display for RadxError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
+            #[cfg(feature = "std")]
             RadxError::IoError(ref err) => write!(f, "IO Error: {}", err),
+            RadxError::UnexpectedEof => write!(f, "unexpected end of data"),
             RadxError::BadAhxFrameHeader => write!(f, "bad ahx frame header"),
             RadxError::BadAdxHeader(reason) => write!(f, "bad adx header: {}", reason),
+            RadxError::BadMp2FrameHeader(reason) => write!(f, "bad mp2 frame header: {}", reason),
+            RadxError::BadMp2FrameCrc => write!(f, "bad mp2 frame crc"),
+            RadxError::BadDecoderState(reason) => write!(f, "bad decoder state: {}", reason),
+            RadxError::SampleCountMismatch(declared, given) =>
+                write!(f, "expected {} samples but got {}", declared, given),
+            RadxError::BadEncoderOptions(reason) => write!(f, "bad encoder options: {}", reason),
+            RadxError::Cancelled => write!(f, "cancelled"),
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl Error for RadxError {
     fn description(&self) -> &str {
         match *self {
             RadxError::IoError(ref err) => err.description(),
+            RadxError::UnexpectedEof => "unexpected end of data",
             RadxError::BadAhxFrameHeader => "bad ahx frame header",
             RadxError::BadAdxHeader(reason) => reason,
+            RadxError::BadMp2FrameHeader(reason) => reason,
+            RadxError::BadMp2FrameCrc => "bad mp2 frame crc",
+            RadxError::BadDecoderState(reason) => reason,
+            RadxError::SampleCountMismatch(..) => "sample count doesn't match the declared total",
+            RadxError::BadEncoderOptions(reason) => reason,
+            RadxError::Cancelled => "cancelled",
         }
     }
 
     fn cause(&self) -> Option<&Error> {
         match *self {
             RadxError::IoError(ref err) => Some(err),
+            RadxError::UnexpectedEof => None,
             RadxError::BadAhxFrameHeader => None,
             RadxError::BadAdxHeader(_) => None,
+            RadxError::BadMp2FrameHeader(_) => None,
+            RadxError::BadMp2FrameCrc => None,
+            RadxError::BadDecoderState(_) => None,
+            RadxError::SampleCountMismatch(..) => None,
+            RadxError::BadEncoderOptions(_) => None,
+            RadxError::Cancelled => None,
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl From<io::Error> for RadxError {
     fn from(err: io::Error) -> Self {
         RadxError::IoError(err)
@@ -1,20 +1,52 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(feature = "std"))]
+extern crate core as std;
+#[cfg_attr(not(feature = "std"), macro_use)]
+extern crate alloc;
 extern crate byteorder;
+#[cfg(feature = "std")]
 #[macro_use]
 extern crate lazy_static;
 
 pub mod adx_header;
 mod adx_reader;
+#[cfg(feature = "std")]
 mod adx_writer;
+#[cfg(feature = "std")]
+pub mod convert;
 pub mod decoder;
+#[cfg(feature = "std")]
 pub mod encoder;
 pub mod error;
+#[cfg(feature = "std")]
+pub mod loudness;
+mod math;
+#[cfg(test)]
+mod noise;
+pub mod progress;
+pub mod source;
+#[cfg(feature = "std")]
+pub mod wav_stream;
 
-use std::io::{Read, Seek};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use std::f64;
+#[cfg(feature = "std")]
+use std::io::{Write, Seek};
 
 use adx_header::{AdxHeader, AdxEncoding};
-use decoder::{Decoder, StandardDecoder, AhxDecoder};
-use error::RadxResult;
+use decoder::{Decoder, StandardDecoder, AhxDecoder, Mp2Decoder};
+#[cfg(feature = "std")]
+use encoder::{Encoder, EncoderOptions, Codec, Normalize, Reformat};
+#[cfg(feature = "std")]
+use encoder::standard_encoder::StandardEncoder;
+#[cfg(feature = "std")]
+use encoder::ahx_encoder::AhxEncoder;
+#[cfg(feature = "std")]
+use encoder::mp2_encoder::Mp2Encoder;
+use error::{RadxResult, RadxError};
+use source::{SeekSource, SliceReader};
 
 #[derive(Clone,Copy,Debug)]
 pub struct LoopInfo {
@@ -27,14 +59,54 @@ pub struct AdxSpec {
     pub channels: u32,
     pub sample_rate: u32,
     pub loop_info: Option<LoopInfo>,
+    /// Cutoff in Hz the ADPCM predictor is tuned to. Usually
+    /// `DEFAULT_HIGHPASS_FREQUENCY`, though
+    /// `standard_encoder::best_highpass_frequency` can find one that suits
+    /// the audio better.
+    pub highpass_frequency: u16,
 }
 
+/// The predictor cutoff nearly every ADX file uses.
+pub const DEFAULT_HIGHPASS_FREQUENCY: u16 = 500;
+
 type Sample = Vec<i16>;
 
-pub fn from_reader<R>(mut reader: R, looping: bool) -> RadxResult<Box<Decoder>>
-    where R: Seek + Read + 'static
+/// Combined delay of the AHX analysis and synthesis filterbanks, in samples.
+///
+/// The encoder flushes this many extra samples through the filterbank and
+/// records it in the AHX header, so the decoder can drop them from the start
+/// and `total_samples` lines up with the original input.
+#[cfg(feature = "std")]
+const AHX_DELAY: usize = 480;
+
+pub fn from_reader<R>(reader: R, looping: bool) -> RadxResult<Box<Decoder>>
+    where R: SeekSource + 'static
+{
+    from_source(reader, looping)
+}
+
+/// Makes a decoder that works straight out of `data`, which should hold a
+/// whole file.
+pub fn from_slice<'a>(data: &'a [u8], looping: bool) -> RadxResult<Box<Decoder + 'a>> {
+    from_source(SliceReader::new(data), looping)
+}
+
+fn from_source<'a, R>(mut reader: R, looping: bool) -> RadxResult<Box<Decoder + 'a>>
+    where R: SeekSource + 'a
 {
-    let header = AdxHeader::read_header(&mut reader)?;
+    let header = match AdxHeader::read_header_from(&mut reader) {
+        Ok(header) => header,
+        // Not an adx file, but it might still be a plain mp2 stream
+        Err(RadxError::BadAdxHeader(reason)) => {
+            reader.seek_to(0)?;
+            return match Mp2Decoder::new(reader) {
+                Ok(decoder) => Ok(Box::new(decoder)),
+                Err(_) => Err(RadxError::BadAdxHeader(reason)),
+            };
+        }
+        Err(err) => return Err(err),
+    };
+
     match header.encoding {
         AdxEncoding::Standard =>
             Ok(Box::new(StandardDecoder::from_header(header, reader, looping))),
@@ -44,12 +116,56 @@ pub fn from_reader<R>(mut reader: R, looping: bool) -> RadxResult<Box<Decoder>>
     }
 }
 
+/// Makes an encoder for whichever codec `options` asks for. The encoder goes
+/// back to fill in the header when it finishes, so `writer` has to seek.
+#[cfg(feature = "std")]
+pub fn to_writer<'a, W>(writer: W, options: &EncoderOptions) -> RadxResult<Box<Encoder + 'a>>
+    where W: Write + Seek + 'a
+{
+    options.validate()?;
+
+    let encoder: Box<Encoder + 'a> = match options.codec {
+        Codec::Standard => {
+            let spec = AdxSpec {
+                channels: options.channels,
+                sample_rate: options.sample_rate,
+                loop_info: options.loop_info,
+                highpass_frequency: options.highpass_frequency,
+            };
+            let mut encoder = StandardEncoder::new(writer, spec)?;
+            encoder.set_version(options.version);
+            encoder.set_quality(options.quality);
+            encoder.set_noise_shaping(options.noise_shaping);
+            encoder.set_loop_crossfade(options.crossfade);
+            encoder.set_match_loop_entry(options.match_loop_entry);
+            encoder.set_threads(options.threads);
+            Box::new(encoder)
+        }
+        Codec::Ahx => {
+            let encoder = Box::new(AhxEncoder::new(writer)?);
+            if options.channels == 1 && options.sample_rate == 22050 {
+                encoder
+            }
+            else {
+                Box::new(Reformat::new(encoder, options.channels, options.sample_rate, 22050))
+            }
+        }
+        Codec::Mp2 => Box::new(Mp2Encoder::new(writer, options.mp2_profile()?)),
+    };
+
+    // Loudness is measured on the input as it comes, before any mixing down
+    match options.normalization {
+        Some(normalization) => Ok(Box::new(Normalize::new(encoder, options.channels, options.sample_rate, normalization))),
+        None => Ok(encoder),
+    }
+}
+
 /// Returns 12-bit fixed-point coefficients.
 fn gen_coeffs(highpass_frequency: u32, sample_rate: u32) -> (i32, i32) {
     let highpass_samples = highpass_frequency as f64 / sample_rate as f64;
-    let a = f64::consts::SQRT_2 - (2.0 * f64::consts::PI * highpass_samples).cos();
+    let a = f64::consts::SQRT_2 - math::cos(2.0 * f64::consts::PI * highpass_samples);
     let b = f64::consts::SQRT_2 - 1.0;
-    let c = (a - ((a + b) * (a - b)).sqrt()) / b;
+    let c = (a - math::sqrt((a + b) * (a - b))) / b;
 
     let coeff1 = c * 2.0;
     let coeff2 = -(c * c);
@@ -60,7 +176,199 @@ fn gen_coeffs(highpass_frequency: u32, sample_rate: u32) -> (i32, i32) {
 
 #[cfg(test)]
 mod tests {
+    use alloc::vec::Vec;
+    use std::f64;
+
+    use math;
+
     #[test]
     fn it_works() {
     }
+
+    #[test]
+    fn from_slice() {
+        // Checked in rather than encoded here, so this runs without std too.
+        // Every channel holds 2000 samples of sin(i * 0.05) * 8000.
+        let expected: Vec<i16> = (0..2000)
+            .map(|i| (math::cos(i as f64 * 0.05 - f64::consts::FRAC_PI_2) * 8000.0) as i16)
+            .collect();
+        let files: [(&[u8], u32, i32); 2] = [
+            (include_bytes!("../testdata/tone.adx"), 2, 160),
+            (include_bytes!("../testdata/tone.ahx"), 1, 512),
+        ];
+        for &(data, channels, max_error) in files.iter() {
+            let decoder = super::from_slice(data, false).unwrap();
+            assert_eq!((decoder.channels(), decoder.sample_rate()), (channels, 22050));
+            assert_eq!(decoder.total_samples(), Some(2000));
+            let output: Vec<_> = decoder.collect();
+            assert_eq!(output.len(), expected.len());
+            for (&x, sample) in expected.iter().zip(output.iter()) {
+                for &y in sample.iter() {
+                    assert!((x as i32 - y as i32).abs() < max_error, "{} became {}", x, y);
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn from_slice_matches_reader() {
+        use std::io::Cursor;
+        use encoder::standard_encoder::StandardEncoder;
+        use {AdxSpec, DEFAULT_HIGHPASS_FREQUENCY};
+
+        let spec = AdxSpec {
+            channels: 1,
+            sample_rate: 22050,
+            loop_info: None,
+            highpass_frequency: DEFAULT_HIGHPASS_FREQUENCY,
+        };
+        let mut buf = Cursor::new(Vec::new());
+        {
+            let mut encoder = StandardEncoder::new(&mut buf, spec).unwrap();
+            encoder.encode_data((0..2000).map(|i| vec![((i as f64 * 0.01).sin() * 8000.0) as i16])).unwrap();
+            encoder.finish().unwrap();
+        }
+        let data = buf.into_inner();
+
+        let from_slice: Vec<_> = super::from_slice(&data, false).unwrap().collect();
+        let from_reader: Vec<_> = super::from_reader(Cursor::new(data.clone()), false).unwrap().collect();
+        assert_eq!(from_slice.len(), 2000);
+        assert_eq!(from_slice, from_reader);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn to_writer() {
+        use std::io::Cursor;
+        use encoder::{Codec, EncoderOptions};
+        use error::RadxError;
+        use LoopInfo;
+
+        let input: Vec<Vec<i16>> = (0..3000).map(|i| vec![((i as f64 * 0.03).sin() * 6000.0) as i16]).collect();
+        let options = [
+            EncoderOptions::new().channels(1).sample_rate(22050),
+            EncoderOptions::new().channels(1).sample_rate(22050).version(4),
+            EncoderOptions::new().channels(1).sample_rate(22050).codec(Codec::Ahx),
+        ];
+        for options in options.iter() {
+            let mut buf = Cursor::new(Vec::new());
+            {
+                let mut encoder = super::to_writer(&mut buf, options).unwrap();
+                encoder.encode_samples(&input).unwrap();
+                let stats = encoder.finish().unwrap();
+                assert_eq!(stats.samples, 3000);
+            }
+            let decoder = super::from_reader(Cursor::new(buf.into_inner()), false).unwrap();
+            assert_eq!(decoder.total_samples(), Some(3000));
+        }
+
+        // AHX mixes down and resamples anything else to mono at 22050 Hz
+        let stereo: Vec<Vec<i16>> = input.iter().map(|sample| vec![sample[0], sample[0] / 2]).collect();
+        let options = EncoderOptions::new().sample_rate(44100).codec(Codec::Ahx);
+        let mut buf = Cursor::new(Vec::new());
+        {
+            let mut encoder = super::to_writer(&mut buf, &options).unwrap();
+            encoder.encode_samples(&stereo).unwrap();
+            assert_eq!(encoder.finish().unwrap().samples, 1500);
+        }
+        let decoder = super::from_reader(Cursor::new(buf.into_inner()), false).unwrap();
+        assert_eq!((decoder.channels(), decoder.sample_rate()), (1, 22050));
+
+        // Plain mp2 keeps the channels and sample rate, starting with the
+        // filterbank delay and ending on a whole frame
+        let options = EncoderOptions::new().sample_rate(44100).codec(Codec::Mp2);
+        let mut buf = Cursor::new(Vec::new());
+        {
+            let mut encoder = super::to_writer(&mut buf, &options).unwrap();
+            encoder.encode_samples(&stereo).unwrap();
+            encoder.finish().unwrap();
+        }
+        let decoder = super::from_reader(Cursor::new(buf.into_inner()), false).unwrap();
+        assert_eq!((decoder.channels(), decoder.sample_rate()), (2, 44100));
+        assert_eq!(decoder.count(), 4 * 1152);
+
+        let odd_rate = EncoderOptions::new().sample_rate(11025).codec(Codec::Mp2);
+        match super::to_writer(Cursor::new(Vec::new()), &odd_rate) {
+            Err(RadxError::BadEncoderOptions(_)) => {}
+            Err(err) => panic!("{:?}", err),
+            Ok(_) => panic!("mp2 at 11025 Hz"),
+        }
+
+        let looping = EncoderOptions::new().version(5).loop_info(Some(LoopInfo {
+            start_sample: 0,
+            end_sample: 100,
+        }));
+        match super::to_writer(Cursor::new(Vec::new()), &looping) {
+            Err(RadxError::BadEncoderOptions(_)) => {}
+            Err(err) => panic!("{:?}", err),
+            Ok(_) => panic!("looping version 5 header"),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn normalize() {
+        use std::io::Cursor;
+        use encoder::EncoderOptions;
+        use loudness::{self, LoudnessMeter, Normalization};
+
+        // A 1 kHz sine at -35 dBFS in both channels, so -35 LUFS
+        let amplitude = 10.0f64.powf(-35.0 / 20.0) * 32768.0;
+        let input: Vec<Vec<i16>> = (0..44100 * 3)
+            .map(|i| vec![((i as f64 * 2.0 * f64::consts::PI * 1000.0 / 44100.0).sin() * amplitude) as i16; 2])
+            .collect();
+
+        let encode_bytes = |input: &[Vec<i16>], normalization: Option<Normalization>| {
+            let options = EncoderOptions::new().normalize(normalization);
+            let mut buf = Cursor::new(Vec::new());
+            {
+                let mut encoder = super::to_writer(&mut buf, &options).unwrap();
+                encoder.encode_samples(input).unwrap();
+                encoder.finish().unwrap();
+            }
+            buf.into_inner()
+        };
+        let encode = |target: f64| {
+            let adx = encode_bytes(&input, Some(Normalization {
+                target: target,
+                peak_ceiling: -1.0,
+            }));
+            let mut decoder = super::from_reader(Cursor::new(adx), false).unwrap();
+            loudness::measure(&mut *decoder).unwrap()
+        };
+
+        let normalized = encode(-16.0);
+        assert!((normalized.integrated + 16.0).abs() < 0.2, "{:?}", normalized);
+
+        // Getting this one to 0 LUFS would peak at 0 dBTP
+        let limited = encode(0.0);
+        assert!((limited.true_peak + 1.0).abs() < 0.2, "{:?}", limited);
+
+        // At 0 dB of gain the samples go through untouched, without dither
+        let mut meter = LoudnessMeter::new(2, 44100);
+        meter.add_samples(&input);
+        let silence = vec![vec![0i16; 2]; 44100];
+        for &(samples, target) in [(&input, meter.loudness().integrated), (&silence, -16.0)].iter() {
+            let normalization = Normalization {
+                target: target,
+                peak_ceiling: 0.0,
+            };
+            assert!(encode_bytes(samples, Some(normalization)) == encode_bytes(samples, None), "target {}", target);
+        }
+    }
+
+    #[test]
+    fn coeffs_match_std() {
+        for &sample_rate in &[8000, 11025, 16000, 22050, 24000, 32000, 44100, 48000, 96000] {
+            for highpass_frequency in 0..2000 {
+                let highpass_samples = highpass_frequency as f64 / sample_rate as f64;
+                let a = f64::consts::SQRT_2 - (2.0 * f64::consts::PI * highpass_samples).cos();
+                let b = f64::consts::SQRT_2 - 1.0;
+                let c = (a - ((a + b) * (a - b)).sqrt()) / b;
+                let expected = (((c * 2.0 * 4096.0) + 0.5) as i32, ((-(c * c) * 4096.0) + 0.5) as i32);
+                assert_eq!(super::gen_coeffs(highpass_frequency, sample_rate), expected);
+            }
+        }
+    }
 }
@@ -0,0 +1,333 @@
+//! Loudness and true peak measurement following ITU-R BS.1770-4, for
+//! normalizing tracks on the way into an encoder and for checking files
+//! that are already encoded.
+
+use std::f64;
+
+use convert::bessel_i0;
+use decoder::Decoder;
+use error::RadxResult;
+use Sample;
+
+/// Gating blocks are 400 ms long and start every 100 ms, so each one is made
+/// of this many 100 ms steps.
+const STEPS_PER_BLOCK: usize = 4;
+
+/// Blocks quieter than this, in LUFS, are left out entirely.
+const ABSOLUTE_GATE: f64 = -70.0;
+
+/// Blocks this many LU below the loudness of the blocks through the absolute
+/// gate are left out too.
+const RELATIVE_GATE: f64 = -10.0;
+
+/// How many times over the true peak filter oversamples.
+const OVERSAMPLING: usize = 4;
+
+/// True peak filter taps for each oversampled phase.
+const PEAK_TAPS: usize = 12;
+
+/// Kaiser window shape for the true peak filter.
+const PEAK_KAISER_BETA: f64 = 7.0;
+
+/// Loudness of a whole track.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS. Negative infinity when every block was
+    /// gated out, as for silence or anything shorter than 400 ms.
+    pub integrated: f64,
+    /// True peak in dB relative to full scale, dBTP. Negative infinity for
+    /// silence.
+    pub true_peak: f64,
+}
+
+/// Second order IIR filter, direct form I.
+#[derive(Clone,Debug)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+        Biquad {
+            b: b,
+            a: a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// The K-weighting filter: a high shelf for the head's effect on sound,
+/// then a highpass for the ear's lack of bass. BS.1770 gives coefficients
+/// at 48000 Hz; these are the analog prototypes they came from, so any
+/// sample rate works.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let sample_rate = sample_rate as f64;
+
+    let k = (f64::consts::PI * 1681.974450955533 / sample_rate).tan();
+    let q = 0.7071752369554196;
+    let vh = 10.0f64.powf(3.999843853973347 / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new([(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+                            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+
+    let k = (f64::consts::PI * 38.13547087602444 / sample_rate).tan();
+    let q = 0.5003270373238773;
+    let a0 = 1.0 + k / q + k * k;
+    let highpass = Biquad::new([1.0, -2.0, 1.0], [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+
+    [shelf, highpass]
+}
+
+/// How much each channel counts towards loudness. Surround channels of a
+/// 5.1 layout count for more, and its LFE not at all.
+fn channel_weight(channels: usize, channel: usize) -> f64 {
+    match (channels, channel) {
+        (6, 3) => 0.0,
+        (6, 4) | (6, 5) | (5, 3) | (5, 4) => 1.41,
+        _ => 1.0,
+    }
+}
+
+/// Measures loudness and true peak a block of samples at a time.
+#[derive(Clone,Debug)]
+pub struct LoudnessMeter {
+    filters: Vec<[Biquad; 2]>,
+    weights: Vec<f64>,
+    /// Samples in each 100 ms step.
+    step_len: usize,
+    /// Samples into the current step.
+    step_idx: usize,
+    /// Weighted energy of the current step so far.
+    step_energy: f64,
+    /// Weighted energy of every finished step.
+    steps: Vec<f64>,
+    /// Oversampling filter, phase by phase.
+    peak_filter: [[f64; PEAK_TAPS]; OVERSAMPLING],
+    /// Last `PEAK_TAPS` samples of each channel, newest first.
+    peak_history: Vec<[f64; PEAK_TAPS]>,
+    peak: f64,
+}
+
+impl LoudnessMeter {
+    pub fn new(channels: usize, sample_rate: u32) -> LoudnessMeter {
+        // Windowed sinc at the original Nyquist frequency. Phase 0 lands on
+        // the input samples themselves.
+        let center = (OVERSAMPLING * PEAK_TAPS / 2) as f64;
+        let mut peak_filter = [[0.0; PEAK_TAPS]; OVERSAMPLING];
+        for (phase, taps) in peak_filter.iter_mut().enumerate() {
+            for (tap, coeff) in taps.iter_mut().enumerate() {
+                let offset = (tap * OVERSAMPLING + phase) as f64 - center;
+                let x = offset / OVERSAMPLING as f64;
+                let sinc = if x == 0.0 { 1.0 } else { (f64::consts::PI * x).sin() / (f64::consts::PI * x) };
+                let position = offset / center;
+                let window = if position.abs() < 1.0 {
+                    bessel_i0(PEAK_KAISER_BETA * (1.0 - position * position).sqrt()) / bessel_i0(PEAK_KAISER_BETA)
+                }
+                else {
+                    0.0
+                };
+                *coeff = sinc * window;
+            }
+        }
+
+        LoudnessMeter {
+            filters: (0..channels).map(|_| k_weighting(sample_rate)).collect(),
+            weights: (0..channels).map(|channel| channel_weight(channels, channel)).collect(),
+            step_len: ((sample_rate as f64 * 0.1).round() as usize).max(1),
+            step_idx: 0,
+            step_energy: 0.0,
+            steps: Vec::new(),
+            peak_filter: peak_filter,
+            peak_history: vec![[0.0; PEAK_TAPS]; channels],
+            peak: 0.0,
+        }
+    }
+
+    /// Measures `samples`, each holding a value for every channel.
+    pub fn add_samples(&mut self, samples: &[Sample]) {
+        for sample in samples {
+            for (channel, &value) in sample.iter().enumerate().take(self.filters.len()) {
+                let value = value as f64 / 32768.0;
+
+                let filters = &mut self.filters[channel];
+                let shelved = filters[0].process(value);
+                let weighted = filters[1].process(shelved);
+                self.step_energy += self.weights[channel] * weighted * weighted;
+
+                let history = &mut self.peak_history[channel];
+                for idx in (1..PEAK_TAPS).rev() {
+                    history[idx] = history[idx - 1];
+                }
+                history[0] = value;
+                for taps in self.peak_filter.iter() {
+                    let oversampled: f64 = taps.iter().zip(history.iter()).map(|(tap, x)| tap * x).sum();
+                    self.peak = self.peak.max(oversampled.abs());
+                }
+            }
+
+            self.step_idx += 1;
+            if self.step_idx == self.step_len {
+                self.steps.push(self.step_energy);
+                self.step_idx = 0;
+                self.step_energy = 0.0;
+            }
+        }
+    }
+
+    /// Loudness of everything measured so far. Samples short of a whole
+    /// 100 ms step at the end aren't counted towards integrated loudness.
+    pub fn loudness(&self) -> Loudness {
+        let block_len = (self.step_len * STEPS_PER_BLOCK) as f64;
+        let blocks: Vec<f64> = self.steps
+            .windows(STEPS_PER_BLOCK)
+            .map(|steps| steps.iter().sum::<f64>() / block_len)
+            .collect();
+
+        let gated_mean = |threshold: f64| {
+            let (sum, count) = blocks.iter()
+                .filter(|&&energy| block_loudness(energy) > threshold)
+                .fold((0.0, 0), |(sum, count), &energy| (sum + energy, count + 1));
+            if count == 0 { 0.0 } else { sum / count as f64 }
+        };
+        let relative_gate = block_loudness(gated_mean(ABSOLUTE_GATE)) + RELATIVE_GATE;
+
+        Loudness {
+            integrated: block_loudness(gated_mean(relative_gate.max(ABSOLUTE_GATE))),
+            true_peak: 20.0 * self.peak.log10(),
+        }
+    }
+}
+
+/// Loudness in LUFS of a block with weighted mean square `energy`.
+fn block_loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+/// Measures a whole stream through its decoder, which should have been made
+/// without looping. Fails with `RadxError::Cancelled` if the decoder's
+/// progress cancels it, rather than measuring only part of the stream.
+pub fn measure<D>(decoder: &mut D) -> RadxResult<Loudness>
+    where D: Decoder + ?Sized
+{
+    let mut meter = LoudnessMeter::new(decoder.channels() as usize, decoder.sample_rate());
+    let mut block = Vec::with_capacity(4096);
+    while let Some(sample) = decoder.try_next_sample()? {
+        block.push(sample);
+        if block.len() == 4096 {
+            meter.add_samples(&block);
+            block.clear();
+        }
+    }
+    meter.add_samples(&block);
+    Ok(meter.loudness())
+}
+
+/// Where `EncoderOptions::normalize` takes a track.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Normalization {
+    /// Integrated loudness to aim for, in LUFS.
+    pub target: f64,
+    /// Highest true peak allowed afterwards, in dBTP. Tracks that would go
+    /// over it at the target loudness come out quieter instead.
+    pub peak_ceiling: f64,
+}
+
+impl Normalization {
+    /// Gain in dB that takes a track measuring `loudness` to the target
+    /// without its true peak going over the ceiling. No gain for silence.
+    pub fn gain(&self, loudness: &Loudness) -> f64 {
+        if loudness.true_peak == f64::NEG_INFINITY {
+            return 0.0;
+        }
+
+        let peak_gain = self.peak_ceiling - loudness.true_peak;
+        if loudness.integrated == f64::NEG_INFINITY {
+            // Too short or quiet to gate, so only the ceiling applies
+            peak_gain.min(0.0)
+        }
+        else {
+            (self.target - loudness.integrated).min(peak_gain)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64;
+
+    use super::{LoudnessMeter, Loudness, Normalization};
+
+    fn sine(sample_rate: u32, seconds: f64, freq: f64, dbfs: f64, phase: f64) -> Vec<Vec<i16>> {
+        let amplitude = 10.0f64.powf(dbfs / 20.0) * 32768.0;
+        (0..(sample_rate as f64 * seconds) as usize).map(|i| {
+            let value = (i as f64 * 2.0 * f64::consts::PI * freq / sample_rate as f64 + phase).sin() * amplitude;
+            vec![value.round() as i16; 2]
+        }).collect()
+    }
+
+    #[test]
+    fn sine_loudness() {
+        // EBU Tech 3341 case 1: a 1 kHz sine at -23 dBFS in both channels
+        // is -23 LUFS
+        for &sample_rate in &[32000, 44100, 48000] {
+            let mut meter = LoudnessMeter::new(2, sample_rate);
+            meter.add_samples(&sine(sample_rate, 5.0, 1000.0, -23.0, 0.0));
+            let loudness = meter.loudness();
+            assert!((loudness.integrated + 23.0).abs() < 0.1, "{} Hz: {:?}", sample_rate, loudness);
+        }
+
+        // Silence around it gets gated out. Blocks overlapping the edges of
+        // the tone still count, which pulls it down a touch.
+        let mut meter = LoudnessMeter::new(2, 48000);
+        meter.add_samples(&sine(48000, 2.0, 1000.0, -90.0, 0.0));
+        meter.add_samples(&sine(48000, 10.0, 1000.0, -23.0, 0.0));
+        meter.add_samples(&vec![vec![0, 0]; 48000 * 2]);
+        assert!((meter.loudness().integrated + 23.0).abs() < 0.2, "{:?}", meter.loudness());
+    }
+
+    #[test]
+    fn true_peak() {
+        // A quarter of the sample rate, 45 degrees off, only ever samples
+        // at 3 dB below its real peak
+        let mut meter = LoudnessMeter::new(2, 44100);
+        meter.add_samples(&sine(44100, 1.0, 11025.0, -6.0, f64::consts::PI / 4.0));
+        let true_peak = meter.loudness().true_peak;
+        assert!((true_peak + 6.0).abs() < 0.5, "true peak {}", true_peak);
+    }
+
+    #[test]
+    fn normalization_gain() {
+        let normalization = Normalization {
+            target: -16.0,
+            peak_ceiling: -1.0,
+        };
+        let quiet = Loudness {
+            integrated: -30.0,
+            true_peak: -20.0,
+        };
+        assert_eq!(normalization.gain(&quiet), 14.0);
+        let peaky = Loudness {
+            integrated: -20.0,
+            true_peak: -3.0,
+        };
+        assert_eq!(normalization.gain(&peaky), 2.0);
+        let silent = Loudness {
+            integrated: f64::NEG_INFINITY,
+            true_peak: f64::NEG_INFINITY,
+        };
+        assert_eq!(normalization.gain(&silent), 0.0);
+    }
+}
@@ -0,0 +1,90 @@
+//! The little bit of floating point math radx needs, written out so it works
+//! without std and at compile time.
+
+use std::f64::consts::{PI, FRAC_PI_2, TAU};
+
+// 2pi split in two so range reduction stays exact for larger arguments
+const TAU_HI: f64 = TAU;
+const TAU_LO: f64 = 2.4492935982947064e-16;
+
+pub(crate) const fn cos(x: f64) -> f64 {
+    // Bring x into [-pi, pi]
+    let k = if x >= 0.0 {
+        (x / TAU_HI + 0.5) as i64
+    }
+    else {
+        (x / TAU_HI - 0.5) as i64
+    } as f64;
+    let mut r = (x - k * TAU_HI) - k * TAU_LO;
+    if r < 0.0 {
+        r = -r;
+    }
+
+    // cos(x) == -cos(pi - x), so only [0, pi/2] needs the series
+    if r > FRAC_PI_2 {
+        -cos_series((PI - r) + TAU_LO / 2.0)
+    }
+    else {
+        cos_series(r)
+    }
+}
+
+/// Taylor series for cos, for |x| <= pi/2.
+const fn cos_series(x: f64) -> f64 {
+    let x2 = x * x;
+    let mut term = 1.0;
+    let mut sum = 1.0;
+    let mut n = 0;
+    while n < 30 {
+        term = -term * x2 / (((n + 1) * (n + 2)) as f64);
+        sum += term;
+        n += 2;
+    }
+    sum
+}
+
+/// Correctly rounded square root of a normal, positive `x`.
+pub(crate) const fn sqrt(x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+
+    // x == mantissa * 2**exp, with an even exp
+    let bits = x.to_bits();
+    let mut exp = ((bits >> 52) & 0x7ff) as i64 - 1075;
+    let mut mantissa = (bits & ((1 << 52) - 1)) | (1 << 52);
+    if exp & 1 != 0 {
+        mantissa <<= 1;
+        exp -= 1;
+    }
+
+    // Shifted up so the integer root has all 53 bits, then rounded to nearest
+    let n = (mantissa as u128) << 52;
+    let mut root = n.isqrt();
+    if n - root * root > root {
+        root += 1;
+    }
+
+    let scale = f64::from_bits((((exp - 52) / 2 + 1023) as u64) << 52);
+    root as f64 * scale
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn cos_matches_std() {
+        for i in 0..100000 {
+            let x = i as f64 * 0.0025 - 125.0;
+            assert!((super::cos(x) - x.cos()).abs() < 1e-14, "cos({})", x);
+        }
+    }
+
+    #[test]
+    fn sqrt_matches_std() {
+        for i in 1..100000 {
+            let x = i as f64 * 1e-5;
+            assert_eq!(super::sqrt(x), x.sqrt(), "sqrt({})", x);
+            assert_eq!(super::sqrt(x * 12345.0), (x * 12345.0).sqrt());
+        }
+    }
+}
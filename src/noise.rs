@@ -0,0 +1,26 @@
+//! The same white noise every run, for tests and benchmarks.
+
+/// 32 bit xorshift from a fixed seed.
+#[derive(Clone,Debug)]
+pub(crate) struct Noise {
+    state: u32,
+}
+
+impl Noise {
+    pub(crate) fn new() -> Noise {
+        Noise {
+            state: 0x1234_5678,
+        }
+    }
+}
+
+impl Iterator for Noise {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        Some(self.state)
+    }
+}
@@ -0,0 +1,123 @@
+//! Progress reports and cancellation for long encodes and decodes.
+
+use alloc::sync::Arc;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "std")]
+use error::{RadxResult, RadxError};
+
+type Callback = Arc<Fn(u64, Option<u64>) + Send + Sync>;
+
+/// What an encoder or decoder does between frames: reports how far it has
+/// got, and stops if asked to.
+///
+/// Reports are the samples processed so far and the total, if known. A
+/// decoder knows its total unless it loops. An encoder only knows it when
+/// streaming or when it's given here with `total`.
+#[derive(Clone)]
+pub struct Progress {
+    callback: Option<Callback>,
+    cancel: Option<Arc<AtomicBool>>,
+    total: Option<u64>,
+    /// Whether the last check found the cancel flag set.
+    stopped: bool,
+}
+
+impl Progress {
+    /// Reports nothing and never cancels.
+    pub fn new() -> Progress {
+        Progress {
+            callback: None,
+            cancel: None,
+            total: None,
+            stopped: false,
+        }
+    }
+
+    /// Calls `callback` with the samples processed and the total after
+    /// every frame.
+    pub fn callback<F>(mut self, callback: F) -> Progress
+        where F: Fn(u64, Option<u64>) + Send + Sync + 'static
+    {
+        self.callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Stops at the next frame once `flag` is set. Clearing it again lets
+    /// the work carry on from where it stopped.
+    pub fn cancel_flag(mut self, flag: Arc<AtomicBool>) -> Progress {
+        self.cancel = Some(flag);
+        self
+    }
+
+    /// Total to report, for when the encoder or decoder can't tell.
+    pub fn total(mut self, total: u64) -> Progress {
+        self.total = Some(total);
+        self
+    }
+
+    /// Fills in the total if it wasn't given.
+    pub(crate) fn default_total(&mut self, total: Option<u64>) {
+        if self.total.is_none() {
+            self.total = total;
+        }
+    }
+
+    pub(crate) fn report(&self, processed: u64) {
+        if let Some(ref callback) = self.callback {
+            callback(processed, self.total);
+        }
+    }
+
+    /// Reports `processed` samples, then returns whether to stop.
+    pub(crate) fn should_stop(&mut self, processed: u64) -> bool {
+        self.report(processed);
+        self.stopped = self.is_cancelled();
+        self.stopped
+    }
+
+    /// Like `should_stop`, but as an error.
+    #[cfg(feature = "std")]
+    pub(crate) fn check(&mut self, processed: u64) -> RadxResult<()> {
+        if self.should_stop(processed) {
+            Err(RadxError::Cancelled)
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    /// Whether the cancel flag is set right now, without reporting.
+    pub(crate) fn is_cancelled(&self) -> bool {
+        match self.cancel {
+            Some(ref cancel) => cancel.load(Ordering::SeqCst),
+            None => false,
+        }
+    }
+
+    /// Whether `should_stop` has said to stop since the last `resume`.
+    pub(crate) fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub(crate) fn resume(&mut self) {
+        self.stopped = false;
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Progress {
+        Progress::new()
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Progress")
+            .field("callback", &self.callback.is_some())
+            .field("cancel", &self.cancel)
+            .field("total", &self.total)
+            .finish()
+    }
+}
@@ -0,0 +1,189 @@
+//! Where decoders get their bytes from.
+//!
+//! With the `std` feature, anything that implements `Read` is a `Source` and
+//! anything that also implements `Seek` is a `SeekSource`. Without it, byte
+//! slices and `SliceReader` are the only sources.
+
+#[cfg(feature = "std")]
+use std::io::{self, Read, Seek, SeekFrom};
+#[cfg(feature = "std")]
+use std::cmp;
+
+use error::{RadxResult, RadxError};
+
+pub trait Source {
+    /// Reads until `buf` is full or the data runs out, returning how much was
+    /// read.
+    fn read_bytes(&mut self, buf: &mut [u8]) -> RadxResult<usize>;
+
+    /// Fills `buf`, failing with `UnexpectedEof` if the data runs out first.
+    fn read_all(&mut self, buf: &mut [u8]) -> RadxResult<()> {
+        if self.read_bytes(buf)? == buf.len() {
+            Ok(())
+        }
+        else {
+            Err(RadxError::UnexpectedEof)
+        }
+    }
+}
+
+pub trait SeekSource: Source {
+    /// Moves to `position` bytes from the start of the data.
+    fn seek_to(&mut self, position: u64) -> RadxResult<()>;
+
+    /// Current position in bytes from the start of the data.
+    fn position(&mut self) -> RadxResult<u64>;
+}
+
+/// Seeks a source that's known to be seekable. Decoders that only need
+/// `Source` to decode keep this around as a function pointer when they
+/// happen to be given a `SeekSource`.
+pub(crate) fn seek_source<S>(source: &mut S, position: u64) -> RadxResult<()>
+    where S: SeekSource
+{
+    source.seek_to(position)
+}
+
+#[cfg(feature = "std")]
+impl<R> Source for R
+    where R: Read
+{
+    fn read_bytes(&mut self, buf: &mut [u8]) -> RadxResult<usize> {
+        let mut len = 0;
+        while len < buf.len() {
+            match self.read(&mut buf[len..]) {
+                Ok(0) => break,
+                Ok(read) => len += read,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<S> SeekSource for S
+    where S: Read + Seek
+{
+    fn seek_to(&mut self, position: u64) -> RadxResult<()> {
+        self.seek(SeekFrom::Start(position))?;
+        Ok(())
+    }
+
+    fn position(&mut self) -> RadxResult<u64> {
+        Ok(self.stream_position()?)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<'a, S> Source for &'a mut S
+    where S: Source + ?Sized
+{
+    fn read_bytes(&mut self, buf: &mut [u8]) -> RadxResult<usize> {
+        (**self).read_bytes(buf)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<'a, S> SeekSource for &'a mut S
+    where S: SeekSource + ?Sized
+{
+    fn seek_to(&mut self, position: u64) -> RadxResult<()> {
+        (**self).seek_to(position)
+    }
+
+    fn position(&mut self) -> RadxResult<u64> {
+        (**self).position()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<'a> Source for &'a [u8] {
+    fn read_bytes(&mut self, buf: &mut [u8]) -> RadxResult<usize> {
+        let len = if buf.len() < self.len() { buf.len() } else { self.len() };
+        buf[..len].copy_from_slice(&self[..len]);
+        *self = &self[len..];
+        Ok(len)
+    }
+}
+
+/// A seekable source over bytes in memory, such as a whole file that was
+/// loaded or memory-mapped ahead of time. Nothing gets copied out of the
+/// slice except the bytes being decoded.
+#[derive(Clone,Debug)]
+pub struct SliceReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> SliceReader<'a> {
+    pub fn new(data: &'a [u8]) -> SliceReader<'a> {
+        SliceReader {
+            data: data,
+            position: 0,
+        }
+    }
+
+    fn read_slice(&mut self, buf: &mut [u8]) -> usize {
+        let start = if self.position < self.data.len() { self.position } else { self.data.len() };
+        let remaining = &self.data[start..];
+        let len = if buf.len() < remaining.len() { buf.len() } else { remaining.len() };
+        buf[..len].copy_from_slice(&remaining[..len]);
+        self.position = start + len;
+        len
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<'a> Source for SliceReader<'a> {
+    fn read_bytes(&mut self, buf: &mut [u8]) -> RadxResult<usize> {
+        Ok(self.read_slice(buf))
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<'a> SeekSource for SliceReader<'a> {
+    fn seek_to(&mut self, position: u64) -> RadxResult<()> {
+        self.position = position as usize;
+        Ok(())
+    }
+
+    fn position(&mut self) -> RadxResult<u64> {
+        Ok(self.position as u64)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> Read for SliceReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(self.read_slice(buf))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> Seek for SliceReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset as usize;
+                return Ok(offset);
+            }
+            SeekFrom::Current(offset) => (self.position as u64, offset),
+            SeekFrom::End(offset) => (self.data.len() as u64, offset),
+        };
+        let position = if offset >= 0 {
+            base.checked_add(offset as u64)
+        }
+        else {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        };
+        match position {
+            Some(position) => {
+                self.position = cmp::min(position, usize::MAX as u64) as usize;
+                Ok(position)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")),
+        }
+    }
+}
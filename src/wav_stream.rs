@@ -0,0 +1,309 @@
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use decoder::Decoder;
+
+const WAV_HEADER_LEN: u64 = 44;
+
+/// Reads a decoder out as a 16-bit PCM wav file, decoding as it goes.
+///
+/// Seeking works as long as the decoder can `rewind`. Seeking backwards
+/// decodes again from the start, so it's slow on long files.
+///
+/// If the decoder's progress cancel flag gets set, reads and seeks fail with
+/// `RadxError::Cancelled`. A read leaves the stream where it was and a seek
+/// leaves it as far as it got, so it can carry on once the flag is cleared.
+pub struct WavStream {
+    decoder: Box<Decoder>,
+    header: [u8; WAV_HEADER_LEN as usize],
+    /// Total length of the wav in bytes. `None` until the end is found if
+    /// the decoder doesn't know how many samples it has.
+    len: Option<u64>,
+    position: u64,
+    /// Samples taken from the decoder so far.
+    decoded: u64,
+    /// Bytes of the last sample taken from the decoder.
+    frame: Vec<u8>,
+}
+
+impl WavStream {
+    /// Makes a wav stream over a decoder.
+    ///
+    /// For a looping decoder, `loops` says how many times to go through the
+    /// loop before stopping. It is ignored for decoders that don't loop.
+    pub fn new(decoder: Box<Decoder>, loops: Option<u32>) -> WavStream {
+        let channels = decoder.channels();
+        let sample_rate = decoder.sample_rate();
+        let block_align = channels * 2;
+
+        let total_samples = match (loops, decoder.loop_info()) {
+            (Some(loops), Some(loop_info)) if decoder.looping() =>
+                Some(loop_info.start_sample as u64 + loops as u64 * loop_info.end_sample.saturating_sub(loop_info.start_sample) as u64),
+            _ => decoder.total_samples().map(|total_samples| total_samples as u64),
+        };
+        let data_len = total_samples.map(|total_samples| total_samples * block_align as u64);
+
+        // Streams of unknown length get the biggest size the header can hold
+        let data_len_field = cmp::min(data_len.unwrap_or(u64::MAX), (u32::MAX - 36) as u64) as u32;
+
+        let mut header = [0; WAV_HEADER_LEN as usize];
+        header[0..4].copy_from_slice(b"RIFF");
+        header[4..8].copy_from_slice(&le_u32(36 + data_len_field));
+        header[8..12].copy_from_slice(b"WAVE");
+        header[12..16].copy_from_slice(b"fmt ");
+        header[16..20].copy_from_slice(&le_u32(16));
+        // PCM
+        header[20..22].copy_from_slice(&le_u16(1));
+        header[22..24].copy_from_slice(&le_u16(channels as u16));
+        header[24..28].copy_from_slice(&le_u32(sample_rate));
+        header[28..32].copy_from_slice(&le_u32(sample_rate * block_align));
+        header[32..34].copy_from_slice(&le_u16(block_align as u16));
+        header[34..36].copy_from_slice(&le_u16(16));
+        header[36..40].copy_from_slice(b"data");
+        header[40..44].copy_from_slice(&le_u32(data_len_field));
+
+        WavStream {
+            decoder: decoder,
+            header: header,
+            len: data_len.map(|data_len| WAV_HEADER_LEN + data_len),
+            position: 0,
+            decoded: 0,
+            frame: Vec::with_capacity(block_align as usize),
+        }
+    }
+
+    /// Takes back the decoder.
+    pub fn into_inner(self) -> Box<Decoder> {
+        self.decoder
+    }
+
+    fn block_align(&self) -> u64 {
+        self.decoder.channels() as u64 * 2
+    }
+
+    /// Takes the next sample from the decoder into `frame`. Returns `false`
+    /// at the end of the stream.
+    fn next_frame(&mut self) -> io::Result<bool> {
+        if let Some(len) = self.len {
+            if WAV_HEADER_LEN + self.decoded * self.block_align() >= len {
+                return Ok(false);
+            }
+        }
+
+        self.frame.clear();
+        match self.decoder.try_next_sample().map_err(io::Error::other)? {
+            Some(sample) => {
+                for channel_sample in sample {
+                    self.frame.extend_from_slice(&le_u16(channel_sample as u16));
+                }
+            }
+            None => {
+                if self.len.is_none() {
+                    self.len = Some(WAV_HEADER_LEN + self.decoded * self.block_align());
+                    return Ok(false);
+                }
+                // The data ended early. Keep the length we promised in the
+                // header by filling with silence.
+                let block_align = self.block_align() as usize;
+                self.frame.resize(block_align, 0);
+            }
+        }
+        self.decoded += 1;
+        Ok(true)
+    }
+}
+
+impl Read for WavStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            if self.position < WAV_HEADER_LEN {
+                let start = self.position as usize;
+                let count = cmp::min(WAV_HEADER_LEN as usize - start, buf.len() - written);
+                buf[written..written + count].copy_from_slice(&self.header[start..start + count]);
+                written += count;
+                self.position += count as u64;
+                continue;
+            }
+
+            if let Some(len) = self.len {
+                if self.position >= len {
+                    break;
+                }
+            }
+
+            let block_align = self.block_align();
+            let offset = ((self.position - WAV_HEADER_LEN) % block_align) as usize;
+            if offset == 0 {
+                match self.next_frame() {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    // Hand over what was read, the error comes up next time
+                    Err(_) if written != 0 => break,
+                    Err(err) => return Err(err),
+                }
+            }
+
+            let count = cmp::min(block_align as usize - offset, buf.len() - written);
+            buf[written..written + count].copy_from_slice(&self.frame[offset..offset + count]);
+            written += count;
+            self.position += count as u64;
+        }
+        Ok(written)
+    }
+}
+
+impl Seek for WavStream {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => offset_by(self.position, offset),
+            SeekFrom::End(offset) => {
+                let len = match self.len {
+                    Some(len) => len,
+                    None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "wav stream length is not known")),
+                };
+                offset_by(len, offset)
+            }
+        };
+        let target = match target {
+            Some(target) => target,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")),
+        };
+
+        if target >= WAV_HEADER_LEN {
+            let block_align = self.block_align();
+            let target_sample = (target - WAV_HEADER_LEN) / block_align;
+            // When landing partway into a sample, that sample has to be in
+            // `frame` already.
+            let wanted = if (target - WAV_HEADER_LEN).is_multiple_of(block_align) {
+                target_sample
+            }
+            else {
+                target_sample + 1
+            };
+
+            if wanted < self.decoded {
+                if !self.decoder.rewind().map_err(io::Error::other)? {
+                    return Err(io::Error::other("decoder can't seek backwards"));
+                }
+                self.decoded = 0;
+            }
+            while self.decoded < wanted {
+                match self.next_frame() {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(err) => {
+                        self.position = WAV_HEADER_LEN + self.decoded * block_align;
+                        return Err(err);
+                    }
+                }
+            }
+        }
+
+        self.position = target;
+        Ok(target)
+    }
+}
+
+fn offset_by(position: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        position.checked_add(offset as u64)
+    }
+    else {
+        position.checked_sub(offset.wrapping_neg() as u64)
+    }
+}
+
+fn le_u16(num: u16) -> [u8; 2] {
+    [num as u8, (num >> 8) as u8]
+}
+
+fn le_u32(num: u32) -> [u8; 4] {
+    [num as u8, (num >> 8) as u8, (num >> 16) as u8, (num >> 24) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    use {AdxSpec, DEFAULT_HIGHPASS_FREQUENCY, LoopInfo, from_reader};
+    use encoder::standard_encoder::StandardEncoder;
+    use super::WavStream;
+
+    fn encode(loop_info: Option<LoopInfo>) -> Vec<u8> {
+        let spec = AdxSpec {
+            channels: 2,
+            sample_rate: 44100,
+            loop_info: loop_info,
+            highpass_frequency: DEFAULT_HIGHPASS_FREQUENCY,
+        };
+        let mut buf = Cursor::new(Vec::new());
+        let mut encoder = StandardEncoder::new(&mut buf, spec).unwrap();
+        encoder.encode_data((0..1000).map(|i| {
+            let sample = ((i as f64 * 0.05).sin() * 10000.0) as i16;
+            vec![sample, sample / 2]
+        })).unwrap();
+        encoder.finish().unwrap();
+        buf.into_inner()
+    }
+
+    #[test]
+    fn read_and_seek() {
+        let adx = encode(None);
+        let decoder = from_reader(Cursor::new(adx.clone()), false).unwrap();
+        let samples: Vec<_> = decoder.collect();
+
+        let mut wav = Vec::new();
+        let mut stream = WavStream::new(from_reader(Cursor::new(adx), false).unwrap(), None);
+        stream.read_to_end(&mut wav).unwrap();
+        assert_eq!(wav.len(), 44 + samples.len() * 4);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[40..44], &[0xa0, 0x0f, 0, 0]);
+        assert_eq!(wav[44 + 4 * 10], samples[10][0] as u8);
+        assert_eq!(wav[44 + 4 * 10 + 3], (samples[10][1] as u16 >> 8) as u8);
+
+        // Back into the middle of a sample
+        let mut buf = [0; 7];
+        stream.seek(SeekFrom::Start(44 + 4 * 500 + 1)).unwrap();
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &wav[44 + 4 * 500 + 1..44 + 4 * 500 + 8]);
+        assert_eq!(stream.seek(SeekFrom::End(0)).unwrap(), wav.len() as u64);
+    }
+
+    #[test]
+    fn loops() {
+        let adx = encode(Some(LoopInfo {
+            start_sample: 100,
+            end_sample: 800,
+        }));
+        let decoder = from_reader(Cursor::new(adx.clone()), true).unwrap();
+        let mut wav = Vec::new();
+        WavStream::new(decoder, Some(3)).read_to_end(&mut wav).unwrap();
+        assert_eq!(wav.len(), 44 + (100 + 3 * 700) * 4);
+
+        // Played straight through, the loop doesn't change the length
+        for &loops in [Some(1), Some(3), None].iter() {
+            let decoder = from_reader(Cursor::new(adx.clone()), false).unwrap();
+            let mut wav = Vec::new();
+            WavStream::new(decoder, loops).read_to_end(&mut wav).unwrap();
+            assert_eq!(wav.len(), 44 + 1000 * 4, "{:?} loops", loops);
+        }
+    }
+
+    #[test]
+    fn bad_alignment() {
+        let mut adx = encode(Some(LoopInfo {
+            start_sample: 100,
+            end_sample: 800,
+        }));
+        // More alignment samples than the file has
+        adx[0x14] = 0xff;
+        adx[0x15] = 0xff;
+        let decoder = from_reader(Cursor::new(adx), true).unwrap();
+        assert_eq!(decoder.total_samples(), Some(0));
+        let mut wav = Vec::new();
+        WavStream::new(decoder, Some(1)).read_to_end(&mut wav).unwrap();
+        assert_eq!(&wav[40..44], &[0, 0, 0, 0]);
+    }
+}